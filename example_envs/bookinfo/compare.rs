@@ -0,0 +1,191 @@
+//! Compares what a filter costs:  runs the same seeded workload on the same topology twice, once
+//! as a baseline (without a plugin, or with another plugin) and once with the candidate filter,
+//! and reports the difference of every metric between the two runs.
+
+use crate::runner::{run, RunConfig, RunResult};
+use crate::topology::{BuildOptions, Topology};
+use serde::Serialize;
+use sim::aggregation::percentile;
+use std::fmt;
+
+// One metric of both runs;  the deltas are candidate minus baseline
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricDelta {
+    pub metric: String,
+    pub baseline: Option<f64>,
+    pub candidate: Option<f64>,
+    pub delta: Option<f64>,
+    pub relative: Option<f64>, // the delta as a fraction of the baseline
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Comparison {
+    pub topology: String,
+    pub seed: u64,
+    pub baseline: String,  // the baseline plugin, or "none"
+    pub candidate: String, // the candidate plugin, or "none"
+    pub metrics: Vec<MetricDelta>,
+}
+
+impl MetricDelta {
+    fn new(metric: &str, baseline: Option<f64>, candidate: Option<f64>) -> MetricDelta {
+        let delta = baseline.zip(candidate).map(|(b, c)| c - b);
+        MetricDelta {
+            metric: metric.to_string(),
+            baseline,
+            candidate,
+            delta,
+            relative: baseline
+                .zip(delta)
+                .filter(|(b, _)| *b != 0.0)
+                .map(|(b, d)| d / b),
+        }
+    }
+}
+
+impl Comparison {
+    pub fn get(&self, metric: &str) -> Option<&MetricDelta> {
+        self.metrics.iter().find(|delta| delta.metric == metric)
+    }
+}
+
+// The metrics of one run, in the order of the report
+fn metrics(result: &RunResult) -> Vec<(&'static str, Option<f64>)> {
+    let summary = &result.summary;
+    let mut latencies: Vec<u64> = result.traces.iter().filter_map(|t| t.latency).collect();
+    latencies.sort_unstable();
+    let dropped: u64 = result
+        .metrics
+        .iter()
+        .map(|m| m.ingress_dropped + m.egress_dropped + m.capacity_dropped)
+        .sum();
+    let throughput = if summary.ticks == 0 {
+        None
+    } else {
+        Some(summary.responses as f64 / summary.ticks as f64)
+    };
+    vec![
+        ("latency_mean", summary.mean_latency),
+        (
+            "latency_p50",
+            percentile(&latencies, 50.0).map(|l| l as f64),
+        ),
+        (
+            "latency_p99",
+            percentile(&latencies, 99.0).map(|l| l as f64),
+        ),
+        ("latency_max", summary.max_latency.map(|l| l as f64)),
+        ("ticks", Some(summary.ticks as f64)),
+        ("responses", Some(summary.responses as f64)),
+        ("throughput", throughput), // responses per tick
+        ("errors", Some(summary.errors as f64)),
+        ("dropped", Some(dropped as f64)),
+        ("network_bytes", Some(summary.network_bytes as f64)),
+        ("records", Some(summary.records as f64)),
+        ("storage_bytes", Some(result.storage_bytes as f64)),
+    ]
+}
+
+/* Runs the experiment of @config as the candidate, and again as the baseline with
+ * @baseline_plugin on every service instead of the candidate's plugins, including those the
+ * topology gives its services.  Both runs use the seed and workload of @config, and neither
+ * writes output files.
+ */
+pub fn compare(
+    topology: &Topology,
+    config: &RunConfig,
+    baseline_plugin: Option<&str>,
+) -> Result<Comparison, String> {
+    let run_with = |build: BuildOptions| {
+        let config = RunConfig {
+            seed: config.seed,
+            duration: config.duration,
+            workload: config.workload,
+            build,
+            output_dir: None,
+            formats: Vec::new(),
+        };
+        run(topology, &config)
+    };
+    let baseline = run_with(BuildOptions {
+        plugin: baseline_plugin.map(String::from),
+        service_plugins: Default::default(),
+        ignore_topology_plugins: true,
+        record_network_usage: None,
+        storage_path: None,
+        ..config.build.clone()
+    })?;
+    let candidate = run_with(config.build.clone())?;
+
+    let describe = |build: &BuildOptions| {
+        let mut plugins: Vec<String> = build.plugin.iter().cloned().collect();
+        for service in &topology.services {
+            if let Some(plugin) = service.own_plugin(build) {
+                plugins.push(format!("{0}={1}", service.id, plugin));
+            }
+        }
+        if plugins.is_empty() {
+            "none".to_string()
+        } else {
+            plugins.join(",")
+        }
+    };
+    Ok(Comparison {
+        topology: topology.name.clone(),
+        seed: config.seed,
+        baseline: baseline_plugin.unwrap_or("none").to_string(),
+        candidate: describe(&config.build),
+        metrics: metrics(&baseline)
+            .into_iter()
+            .zip(metrics(&candidate))
+            .map(|((metric, b), (_, c))| MetricDelta::new(metric, b, c))
+            .collect(),
+    })
+}
+
+// A table with one line per metric
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{0:.2}", v));
+        writeln!(
+            f,
+            "{0} (seed {1}):  {2} vs. {3}",
+            self.topology, self.seed, self.baseline, self.candidate
+        )?;
+        writeln!(
+            f,
+            "{0:<14} {1:>12} {2:>12} {3:>12} {4:>9}",
+            "metric", "baseline", "candidate", "delta", "relative"
+        )?;
+        for delta in &self.metrics {
+            writeln!(
+                f,
+                "{0:<14} {1:>12} {2:>12} {3:>12} {4:>9}",
+                delta.metric,
+                value(delta.baseline),
+                value(delta.candidate),
+                value(delta.delta),
+                delta
+                    .relative
+                    .map_or("-".to_string(), |r| format!("{0:+.1}%", r * 100.0))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_delta() {
+        let delta = MetricDelta::new("latency_mean", Some(4.0), Some(5.0));
+        assert!(delta.delta == Some(1.0) && delta.relative == Some(0.25));
+        // no relative delta from nothing
+        let delta = MetricDelta::new("records", Some(0.0), Some(3.0));
+        assert!(delta.delta == Some(3.0) && delta.relative.is_none());
+        let delta = MetricDelta::new("latency_p99", None, Some(3.0));
+        assert!(delta.delta.is_none() && delta.relative.is_none());
+    }
+}
@@ -0,0 +1,521 @@
+//! An interactive debugger for simulations:  steps through a simulation tick by tick, stops at
+//! breakpoints, and shows the queues of the elements and the RPCs they receive, including the
+//! trace graph that filters ferry in the ferried_data header.  Type help for the commands.
+
+use crate::gateway::Gateway;
+use crate::runner::Workload;
+use indexmap::map::IndexMap;
+use rpc_lib::rpc::Rpc;
+use sim::queue_discipline::QueueDiscipline;
+use sim::service::Service;
+use sim::simulator::Simulator;
+use sim::storage::Storage;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use utils::graph::serde::FerriedData;
+
+const HELP: &str = "\
+step [N]                      run one tick, or N ticks;  an empty line steps once
+continue                      run until a breakpoint hits or the simulation is idle
+break tick N                  stop after tick N
+break recv ELEMENT            stop when ELEMENT receives an RPC
+break header NAME=VALUE       stop when an RPC with this header value is delivered
+break trace ID                stop when an RPC of trace ID is delivered
+breakpoints                   list the breakpoints
+delete N                      delete breakpoint N
+elements                      list the elements of the simulation
+inspect ELEMENT               show an element and the RPCs in its queues
+deliveries                    list the RPCs delivered in the last tick
+trace ID                      list every delivery of an RPC of trace ID
+rpc ID [N]                    show the headers and ferried data of delivery N of trace ID (the last by default)
+inject ELEMENT DATA [NAME=VALUE ...]
+                              deliver a new RPC with these headers to ELEMENT;  unless ELEMENT is
+                              the gateway, src has to name an element connected to it, and
+                              direction is request or response (request by default)
+quit";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+    Tick(u64),
+    Receive(String),        // an element receives any RPC
+    Header(String, String), // an RPC with this header value is delivered
+    Trace(u64),             // an RPC of this trace is delivered
+}
+
+impl Breakpoint {
+    pub fn parse(words: &[&str]) -> Result<Breakpoint, String> {
+        match words {
+            ["tick", tick] => Ok(Breakpoint::Tick(parse_number(tick)?)),
+            ["recv", element] => Ok(Breakpoint::Receive(element.to_string())),
+            ["header", assignment] => {
+                let (name, value) = parse_header(assignment)?;
+                Ok(Breakpoint::Header(name, value))
+            }
+            ["trace", id] => Ok(Breakpoint::Trace(parse_number(id)?)),
+            _ => Err(
+                "expected break tick N, recv ELEMENT, header NAME=VALUE or trace ID".to_string(),
+            ),
+        }
+    }
+
+    // Whether the breakpoint hits after @tick ran and delivered @deliveries
+    pub fn hits(&self, tick: u64, deliveries: &[Rpc]) -> bool {
+        match self {
+            Breakpoint::Tick(at) => *at == tick,
+            Breakpoint::Receive(element) => deliveries
+                .iter()
+                .any(|rpc| rpc.headers.get("dest") == Some(element)),
+            Breakpoint::Header(name, value) => deliveries
+                .iter()
+                .any(|rpc| rpc.headers.get(name) == Some(value)),
+            Breakpoint::Trace(id) => deliveries.iter().any(|rpc| rpc.uid == *id),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Tick(tick) => write!(f, "tick {0}", tick),
+            Breakpoint::Receive(element) => write!(f, "recv {0}", element),
+            Breakpoint::Header(name, value) => write!(f, "header {0}={1}", name, value),
+            Breakpoint::Trace(id) => write!(f, "trace {0}", id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Step(u64),
+    Continue,
+    Break(Breakpoint),
+    Breakpoints,
+    Delete(usize),
+    Elements,
+    Inspect(String),
+    Deliveries,
+    Trace(u64),
+    Rpc(u64, Option<usize>),
+    Inject(String, Rpc),
+    Help,
+    Quit,
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+    word.parse::<T>()
+        .map_err(|_| format!("{0} is not a number", word))
+}
+
+fn parse_header(assignment: &str) -> Result<(String, String), String> {
+    match assignment.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, not {0}", assignment)),
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] | ["step"] | ["s"] => Ok(Command::Step(1)),
+            ["step", ticks] | ["s", ticks] => Ok(Command::Step(parse_number(ticks)?)),
+            ["continue"] | ["c"] => Ok(Command::Continue),
+            ["break", rest @ ..] | ["b", rest @ ..] => Ok(Command::Break(Breakpoint::parse(rest)?)),
+            ["breakpoints"] => Ok(Command::Breakpoints),
+            ["delete", index] => Ok(Command::Delete(parse_number(index)?)),
+            ["elements"] | ["ls"] => Ok(Command::Elements),
+            ["inspect", element] | ["i", element] => Ok(Command::Inspect(element.to_string())),
+            ["deliveries"] => Ok(Command::Deliveries),
+            ["trace", id] => Ok(Command::Trace(parse_number(id)?)),
+            ["rpc", id] => Ok(Command::Rpc(parse_number(id)?, None)),
+            ["rpc", id, index] => Ok(Command::Rpc(parse_number(id)?, Some(parse_number(index)?))),
+            ["inject", element, data, headers @ ..] => {
+                let mut rpc = Rpc::new(data);
+                // filters expect every RPC to have a direction
+                rpc.headers
+                    .insert("direction".to_string(), "request".to_string());
+                for assignment in headers {
+                    let (name, value) = parse_header(assignment)?;
+                    rpc.headers.insert(name, value);
+                }
+                match rpc.headers["direction"].as_str() {
+                    "request" | "response" => Ok(Command::Inject(element.to_string(), rpc)),
+                    other => Err(format!(
+                        "the direction has to be request or response, not {0}",
+                        other
+                    )),
+                }
+            }
+            ["help"] | ["h"] => Ok(Command::Help),
+            ["quit"] | ["q"] | ["exit"] => Ok(Command::Quit),
+            _ => Err(format!("unknown command {0};  type help", line.trim())),
+        }
+    }
+}
+
+// One line per RPC
+fn describe(rpc: &Rpc) -> String {
+    let header = |name: &str| rpc.headers.get(name).map_or("?", |value| value.as_str());
+    format!(
+        "trace {0}: {1} -> {2} ({3}) {4:?}",
+        rpc.uid,
+        header("src"),
+        header("dest"),
+        header("direction"),
+        rpc.data
+    )
+}
+
+// The headers of an RPC, with the trace graph of its ferried data
+fn show_rpc(rpc: &Rpc) -> String {
+    let mut lines = vec![describe(rpc)];
+    for (name, value) in &rpc.headers {
+        if name != "ferried_data" {
+            lines.push(format!("  {0}: {1}", name, value));
+        }
+    }
+    if let Some(encoded) = rpc.headers.get("ferried_data") {
+        match FerriedData::decode(encoded) {
+            Ok(data) => {
+                let graph = &data.trace_graph;
+                lines.push(format!(
+                    "  ferried_data: {0} nodes, {1} edges",
+                    graph.node_count(),
+                    graph.edge_count()
+                ));
+                for node in graph.node_indices() {
+                    let (id, properties) = &graph[node];
+                    lines.push(format!("    {0} {1:?}", id, properties));
+                }
+                for edge in graph.edge_indices() {
+                    let (from, to) = graph.edge_endpoints(edge).unwrap();
+                    lines.push(format!("    {0} -> {1}", graph[from].0, graph[to].0));
+                }
+                for property in &data.unassigned_properties {
+                    lines.push(format!(
+                        "    unassigned {0}.{1} = {2}",
+                        property.entity, property.property_name, property.value
+                    ));
+                }
+            }
+            Err(e) => lines.push(format!("  ferried_data: {0} ({1})", encoded, e)),
+        }
+    }
+    lines.join("\n")
+}
+
+fn show_queue(name: &str, queue: &dyn QueueDiscipline, lines: &mut Vec<String>) {
+    lines.push(format!(
+        "  {0} ({1}, {2} RPCs, {3} dropped)",
+        name,
+        queue.name(),
+        queue.size(),
+        queue.dropped()
+    ));
+    for rpc in queue.rpcs() {
+        lines.push(format!("    {0}", describe(rpc)));
+    }
+}
+
+pub struct Debugger {
+    simulator: Simulator,
+    workload: Workload, // requests the gateway receives
+    tick: u64,          // the next tick to run
+    max_ticks: u64,     // continue stops here even if the simulation is not idle
+    breakpoints: Vec<Breakpoint>,
+    history: IndexMap<u64, Vec<(u64, Rpc)>>, // the deliveries of every trace, with their tick
+}
+
+impl Debugger {
+    pub fn new(mut simulator: Simulator, workload: Workload, max_ticks: u64) -> Debugger {
+        simulator.set_record_deliveries(true);
+        Debugger {
+            simulator,
+            workload,
+            tick: 0,
+            max_ticks,
+            breakpoints: Vec::new(),
+            history: IndexMap::new(),
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn simulator(&self) -> &Simulator {
+        &self.simulator
+    }
+
+    // Runs the next tick, and returns the breakpoints that hit
+    fn step(&mut self) -> Vec<usize> {
+        for _ in 0..self.workload.arrivals_at(self.tick) {
+            self.simulator
+                .insert_rpc_at("gateway", Rpc::new("0"), self.tick);
+        }
+        self.simulator.tick(self.tick);
+        for rpc in self.simulator.deliveries() {
+            self.history
+                .entry(rpc.uid)
+                .or_default()
+                .push((self.tick, rpc.clone()));
+        }
+        let hits = (0..self.breakpoints.len())
+            .filter(|index| self.breakpoints[*index].hits(self.tick, self.simulator.deliveries()))
+            .collect();
+        self.tick += 1;
+        hits
+    }
+
+    /* Runs at most @ticks ticks, or until idle if not set, and stops early at a breakpoint.
+     * Returns what happened in the last tick that ran.
+     */
+    fn run(&mut self, ticks: Option<u64>) -> String {
+        let mut ran = 0;
+        loop {
+            if self.tick >= self.max_ticks {
+                return format!("stopped at the limit of {0} ticks", self.max_ticks);
+            }
+            let hits = self.step();
+            ran += 1;
+            let mut lines = vec![format!(
+                "tick {0}: {1} RPCs delivered",
+                self.tick - 1,
+                self.simulator.deliveries().len()
+            )];
+            if !hits.is_empty() {
+                for index in hits {
+                    lines.push(format!(
+                        "breakpoint {0} ({1})",
+                        index, self.breakpoints[index]
+                    ));
+                }
+                return lines.join("\n");
+            }
+            let done = match ticks {
+                Some(ticks) => ran >= ticks,
+                None => {
+                    if self.tick > self.workload.last_arrival() && self.simulator.is_idle() {
+                        lines.push("the simulation is idle".to_string());
+                        true
+                    } else {
+                        false
+                    }
+                }
+            };
+            if done {
+                return lines.join("\n");
+            }
+        }
+    }
+
+    fn inspect(&self, id: &str) -> Result<String, String> {
+        let element = self
+            .simulator
+            .element(id)
+            .ok_or(format!("there is no element {0}", id))?;
+        let mut lines = vec![format!("{0}", element)];
+        let any = element.as_any();
+        if let Some(service) = any.downcast_ref::<Service>() {
+            show_queue("ingress", service.get_ingress_queue(), &mut lines);
+            show_queue("egress", service.get_egress_queue(), &mut lines);
+        } else if let Some(gateway) = any.downcast_ref::<Gateway>() {
+            show_queue("ingress", gateway.get_ingress_queue(), &mut lines);
+            show_queue("egress", gateway.get_egress_queue(), &mut lines);
+            lines.push(format!(
+                "  {0} responses collected",
+                gateway.get_collected_responses().len()
+            ));
+        } else if let Some(storage) = any.downcast_ref::<Storage>() {
+            lines.push(format!("  {0} records", storage.records().len()));
+            for record in storage.records().iter().rev().take(10) {
+                lines.push(format!(
+                    "    trace {0} from {1} at tick {2}: {3:?}",
+                    record.trace_id, record.source, record.tick, record.payload
+                ));
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // Runs a command and returns its output
+    pub fn execute(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Step(ticks) => Ok(self.run(Some(ticks.max(1)))),
+            Command::Continue => Ok(self.run(None)),
+            Command::Break(breakpoint) => {
+                self.breakpoints.push(breakpoint);
+                Ok(format!(
+                    "breakpoint {0} ({1})",
+                    self.breakpoints.len() - 1,
+                    self.breakpoints.last().unwrap()
+                ))
+            }
+            Command::Breakpoints => Ok(self
+                .breakpoints
+                .iter()
+                .enumerate()
+                .map(|(index, breakpoint)| format!("{0}: {1}", index, breakpoint))
+                .collect::<Vec<String>>()
+                .join("\n")),
+            Command::Delete(index) => {
+                if index >= self.breakpoints.len() {
+                    return Err(format!("there is no breakpoint {0}", index));
+                }
+                Ok(format!("deleted {0}", self.breakpoints.remove(index)))
+            }
+            Command::Elements => Ok(self
+                .simulator
+                .element_ids()
+                .iter()
+                .map(|id| {
+                    let element = self.simulator.element(id).unwrap();
+                    let state = if element.is_idle() { "idle" } else { "busy" };
+                    format!("{0} ({1})", id, state)
+                })
+                .collect::<Vec<String>>()
+                .join("\n")),
+            Command::Inspect(id) => self.inspect(&id),
+            Command::Deliveries => Ok(self
+                .simulator
+                .deliveries()
+                .iter()
+                .map(describe)
+                .collect::<Vec<String>>()
+                .join("\n")),
+            Command::Trace(id) => {
+                let deliveries = self
+                    .history
+                    .get(&id)
+                    .ok_or(format!("no RPC of trace {0} was delivered", id))?;
+                Ok(deliveries
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (tick, rpc))| {
+                        format!("{0}: tick {1} {2}", index, tick, describe(rpc))
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"))
+            }
+            Command::Rpc(id, index) => {
+                let deliveries = self
+                    .history
+                    .get(&id)
+                    .ok_or(format!("no RPC of trace {0} was delivered", id))?;
+                let index = index.unwrap_or(deliveries.len() - 1);
+                let (tick, rpc) = deliveries.get(index).ok_or(format!(
+                    "trace {0} has {1} deliveries",
+                    id,
+                    deliveries.len()
+                ))?;
+                Ok(format!("tick {0} {1}", tick, show_rpc(rpc)))
+            }
+            Command::Inject(target, rpc) => {
+                let element = self
+                    .simulator
+                    .element(&target)
+                    .ok_or(format!("there is no element {0}", target))?;
+                // the element answers to src, so it has to be connected to it
+                let is_gateway = element.as_any().is::<Gateway>();
+                match rpc.headers.get("src") {
+                    Some(src) if !element.neighbors().contains(src) => {
+                        return Err(format!("{0} is not connected to {1}", target, src));
+                    }
+                    None if !is_gateway => {
+                        return Err(format!(
+                            "an RPC for {0} needs src=ELEMENT, one of {1:?}",
+                            target,
+                            element.neighbors()
+                        ));
+                    }
+                    _ => {}
+                }
+                let uid = rpc.uid;
+                self.simulator.insert_rpc_at(&target, rpc, self.tick);
+                Ok(format!("injected trace {0} into {1}", uid, target))
+            }
+            Command::Help => Ok(HELP.to_string()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+
+    // Reads commands until quit or the end of the input
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        write!(output, "(tick {0}) ", self.tick)?;
+        output.flush()?;
+        for line in input.lines() {
+            let command = Command::parse(&line?);
+            if command == Ok(Command::Quit) {
+                break;
+            }
+            match command.and_then(|command| self.execute(command)) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => writeln!(output, "{0}", text)?,
+                Err(e) => writeln!(output, "error: {0}", e)?,
+            }
+            write!(output, "(tick {0}) ", self.tick)?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert!(Command::parse("") == Ok(Command::Step(1)));
+        assert!(Command::parse("step 5") == Ok(Command::Step(5)));
+        assert!(Command::parse(" c ") == Ok(Command::Continue));
+        assert!(
+            Command::parse("break header direction=response")
+                == Ok(Command::Break(Breakpoint::Header(
+                    "direction".to_string(),
+                    "response".to_string()
+                )))
+        );
+        assert!(Command::parse("b recv reviews-v1").is_ok());
+        assert!(Command::parse("rpc 3 0") == Ok(Command::Rpc(3, Some(0))));
+        match Command::parse("inject productpage-v1 hello direction=response") {
+            Ok(Command::Inject(target, rpc)) => {
+                assert!(target == "productpage-v1" && rpc.data == "hello");
+                assert!(rpc.headers["direction"] == "response");
+            }
+            other => panic!("{:?}", other),
+        }
+        match Command::parse("inject productpage-v1 hello") {
+            Ok(Command::Inject(_, rpc)) => assert!(rpc.headers["direction"] == "request"),
+            other => panic!("{:?}", other),
+        }
+        for invalid in &[
+            "step x",
+            "break tick",
+            "break header x",
+            "frobnicate",
+            "inject a",
+            "inject a hello direction=sideways",
+        ] {
+            assert!(Command::parse(invalid).is_err(), "accepted {0}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut rpc = Rpc::new("0");
+        rpc.headers
+            .insert("dest".to_string(), "ratings-v1".to_string());
+        rpc.headers
+            .insert("direction".to_string(), "request".to_string());
+        let deliveries = vec![rpc.clone()];
+        assert!(Breakpoint::Tick(3).hits(3, &[]) && !Breakpoint::Tick(3).hits(2, &deliveries));
+        assert!(Breakpoint::Receive("ratings-v1".to_string()).hits(0, &deliveries));
+        assert!(!Breakpoint::Receive("details-v1".to_string()).hits(0, &deliveries));
+        let header = Breakpoint::Header("direction".to_string(), "request".to_string());
+        assert!(header.hits(0, &deliveries) && !header.hits(0, &[]));
+        assert!(Breakpoint::Trace(rpc.uid).hits(0, &deliveries));
+        assert!(!Breakpoint::Trace(rpc.uid + 1).hits(0, &deliveries));
+    }
+}
@@ -3,11 +3,11 @@
 
 use core::any::Any;
 use core::cmp::min;
-use queues::*;
 use rpc_lib::rpc::Rpc;
 use sim::node::node_fmt_with_name;
 use sim::node::Node;
 use sim::node::NodeTraits;
+use sim::queue_discipline::QueueDiscipline;
 use sim::sim_element::SimElement;
 use std::cmp::max;
 use std::fmt;
@@ -15,6 +15,7 @@ use std::fmt;
 pub struct Gateway {
     core_node: Node,
     collected_responses: Vec<Rpc>,
+    entry: String, // the service that receives the requests
 }
 
 impl fmt::Display for Gateway {
@@ -50,7 +51,7 @@ impl SimElement for Gateway {
                     .pass_through_plugin(rpc, &mut queued_rpcs, tick, "egress");
             }
             for queued_rpcs in &queued_rpcs {
-                self.core_node.enqueue_egress(queued_rpcs.clone())
+                self.core_node.enqueue_egress(queued_rpcs.clone(), tick)
             }
         }
         let max_output = min(
@@ -59,7 +60,10 @@ impl SimElement for Gateway {
         );
         let mut outbound_rpcs: Vec<Rpc> = vec![];
         for _ in 0..max_output {
-            outbound_rpcs.push(self.core_node.dequeue_egress().unwrap())
+            match self.core_node.dequeue_egress(tick) {
+                Some(rpc) => outbound_rpcs.push(rpc),
+                None => break,
+            }
         }
         outbound_rpcs
     }
@@ -85,6 +89,12 @@ impl SimElement for Gateway {
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn add_sink(&mut self, sink: String) {
+        self.core_node.add_sink(sink)
+    }
+    fn is_idle(&self) -> bool {
+        self.core_node.is_idle()
+    }
 }
 
 impl NodeTraits for Gateway {
@@ -93,8 +103,7 @@ impl NodeTraits for Gateway {
             .insert("direction".to_string(), "request".to_string());
         rpc.headers
             .insert("src".to_string(), self.core_node.id.to_string());
-        rpc.headers
-            .insert("dest".to_string(), "productpage-v1".to_string());
+        rpc.headers.insert("dest".to_string(), self.entry.clone());
         new_rpcs.push(rpc.clone());
     }
 }
@@ -112,22 +121,33 @@ impl Gateway {
         Gateway {
             core_node,
             collected_responses: vec![],
+            entry: "productpage-v1".to_string(),
         }
     }
 
+    // Send requests to this service instead of productpage
+    pub fn with_entry(mut self, entry: &str) -> Gateway {
+        self.entry = entry.to_string();
+        self
+    }
+
+    pub fn core_node(&self) -> &Node {
+        &self.core_node
+    }
+
     #[allow(dead_code)]
     pub const fn get_collected_responses(&self) -> &Vec<Rpc> {
         return &self.collected_responses;
     }
 
     #[allow(dead_code)]
-    pub const fn get_ingress_queue(&self) -> &Queue<Rpc> {
-        return &self.core_node.ingress_queue;
+    pub fn get_ingress_queue(&self) -> &dyn QueueDiscipline {
+        return self.core_node.ingress_queue.as_ref();
     }
 
     #[allow(dead_code)]
-    pub const fn get_egress_queue(&self) -> &Queue<Rpc> {
-        return &self.core_node.ingress_queue;
+    pub fn get_egress_queue(&self) -> &dyn QueueDiscipline {
+        return self.core_node.egress_queue.as_ref();
     }
 }
 
@@ -1,6 +1,8 @@
-pub mod leafnode;
-pub mod productpage;
-pub mod reviews;
-
 pub mod bookinfo;
+pub mod compare;
+pub mod debugger;
 pub mod gateway;
+pub mod monitor;
+pub mod runner;
+pub mod sweep;
+pub mod topology;
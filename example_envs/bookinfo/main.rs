@@ -1,18 +1,14 @@
-#![feature(test)]
-#![feature(extern_types)]
-
-mod gateway;
-mod leafnode;
-mod productpage;
-mod reviews;
-
-pub mod bookinfo;
-
-use crate::bookinfo::new_bookinfo;
-use crate::gateway::Gateway;
 use clap::{App, Arg};
+use example_envs::compare::compare;
+use example_envs::debugger::Debugger;
+use example_envs::monitor::Monitor;
+use example_envs::runner::{run, write_rows, Duration, OutputFormat, RunConfig, Workload};
+use example_envs::sweep::{run_sweep, SweepSpec};
+use example_envs::topology::{BuildOptions, Topology};
+use indexmap::map::IndexMap;
 use rand::Rng;
-use rpc_lib::rpc::Rpc;
+use sim::simulator::Simulator;
+use std::path::PathBuf;
 
 use log4rs::{
     append::{
@@ -24,7 +20,7 @@ use log4rs::{
     filter::threshold::ThresholdFilter,
 };
 
-fn log_setup() {
+fn log_setup(stderr_level: log::LevelFilter) {
     // Build a stderr logger.
     let stderr = ConsoleAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{h({l})}: {m}\n")))
@@ -43,7 +39,7 @@ fn log_setup() {
         .appender(Appender::builder().build("logfile", Box::new(logfile)))
         .appender(
             Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(log::LevelFilter::Info)))
+                .filter(Box::new(ThresholdFilter::new(stderr_level)))
                 .build("stderr", Box::new(stderr)),
         )
         .build(
@@ -60,10 +56,81 @@ fn log_setup() {
     let _handle = log4rs::init_config(config);
 }
 
-fn main() {
-    // Set up logging
-    log_setup();
+// Parses a command line value, or exits with an error that names the option
+fn parse_arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str) -> Option<T> {
+    matches
+        .value_of(name)
+        .map(|value| match value.parse::<T>() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                log::error!("invalid value {0} for --{1}", value, name);
+                std::process::exit(2);
+            }
+        })
+}
+
+fn output_formats(matches: &clap::ArgMatches) -> Vec<OutputFormat> {
+    let mut formats = Vec::new();
+    for name in matches.values_of("formats").into_iter().flatten() {
+        match OutputFormat::from_name(name) {
+            Some(format) => formats.push(format),
+            None => {
+                log::error!("unknown output format {0}", name);
+                std::process::exit(2);
+            }
+        }
+    }
+    formats
+}
+
+// The simulator of the topology, or exits if it cannot be built
+fn build(topology: &Topology, seed: u64, options: &BuildOptions) -> Simulator {
+    match topology.build(seed, options) {
+        Ok(simulator) => simulator,
+        Err(e) => {
+            log::error!("{0}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Batch mode:  runs a sweep and writes its results table
+fn sweep(matches: &clap::ArgMatches, path: &str) {
+    let spec = match SweepSpec::from_file(path) {
+        Ok(spec) => spec,
+        Err(e) => {
+            log::error!("{0}", e);
+            std::process::exit(2);
+        }
+    };
+    let jobs = parse_arg::<usize>(matches, "jobs")
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cores| cores.get()));
+    let rows = run_sweep(&spec, jobs, matches.value_of("aggr_filter"));
 
+    let output_dir = PathBuf::from(matches.value_of("output_dir").unwrap_or("."));
+    if let Err(e) = std::fs::create_dir_all(&output_dir) {
+        log::error!("could not create {0:?}: {1}", output_dir, e);
+        std::process::exit(1);
+    }
+    let mut outputs = Vec::new();
+    for format in output_formats(matches) {
+        match write_rows(&output_dir, "sweep", &rows, format) {
+            Ok(output) => outputs.push(output),
+            Err(e) => {
+                log::error!("{0}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let summary = serde_json::json!({
+        "runs": rows.len(),
+        "failed": rows.iter().filter(|row| row.error.is_some()).count(),
+        "outputs": outputs,
+    });
+    println!("{0}", summary);
+}
+
+fn main() {
     let matches = App::new("Tracing Simulator")
         .arg(
             Arg::with_name("print_graph")
@@ -86,6 +153,14 @@ fn main() {
                 .value_name("PLUGIN")
                 .help("Path to the plugin."),
         )
+        .arg(
+            Arg::with_name("service_plugin")
+                .long("service_plugin")
+                .value_name("SERVICE=PLUGIN")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Path to the plugin of one service, instead of --plugin.  Can be repeated."),
+        )
         .arg(
             Arg::with_name("aggr_filter")
                 .short("a")
@@ -93,6 +168,13 @@ fn main() {
                 .value_name("AGGREGATION_FILTER")
                 .help("Path to the aggregation filter."),
         )
+        .arg(
+            Arg::with_name("storage_path")
+                .short("s")
+                .long("storage_path")
+                .value_name("STORAGE_PATH")
+                .help("Append the records of the storage to this newline-delimited JSON file."),
+        )
         .arg(
             Arg::with_name("random_num_seed")
                 .short("r")
@@ -100,46 +182,271 @@ fn main() {
                 .value_name("RANDOM_NUM_SEED")
                 .help("A seed for all the random routing decisions."),
         )
+        .arg(
+            Arg::with_name("topology")
+                .short("t")
+                .long("topology")
+                .value_name("TOPOLOGY")
+                .default_value("bookinfo")
+                .help("A built-in topology (bookinfo) or the path to a topology JSON file."),
+        )
+        .arg(
+            Arg::with_name("ticks")
+                .long("ticks")
+                .value_name("TICKS")
+                .help("Run for this many ticks.  Defaults to 7, unless --until_idle is set."),
+        )
+        .arg(
+            Arg::with_name("until_idle")
+                .long("until_idle")
+                .conflicts_with("ticks")
+                .help("Run until every request has been answered and nothing is in flight."),
+        )
+        .arg(
+            Arg::with_name("max_ticks")
+                .long("max_ticks")
+                .value_name("MAX_TICKS")
+                .default_value("10000")
+                .help("With --until_idle, --debug or --monitor, stop after this many ticks anyway."),
+        )
+        .arg(
+            Arg::with_name("requests")
+                .long("requests")
+                .value_name("REQUESTS")
+                .default_value("1")
+                .help("Number of requests the gateway sends."),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("INTERVAL")
+                .default_value("1")
+                .help("Ticks between bursts of requests."),
+        )
+        .arg(
+            Arg::with_name("burst")
+                .long("burst")
+                .value_name("BURST")
+                .default_value("1")
+                .help("Requests sent together in every burst."),
+        )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .value_name("START")
+                .default_value("0")
+                .help("Tick of the first burst of requests."),
+        )
+        .arg(
+            Arg::with_name("output_dir")
+                .short("o")
+                .long("output_dir")
+                .value_name("OUTPUT_DIR")
+                .help("Write traces, metrics and storage records to this directory."),
+        )
+        .arg(
+            Arg::with_name("formats")
+                .short("f")
+                .long("formats")
+                .value_name("FORMATS")
+                .default_value("ndjson")
+                .use_delimiter(true)
+                .help("Formats of the output files, separated by commas:  ndjson, csv."),
+        )
+        .arg(
+            Arg::with_name("sweep")
+                .long("sweep")
+                .value_name("SWEEP")
+                .help("Run every combination of a sweep specification (JSON) and write one row per run to sweep.<format> in the output directory."),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("JOBS")
+                .help("Number of runs of a sweep to run at the same time.  Defaults to the number of cores."),
+        )
+        .arg(
+            Arg::with_name("compare")
+                .long("compare")
+                .help("Run the experiment twice, with the baseline plugin and with the plugins of --plugin and --service_plugin, and report how every metric differs."),
+        )
+        .arg(
+            Arg::with_name("baseline_plugin")
+                .long("baseline_plugin")
+                .value_name("BASELINE_PLUGIN")
+                .requires("compare")
+                .help("Path to the plugin of every service in the baseline of --compare.  Defaults to no plugin."),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("Step through the simulation interactively instead of running it;  type help for the commands."),
+        )
+        .arg(
+            Arg::with_name("monitor")
+                .long("monitor")
+                .conflicts_with("debug")
+                .help("Watch the simulation in a terminal UI with the queues, edges and storage of the topology."),
+        )
+        .arg(
+            Arg::with_name("tick_ms")
+                .long("tick_ms")
+                .value_name("TICK_MS")
+                .default_value("250")
+                .help("With --monitor, milliseconds between ticks at the start."),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only log warnings and errors to stderr."),
+        )
         .get_matches();
 
-    // Set up library access
-    let plugin_str = matches.value_of("plugin");
-    let aggr_filter_str = matches.value_of("aggr_filter");
-    let seed_arg = matches.value_of("random_num_seed");
-    let network_usage_arg = matches.value_of("record_network_usage");
-    let mut record_network_usage = None;
-    if network_usage_arg.is_some() {
-        record_network_usage = Some(network_usage_arg.unwrap().to_string());
+    // Set up logging;  stdout only carries the summary, or the debugger
+    if matches.is_present("quiet") || matches.is_present("debug") || matches.is_present("monitor") {
+        log_setup(log::LevelFilter::Warn);
+    } else {
+        log_setup(log::LevelFilter::Info);
     }
 
-    let seed;
-    if seed_arg.is_none() {
-        let mut rng = rand::thread_rng();
-        seed = rng.gen::<u64>();
-        log::info!("Using seed {0}\n", seed);
-    } else {
-        seed = seed_arg.unwrap().parse::<u64>().unwrap();
+    if let Some(path) = matches.value_of("sweep") {
+        sweep(&matches, path);
+        return;
     }
 
-    // Create simulator object.
-    let mut simulator = new_bookinfo(seed, record_network_usage, plugin_str, aggr_filter_str);
+    let topology = match Topology::load(matches.value_of("topology").unwrap()) {
+        Ok(topology) => topology,
+        Err(e) => {
+            log::error!("{0}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut service_plugins = IndexMap::new();
+    for assignment in matches.values_of("service_plugin").into_iter().flatten() {
+        match assignment.split_once('=') {
+            Some((service, plugin)) if topology.service_ids().contains(&service) => {
+                service_plugins.insert(service.to_string(), plugin.to_string());
+            }
+            _ => {
+                log::error!(
+                    "invalid --service_plugin {0}, expected SERVICE=PLUGIN for a service of {1}",
+                    assignment,
+                    topology.name
+                );
+                std::process::exit(2);
+            }
+        }
+    }
+    let formats = output_formats(&matches);
+
+    let seed = match parse_arg::<u64>(&matches, "random_num_seed") {
+        Some(seed) => seed,
+        None => {
+            let mut rng = rand::thread_rng();
+            let seed = rng.gen::<u64>();
+            log::info!("Using seed {0}\n", seed);
+            seed
+        }
+    };
+    let duration = if matches.is_present("until_idle") {
+        Duration::UntilIdle {
+            max_ticks: parse_arg(&matches, "max_ticks").unwrap(),
+        }
+    } else {
+        Duration::Ticks(parse_arg(&matches, "ticks").unwrap_or(7))
+    };
+    let workload = Workload {
+        requests: parse_arg(&matches, "requests").unwrap(),
+        interval: parse_arg(&matches, "interval").unwrap(),
+        burst: parse_arg(&matches, "burst").unwrap(),
+        start: parse_arg(&matches, "start").unwrap(),
+    };
+    if let Err(e) = workload.validate() {
+        log::error!("--interval and --burst: {0}", e);
+        std::process::exit(2);
+    }
+    let config = RunConfig {
+        seed,
+        duration,
+        workload,
+        build: BuildOptions {
+            record_network_usage: matches.value_of("record_network_usage").map(String::from),
+            plugin: matches.value_of("plugin").map(String::from),
+            service_plugins,
+            ignore_topology_plugins: false,
+            aggr_filter: matches.value_of("aggr_filter").map(String::from),
+            storage_path: matches.value_of("storage_path").map(String::from),
+        },
+        output_dir: matches.value_of("output_dir").map(PathBuf::from),
+        formats,
+    };
 
     // Print the graph
     if let Some(_argument) = matches.value_of("print_graph") {
-        simulator.print_graph();
+        build(&topology, seed, &config.build).print_graph();
     }
 
-    // Execute the simulator
-    simulator.insert_rpc("gateway", Rpc::new("0"));
-    for tick in 0..7 {
-        simulator.tick(tick);
-        log::info!("Filter results:\n {0}", simulator.query_storage("storage"));
+    // interactive sessions go on until --ticks or else --max_ticks
+    let max_ticks =
+        parse_arg(&matches, "ticks").unwrap_or_else(|| parse_arg(&matches, "max_ticks").unwrap());
+    if matches.is_present("monitor") {
+        let simulator = build(&topology, seed, &config.build);
+        let interval = std::time::Duration::from_millis(parse_arg(&matches, "tick_ms").unwrap());
+        let mut monitor = Monitor::new(&topology, simulator, workload, max_ticks, interval);
+        if let Err(e) = monitor.run() {
+            log::error!("{0}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.is_present("debug") {
+        let simulator = build(&topology, seed, &config.build);
+        let mut debugger = Debugger::new(simulator, workload, max_ticks);
+        let stdin = std::io::stdin();
+        if let Err(e) = debugger.repl(stdin.lock(), std::io::stdout()) {
+            log::error!("{0}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-    let gateway = simulator.get_element::<Gateway>("gateway");
-    log::info!("Gateway collected RPCS:");
-    for rpc in gateway.get_collected_responses() {
-        log::info!("{:?}", rpc);
+
+    if matches.is_present("compare") {
+        match compare(&topology, &config, matches.value_of("baseline_plugin")) {
+            Ok(comparison) => {
+                log::info!("\n{0}", comparison);
+                if let Some(dir) = &config.output_dir {
+                    if let Err(e) = std::fs::create_dir_all(dir) {
+                        log::error!("could not create {0:?}: {1}", dir, e);
+                        std::process::exit(1);
+                    }
+                    for format in &config.formats {
+                        if let Err(e) = write_rows(dir, "comparison", &comparison.metrics, *format)
+                        {
+                            log::error!("{0}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                println!("{0}", serde_json::to_string(&comparison).unwrap());
+            }
+            Err(e) => {
+                log::error!("{0}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Execute the simulator
+    match run(&topology, &config) {
+        Ok(result) => println!("{0}", serde_json::to_string(&result.summary).unwrap()),
+        Err(e) => {
+            log::error!("{0}", e);
+            std::process::exit(1);
+        }
     }
-    let storage_result = simulator.query_storage("storage");
-    log::info!("Final filter results:\n {0}", storage_result);
 }
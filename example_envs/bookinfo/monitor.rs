@@ -0,0 +1,516 @@
+//! A terminal UI that runs a simulation and redraws the topology at every tick:  the queues and
+//! drops of every service, the RPCs on the edges, and what the storage has received, so that
+//! congestion and filter traffic can be watched as they propagate.
+//! The simulation can be paused, stepped and sped up.  The terminal is put in raw mode with stty,
+//! so that keys are read as they are typed;  without a terminal the frames are only printed.
+
+use crate::gateway::Gateway;
+use crate::runner::Workload;
+use crate::topology::Topology;
+use indexmap::map::IndexMap;
+use rpc_lib::rpc::Rpc;
+use sim::node::Node;
+use sim::service::Service;
+use sim::simulator::Simulator;
+use sim::storage::Storage;
+use std::io;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 10;
+const RECENT_RECORDS: usize = 5;
+const CONTROLS: &str = "space pause/resume   n step   + faster   - slower   q quit";
+
+// ANSI escape sequences
+const ALTERNATE_SCREEN: &str = "\x1b[?1049h\x1b[?25l";
+const MAIN_SCREEN: &str = "\x1b[?25h\x1b[?1049l";
+const HOME: &str = "\x1b[H\x1b[2J";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Pause, // pause or resume
+    Step,
+    Faster,
+    Slower,
+    Quit,
+}
+
+impl Key {
+    pub fn from_byte(byte: u8) -> Option<Key> {
+        match byte {
+            b' ' | b'p' => Some(Key::Pause),
+            b'n' | b's' => Some(Key::Step),
+            b'+' | b'=' => Some(Key::Faster),
+            b'-' | b'_' => Some(Key::Slower),
+            b'q' | 3 => Some(Key::Quit), // 3 is ctrl-c, which raw mode does not turn into a signal
+            _ => None,
+        }
+    }
+}
+
+// What an edge has carried
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct EdgeTraffic {
+    in_flight: usize,
+    last_tick: u64, // RPCs delivered in the last tick
+    total: u64,
+}
+
+pub struct Monitor {
+    simulator: Simulator,
+    topology: Topology,
+    workload: Workload,
+    tick: u64, // the next tick to run
+    max_ticks: u64,
+    interval: Duration, // time between ticks while running
+    paused: bool,
+    sent: u64, // requests the gateway has received
+    traffic: IndexMap<(String, String), EdgeTraffic>,
+}
+
+impl Monitor {
+    pub fn new(
+        topology: &Topology,
+        mut simulator: Simulator,
+        workload: Workload,
+        max_ticks: u64,
+        interval: Duration,
+    ) -> Monitor {
+        simulator.set_record_deliveries(true);
+        Monitor {
+            simulator,
+            topology: topology.clone(),
+            workload,
+            tick: 0,
+            max_ticks,
+            interval,
+            paused: false,
+            sent: 0,
+            traffic: IndexMap::new(),
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    // Whether every request has been sent and answered, or the tick limit is reached
+    pub fn done(&self) -> bool {
+        self.tick >= self.max_ticks
+            || (self.tick > self.workload.last_arrival() && self.simulator.is_idle())
+    }
+
+    pub fn step(&mut self) {
+        if self.tick >= self.max_ticks {
+            return;
+        }
+        for _ in 0..self.workload.arrivals_at(self.tick) {
+            self.simulator
+                .insert_rpc_at("gateway", Rpc::new("0"), self.tick);
+            self.sent += 1;
+        }
+        self.simulator.tick(self.tick);
+        for traffic in self.traffic.values_mut() {
+            traffic.last_tick = 0;
+        }
+        for rpc in self.simulator.deliveries() {
+            let edge = (rpc.headers["src"].clone(), rpc.headers["dest"].clone());
+            let traffic = self.traffic.entry(edge).or_default();
+            traffic.last_tick += 1;
+            traffic.total += 1;
+        }
+        for (src, dest, in_flight) in self.simulator.in_flight() {
+            if in_flight > 0
+                || self
+                    .traffic
+                    .contains_key(&(src.to_string(), dest.to_string()))
+            {
+                self.traffic
+                    .entry((src.to_string(), dest.to_string()))
+                    .or_default()
+                    .in_flight = in_flight;
+            }
+        }
+        self.tick += 1;
+    }
+
+    // Applies a key, and returns false to quit
+    pub fn handle_key(&mut self, key: Key) -> bool {
+        match key {
+            Key::Pause => self.paused = !self.paused,
+            Key::Step => {
+                self.paused = true;
+                self.step();
+            }
+            Key::Faster => self.interval = (self.interval / 2).max(Duration::from_millis(10)),
+            Key::Slower => self.interval = (self.interval * 2).min(Duration::from_secs(10)),
+            Key::Quit => return false,
+        }
+        true
+    }
+
+    // One line per element of the topology, indented below its caller
+    fn render_topology(&self, color: bool, lines: &mut Vec<String>) {
+        let width = self
+            .topology
+            .service_ids()
+            .iter()
+            .map(|id| id.len())
+            .max()
+            .unwrap_or(0)
+            + 8;
+        let gateway = self.simulator.get_element::<Gateway>("gateway");
+        lines.push(render_node("gateway", width, gateway.core_node(), color));
+        let mut path = vec!["gateway".to_string()];
+        self.render_calls(
+            &self.topology.entry,
+            "  ",
+            "",
+            &mut path,
+            width,
+            color,
+            lines,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_calls(
+        &self,
+        id: &str,
+        indent: &str,
+        marker: &str,
+        path: &mut Vec<String>,
+        width: usize,
+        color: bool,
+        lines: &mut Vec<String>,
+    ) {
+        let service = self.simulator.get_element::<Service>(id);
+        let label = format!("{0}{1}{2}", indent, marker, id);
+        lines.push(render_node(&label, width, service.core_node(), color));
+        if path.iter().any(|caller| caller == id) {
+            return; // a cycle;  its calls are shown above
+        }
+        path.push(id.to_string());
+        let config = self.topology.services.iter().find(|s| s.id == id).unwrap();
+        for call in &config.calls {
+            for callee in call {
+                // a call that lists several services picks one of them
+                let marker = if call.len() > 1 { "? " } else { "" };
+                self.render_calls(
+                    callee,
+                    &format!("{0}  ", indent),
+                    marker,
+                    path,
+                    width,
+                    color,
+                    lines,
+                );
+            }
+        }
+        path.pop();
+    }
+
+    // Renders a frame;  with @color, queue bars are colored by how full the queues are
+    pub fn render(&self, color: bool) -> String {
+        let gateway = self.simulator.get_element::<Gateway>("gateway");
+        let state = if self.done() {
+            "done"
+        } else if self.paused {
+            "paused"
+        } else {
+            "running"
+        };
+        let mut lines = vec![
+            format!(
+                "{0}   tick {1}   {2}   {3} ms/tick   requests {4}/{5}   responses {6}",
+                self.topology.name,
+                self.tick,
+                state,
+                self.interval.as_millis(),
+                self.sent,
+                self.workload.requests,
+                gateway.get_collected_responses().len()
+            ),
+            String::new(),
+        ];
+        self.render_topology(color, &mut lines);
+
+        lines.push(String::new());
+        lines.push(format!(
+            "{0:<36} {1:>9} {2:>9} {3:>7}",
+            "edge", "in flight", "last tick", "total"
+        ));
+        for ((src, dest), traffic) in &self.traffic {
+            lines.push(format!(
+                "{0:<36} {1:>9} {2:>9} {3:>7}",
+                format!("{0} -> {1}", src, dest),
+                traffic.in_flight,
+                traffic.last_tick,
+                traffic.total
+            ));
+        }
+
+        for id in self.simulator.storage_ids() {
+            let storage = self.simulator.get_element::<Storage>(id);
+            lines.push(String::new());
+            lines.push(format!("{0}   {1} records", id, storage.records().len()));
+            for record in storage.records().iter().rev().take(RECENT_RECORDS) {
+                lines.push(format!(
+                    "  tick {0:<4} trace {1:<4} from {2}: {3:?}",
+                    record.tick, record.trace_id, record.source, record.payload
+                ));
+            }
+        }
+        lines.push(String::new());
+        lines.push(CONTROLS.to_string());
+        lines.join("\n")
+    }
+
+    /* Runs the simulation in the terminal until q is pressed.  Without a terminal, prints a frame
+     * per tick until the simulation is done.
+     */
+    pub fn run(&mut self) -> io::Result<()> {
+        let saved = match raw_mode() {
+            Some(saved) => saved,
+            None => {
+                let mut stdout = io::stdout();
+                while !self.done() {
+                    self.step();
+                    writeln!(stdout, "{0}\n", self.render(false))?;
+                }
+                return Ok(());
+            }
+        };
+        let _terminal = TerminalGuard { saved };
+        let keys = spawn_key_reader();
+        let mut stdout = io::stdout();
+        write!(stdout, "{0}", ALTERNATE_SCREEN)?;
+        self.run_loop(&keys, &mut stdout)
+    }
+
+    fn run_loop(&mut self, keys: &mpsc::Receiver<u8>, stdout: &mut io::Stdout) -> io::Result<()> {
+        let mut next_tick = Instant::now();
+        loop {
+            write!(
+                stdout,
+                "{0}{1}",
+                HOME,
+                self.render(true).replace('\n', "\r\n")
+            )?;
+            stdout.flush()?;
+            let running = !self.paused && !self.done();
+            let key = if running {
+                keys.recv_timeout(next_tick.saturating_duration_since(Instant::now()))
+            } else {
+                keys.recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+            };
+            match key {
+                Ok(byte) => {
+                    if let Some(key) = Key::from_byte(byte) {
+                        if !self.handle_key(key) {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.step();
+                    next_tick = Instant::now() + self.interval;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+// The queues of a node and how full it is
+fn render_node(label: &str, width: usize, node: &Node, color: bool) -> String {
+    let (ingress, egress) = (node.ingress_queue.size(), node.egress_queue.size());
+    format!(
+        "{0:<width$} {1} {2:>3}/{3:<3} in {4:>3} out {5:>3} dropped {6:>4}",
+        label,
+        bar(ingress + egress, node.capacity as usize, color),
+        ingress + egress,
+        node.capacity,
+        ingress,
+        egress,
+        node.dropped(),
+        width = width
+    )
+}
+
+// A bar of BAR_WIDTH characters, filled by the share of @capacity that is used
+fn bar(used: usize, capacity: usize, color: bool) -> String {
+    let filled = if capacity == 0 {
+        0
+    } else {
+        (used * BAR_WIDTH).div_ceil(capacity).min(BAR_WIDTH)
+    };
+    let bar = format!(
+        "[{0}{1}]",
+        "#".repeat(filled),
+        ".".repeat(BAR_WIDTH - filled)
+    );
+    if !color {
+        return bar;
+    }
+    let color = if filled * 10 >= BAR_WIDTH * 8 {
+        RED
+    } else if filled * 2 >= BAR_WIDTH {
+        YELLOW
+    } else {
+        GREEN
+    };
+    format!("{0}{1}{2}", color, bar, RESET)
+}
+
+// Puts the terminal in raw mode, and returns the settings to restore;  None without a terminal
+fn raw_mode() -> Option<String> {
+    let saved = Command::new("stty")
+        .arg("-g")
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+    let status = Command::new("stty")
+        .args(["-icanon", "-echo", "-isig"])
+        .stdin(Stdio::inherit())
+        .status()
+        .ok()?;
+    if status.success() {
+        Some(saved)
+    } else {
+        None
+    }
+}
+
+fn restore_mode(saved: &str) {
+    let _ = Command::new("stty")
+        .arg(saved)
+        .stdin(Stdio::inherit())
+        .status();
+}
+
+// Leaves the alternate screen and restores the settings of the terminal when dropped, so that
+// an error or a panic of the simulation does not leave the terminal in raw mode
+struct TerminalGuard {
+    saved: String,
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "{0}", MAIN_SCREEN);
+        let _ = stdout.flush();
+        restore_mode(&self.saved);
+    }
+}
+
+// Reads the keys on another thread, so that the simulation runs while no key is pressed
+fn spawn_key_reader() -> mpsc::Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for byte in stdin.lock().bytes() {
+            match byte {
+                Ok(byte) => {
+                    if sender.send(byte).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::BuildOptions;
+
+    fn monitor(workload: Workload) -> Monitor {
+        let topology = Topology::bookinfo();
+        let simulator = topology.build(0, &BuildOptions::default()).unwrap();
+        Monitor::new(
+            &topology,
+            simulator,
+            workload,
+            100,
+            Duration::from_millis(100),
+        )
+    }
+
+    #[test]
+    fn test_render() {
+        let mut monitor = monitor(Workload {
+            requests: 10,
+            burst: 10,
+            ..Workload::default()
+        });
+        monitor.step();
+        let frame = monitor.render(false);
+        assert!(
+            frame.contains("tick 1") && frame.contains("requests 10/10"),
+            "{0}",
+            frame
+        );
+        // the services are indented below their callers
+        assert!(frame.contains("\n  productpage-v1"), "{0}", frame);
+        assert!(frame.contains("\n    ? reviews-v2"), "{0}", frame);
+        assert!(frame.contains("\n      ratings-v1"), "{0}", frame);
+        // the gateway holds as many requests as it can, and sends them on
+        assert!(frame.contains("[##########]   5/5"), "{0}", frame);
+        assert!(frame.contains("gateway -> productpage-v1"), "{0}", frame);
+        while !monitor.done() {
+            monitor.step();
+        }
+        let frame = monitor.render(false);
+        assert!(
+            frame.contains("done") && frame.contains("storage   0 records"),
+            "{0}",
+            frame
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut monitor = monitor(Workload::default());
+        assert!(!monitor.is_paused());
+        assert!(monitor.handle_key(Key::from_byte(b' ').unwrap()) && monitor.is_paused());
+        assert!(monitor.handle_key(Key::Step) && monitor.tick() == 1 && monitor.is_paused());
+        monitor.handle_key(Key::Faster);
+        assert!(monitor.interval() == Duration::from_millis(50));
+        monitor.handle_key(Key::Slower);
+        monitor.handle_key(Key::Slower);
+        assert!(monitor.interval() == Duration::from_millis(200));
+        assert!(!monitor.handle_key(Key::from_byte(b'q').unwrap()));
+        assert!(Key::from_byte(b'x').is_none());
+    }
+
+    #[test]
+    fn test_bar() {
+        assert!(bar(0, 5, false) == "[..........]");
+        assert!(bar(2, 5, false) == "[####......]");
+        assert!(bar(9, 5, false) == "[##########]");
+        assert!(bar(5, 5, true).starts_with(RED));
+    }
+}
@@ -0,0 +1,341 @@
+//! Runs an experiment on a topology: the gateway sends a workload of requests, the simulator runs
+//! for a number of ticks or until it is idle, and what happened is written to an output directory
+//! (one row per trace, per service and per storage record) and summarized.
+
+use crate::gateway::Gateway;
+use crate::topology::{BuildOptions, Topology};
+use indexmap::map::IndexMap;
+use rpc_lib::rpc::Rpc;
+use serde::{Deserialize, Serialize};
+use sim::service::Service;
+use sim::storage::RecordQuery;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Requests sent through the gateway:  a burst of requests every interval ticks, from start on
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Workload {
+    pub requests: u64,
+    pub interval: u64,
+    pub burst: u64,
+    pub start: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Workload {
+        Workload {
+            requests: 1,
+            interval: 1,
+            burst: 1,
+            start: 0,
+        }
+    }
+}
+
+impl Workload {
+    // Every burst needs at least one request and one tick to the next;  the other methods
+    // expect a valid workload, which a deserialized one need not be
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 || self.burst == 0 {
+            return Err("the interval and burst of a workload must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    // The number of requests to send at this tick
+    pub fn arrivals_at(&self, tick: u64) -> u64 {
+        if tick < self.start || !(tick - self.start).is_multiple_of(self.interval) {
+            return 0;
+        }
+        let sent_before = (tick - self.start) / self.interval * self.burst;
+        self.burst.min(self.requests.saturating_sub(sent_before))
+    }
+
+    pub fn last_arrival(&self) -> u64 {
+        let bursts = (self.requests + self.burst - 1) / self.burst.max(1);
+        self.start + bursts.saturating_sub(1) * self.interval
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Duration {
+    Ticks(u64),
+    UntilIdle { max_ticks: u64 }, // stop once every request has been sent and answered
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Ndjson, // one JSON object per line
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "ndjson" | "json" => Some(OutputFormat::Ndjson),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Csv => "csv",
+        }
+    }
+}
+
+pub struct RunConfig {
+    pub seed: u64,
+    pub duration: Duration,
+    pub workload: Workload,
+    pub build: BuildOptions,
+    pub output_dir: Option<PathBuf>,
+    pub formats: Vec<OutputFormat>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TraceRow {
+    pub trace_id: u64,
+    pub sent: u64,             // tick the gateway received the request
+    pub answered: Option<u64>, // tick the gateway received the response
+    pub latency: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ServiceMetrics {
+    pub service: String,
+    pub ingress_dropped: u64,
+    pub egress_dropped: u64,
+    pub capacity_dropped: u64, // dropped on arrival because the service was full
+    pub sampled: u64,
+    pub unsampled: u64,
+    pub discarded: u64,
+}
+
+#[derive(Serialize)]
+struct RecordRow {
+    sink: String, // the id of the storage element that holds the record
+    trace_id: u64,
+    source: String,
+    tick: u64,
+    fields: String, // as a JSON object, so that every format has the same columns
+    payload: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub topology: String,
+    pub seed: u64,
+    pub ticks: u64,
+    pub idle: bool, // whether the simulation ended with nothing left to do
+    pub requests: u64,
+    pub responses: u64,
+    pub errors: u64,
+    pub mean_latency: Option<f64>,
+    pub max_latency: Option<u64>,
+    pub network_bytes: u64,
+    pub records: u64,
+    pub outputs: Vec<String>,
+}
+
+pub struct RunResult {
+    pub summary: RunSummary,
+    pub traces: Vec<TraceRow>,
+    pub metrics: Vec<ServiceMetrics>,
+    pub storage_bytes: u64, // the size of the payloads of the storage records
+}
+
+pub fn run(topology: &Topology, config: &RunConfig) -> Result<RunResult, String> {
+    config.workload.validate()?;
+    let mut simulator = topology.build(config.seed, &config.build)?;
+    let mut traces: IndexMap<u64, TraceRow> = IndexMap::new();
+    let mut answered = 0;
+    let mut idle = false;
+    let mut tick = 0;
+    loop {
+        let max_ticks = match config.duration {
+            Duration::Ticks(ticks) => ticks,
+            Duration::UntilIdle { max_ticks } => max_ticks,
+        };
+        if tick >= max_ticks {
+            break;
+        }
+        for _ in 0..config.workload.arrivals_at(tick) {
+            let rpc = Rpc::new("0");
+            traces.insert(
+                rpc.uid,
+                TraceRow {
+                    trace_id: rpc.uid,
+                    sent: tick,
+                    answered: None,
+                    latency: None,
+                    error: None,
+                },
+            );
+            simulator.insert_rpc("gateway", rpc);
+        }
+        simulator.tick(tick);
+
+        let gateway = simulator.get_element::<Gateway>("gateway");
+        for response in &gateway.get_collected_responses()[answered..] {
+            if let Some(trace) = traces.get_mut(&response.uid) {
+                trace.answered = Some(tick);
+                trace.latency = Some(tick - trace.sent);
+                trace.error = response.headers.get("error").cloned();
+            }
+        }
+        answered = gateway.get_collected_responses().len();
+        tick += 1;
+        if let Duration::UntilIdle { .. } = config.duration {
+            if tick > config.workload.last_arrival() && simulator.is_idle() {
+                idle = true;
+                break;
+            }
+        }
+    }
+
+    let metrics: Vec<ServiceMetrics> = topology
+        .service_ids()
+        .iter()
+        .map(|id| {
+            let node = simulator.get_element::<Service>(id).core_node();
+            ServiceMetrics {
+                service: id.to_string(),
+                ingress_dropped: node.ingress_queue.dropped(),
+                egress_dropped: node.egress_queue.dropped(),
+                capacity_dropped: node.capacity_dropped,
+                sampled: node.sampling_stats.sampled,
+                unsampled: node.sampling_stats.unsampled,
+                discarded: node.sampling_stats.discarded,
+            }
+        })
+        .collect();
+    let records: Vec<RecordRow> = simulator
+        .storage_ids()
+        .into_iter()
+        .flat_map(|sink| {
+            simulator
+                .query_records(sink, &RecordQuery::new())
+                .into_iter()
+                .map(move |record| RecordRow {
+                    sink: sink.to_string(),
+                    trace_id: record.trace_id,
+                    source: record.source,
+                    tick: record.tick,
+                    fields: serde_json::to_string(&record.fields).unwrap(),
+                    payload: record.payload,
+                })
+        })
+        .collect();
+    let traces: Vec<TraceRow> = traces.into_iter().map(|(_, trace)| trace).collect();
+
+    let latencies: Vec<u64> = traces.iter().filter_map(|trace| trace.latency).collect();
+    let mut summary = RunSummary {
+        topology: topology.name.clone(),
+        seed: config.seed,
+        ticks: tick,
+        idle,
+        requests: traces.len() as u64,
+        responses: latencies.len() as u64,
+        errors: traces.iter().filter(|trace| trace.error.is_some()).count() as u64,
+        mean_latency: if latencies.is_empty() {
+            None
+        } else {
+            Some(latencies.iter().sum::<u64>() as f64 / latencies.len() as f64)
+        },
+        max_latency: latencies.iter().max().copied(),
+        network_bytes: simulator.network_bytes(),
+        records: records.len() as u64,
+        outputs: Vec::new(),
+    };
+    if let Some(dir) = &config.output_dir {
+        fs::create_dir_all(dir).map_err(|e| format!("could not create {0:?}: {1}", dir, e))?;
+        for format in &config.formats {
+            summary
+                .outputs
+                .push(write_rows(dir, "traces", &traces, *format)?);
+            summary
+                .outputs
+                .push(write_rows(dir, "metrics", &metrics, *format)?);
+            summary
+                .outputs
+                .push(write_rows(dir, "records", &records, *format)?);
+        }
+    }
+    Ok(RunResult {
+        summary,
+        traces,
+        metrics,
+        storage_bytes: records
+            .iter()
+            .map(|record| record.payload.len() as u64)
+            .sum(),
+    })
+}
+
+// Writes the rows to dir/name.<extension of the format>, and returns the path
+pub fn write_rows<T: Serialize>(
+    dir: &Path,
+    name: &str,
+    rows: &[T],
+    format: OutputFormat,
+) -> Result<String, String> {
+    let path = dir.join(format!("{0}.{1}", name, format.extension()));
+    let error = |e: &dyn std::fmt::Display| format!("could not write {0:?}: {1}", path, e);
+    let file = File::create(&path).map_err(|e| error(&e))?;
+    match format {
+        OutputFormat::Ndjson => {
+            let mut writer = BufWriter::new(file);
+            for row in rows {
+                let line = serde_json::to_string(row).map_err(|e| error(&e))?;
+                writeln!(writer, "{0}", line).map_err(|e| error(&e))?;
+            }
+            writer.flush().map_err(|e| error(&e))?;
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(file);
+            for row in rows {
+                writer.serialize(row).map_err(|e| error(&e))?;
+            }
+            writer.flush().map_err(|e| error(&e))?;
+        }
+    }
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workload() {
+        let workload = Workload {
+            requests: 5,
+            interval: 3,
+            burst: 2,
+            start: 1,
+        };
+        let arrivals: Vec<u64> = (0..10).map(|tick| workload.arrivals_at(tick)).collect();
+        assert!(arrivals == vec![0, 2, 0, 0, 2, 0, 0, 1, 0, 0]);
+        assert!(workload.last_arrival() == 7);
+        assert!(Workload::default().last_arrival() == 0);
+
+        assert!(workload.validate().is_ok());
+        let no_interval = Workload {
+            interval: 0,
+            ..workload
+        };
+        assert!(no_interval.validate().is_err());
+        let no_burst = Workload {
+            burst: 0,
+            ..workload
+        };
+        assert!(no_burst.validate().is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! A sweep runs an experiment for every combination of seeds, workloads, plugins and topologies,
+//! spreads the runs over threads since simulations are independent, and collects one row of
+//! metrics per run.  A sweep is specified in JSON, e.g.
+//!
+//! {
+//!     "seeds": [0, 1, 2],
+//!     "topologies": ["bookinfo"],
+//!     "plugins": [{ "name": "none" }, { "name": "height", "path": "target/debug/libfilter_example" }],
+//!     "loads": [{ "requests": 10 }, { "requests": 10, "burst": 5 }],
+//!     "max_ticks": 1000
+//! }
+//!
+//! Every field can be left out:  by default a sweep has seed 0, bookinfo, no plugin and one
+//! request, and runs until idle.
+
+use crate::runner::{run, Duration, RunConfig, Workload};
+use crate::topology::{BuildOptions, Topology};
+use serde::{Deserialize, Serialize};
+use sim::aggregation::percentile;
+use std::fs;
+use std::panic;
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PluginVariant {
+    pub name: String,
+    #[serde(default)]
+    pub path: Option<String>, // no plugin if not set
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SweepSpec {
+    pub seeds: Vec<u64>,
+    pub topologies: Vec<String>, // built-in topologies or topology files
+    pub plugins: Vec<PluginVariant>,
+    pub loads: Vec<Workload>,
+    pub ticks: Option<u64>, // run every experiment for this many ticks, or else until idle
+    pub max_ticks: u64,
+}
+
+impl Default for SweepSpec {
+    fn default() -> SweepSpec {
+        SweepSpec {
+            seeds: vec![0],
+            topologies: vec!["bookinfo".to_string()],
+            plugins: vec![PluginVariant {
+                name: "none".to_string(),
+                path: None,
+            }],
+            loads: vec![Workload::default()],
+            ticks: None,
+            max_ticks: 10000,
+        }
+    }
+}
+
+// One run of a sweep
+#[derive(Clone, Debug, PartialEq)]
+pub struct SweepRun {
+    pub index: usize,
+    pub topology: String,
+    pub plugin: PluginVariant,
+    pub seed: u64,
+    pub workload: Workload,
+}
+
+// The results table has one row per run
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SweepRow {
+    pub run: usize,
+    pub topology: String,
+    pub plugin: String,
+    pub seed: u64,
+    pub requests: u64,
+    pub interval: u64,
+    pub burst: u64,
+    pub ticks: u64,
+    pub idle: bool,
+    pub responses: u64,
+    pub errors: u64,
+    pub latency_mean: Option<f64>,
+    pub latency_p50: Option<u64>,
+    pub latency_p90: Option<u64>,
+    pub latency_p99: Option<u64>,
+    pub latency_max: Option<u64>,
+    pub network_bytes: u64,
+    pub records: u64,
+    pub storage_bytes: u64,
+    pub error: Option<String>, // why the run failed, in which case it has no metrics
+}
+
+impl SweepSpec {
+    pub fn from_file(path: &str) -> Result<SweepSpec, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("could not read {0}: {1}", path, e))?;
+        SweepSpec::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<SweepSpec, String> {
+        let spec: SweepSpec =
+            serde_json::from_str(text).map_err(|e| format!("invalid sweep: {0}", e))?;
+        for load in &spec.loads {
+            load.validate()
+                .map_err(|e| format!("invalid sweep: {0}", e))?;
+        }
+        Ok(spec)
+    }
+
+    // Every combination, seeds varying fastest
+    pub fn runs(&self) -> Vec<SweepRun> {
+        let mut runs = Vec::new();
+        for topology in &self.topologies {
+            for plugin in &self.plugins {
+                for workload in &self.loads {
+                    for seed in &self.seeds {
+                        runs.push(SweepRun {
+                            index: runs.len(),
+                            topology: topology.clone(),
+                            plugin: plugin.clone(),
+                            seed: *seed,
+                            workload: *workload,
+                        });
+                    }
+                }
+            }
+        }
+        runs
+    }
+
+    fn duration(&self) -> Duration {
+        match self.ticks {
+            Some(ticks) => Duration::Ticks(ticks),
+            None => Duration::UntilIdle {
+                max_ticks: self.max_ticks,
+            },
+        }
+    }
+}
+
+fn run_one(spec: &SweepSpec, sweep_run: &SweepRun, aggr_filter: Option<&str>) -> SweepRow {
+    let workload = sweep_run.workload;
+    let mut row = SweepRow {
+        run: sweep_run.index,
+        topology: sweep_run.topology.clone(),
+        plugin: sweep_run.plugin.name.clone(),
+        seed: sweep_run.seed,
+        requests: workload.requests,
+        interval: workload.interval,
+        burst: workload.burst,
+        ticks: 0,
+        idle: false,
+        responses: 0,
+        errors: 0,
+        latency_mean: None,
+        latency_p50: None,
+        latency_p90: None,
+        latency_p99: None,
+        latency_max: None,
+        network_bytes: 0,
+        records: 0,
+        storage_bytes: 0,
+        error: None,
+    };
+    let config = RunConfig {
+        seed: sweep_run.seed,
+        duration: spec.duration(),
+        workload,
+        build: BuildOptions {
+            plugin: sweep_run.plugin.path.clone(),
+            aggr_filter: aggr_filter.map(String::from),
+            ..BuildOptions::default()
+        },
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    // a panic of the simulator, e.g. if the plugin of the run cannot be loaded, only fails this
+    // run;  a panic inside a plugin cannot unwind across the FFI boundary and aborts the sweep
+    let result = panic::catch_unwind(|| {
+        Topology::load(&sweep_run.topology).and_then(|topology| run(&topology, &config))
+    })
+    .unwrap_or_else(|_| Err("the simulation panicked".to_string()));
+    match result {
+        Ok(result) => {
+            let summary = result.summary;
+            let mut latencies: Vec<u64> = result.traces.iter().filter_map(|t| t.latency).collect();
+            latencies.sort_unstable();
+            row.ticks = summary.ticks;
+            row.idle = summary.idle;
+            row.responses = summary.responses;
+            row.errors = summary.errors;
+            row.latency_mean = summary.mean_latency;
+            row.latency_p50 = percentile(&latencies, 50.0);
+            row.latency_p90 = percentile(&latencies, 90.0);
+            row.latency_p99 = percentile(&latencies, 99.0);
+            row.latency_max = summary.max_latency;
+            row.network_bytes = summary.network_bytes;
+            row.records = summary.records;
+            row.storage_bytes = result.storage_bytes;
+        }
+        Err(e) => {
+            log::error!("run {0} failed: {1}", sweep_run.index, e);
+            row.error = Some(e);
+        }
+    }
+    row
+}
+
+/* Runs every combination of the sweep on @jobs threads, and returns the rows in the order of
+ * the runs.  A run that fails has a row with its error.
+ */
+pub fn run_sweep(spec: &SweepSpec, jobs: usize, aggr_filter: Option<&str>) -> Vec<SweepRow> {
+    let runs = spec.runs();
+    let next_run = Mutex::new(0);
+    let rows = Mutex::new(Vec::with_capacity(runs.len()));
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(runs.len()) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut next_run = next_run.lock().unwrap();
+                    *next_run += 1;
+                    *next_run - 1
+                };
+                match runs.get(index) {
+                    Some(sweep_run) => {
+                        let row = run_one(spec, sweep_run, aggr_filter);
+                        rows.lock().unwrap().push(row);
+                    }
+                    None => break,
+                }
+            });
+        }
+    });
+    let mut rows = rows.into_inner().unwrap();
+    rows.sort_by_key(|row| row.run);
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep_spec() {
+        let spec = SweepSpec::from_json(
+            r#"{ "seeds": [1, 2], "loads": [{ "requests": 4 }, { "requests": 4, "burst": 2 }] }"#,
+        )
+        .unwrap();
+        assert!(spec.topologies == vec!["bookinfo"] && spec.ticks.is_none());
+        let runs = spec.runs();
+        assert!(runs.len() == 4);
+        assert!(runs.iter().map(|run| run.seed).eq(vec![1, 2, 1, 2]));
+        assert!(runs[3].workload.burst == 2 && runs[3].workload.interval == 1);
+        assert!(runs[3].plugin.name == "none" && runs[3].plugin.path.is_none());
+
+        assert!(SweepSpec::from_json(r#"{ "loads": [{ "interval": 0 }] }"#).is_err());
+        assert!(SweepSpec::from_json(r#"{ "seeds": "all" }"#).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! A topology describes a microservice application as data: its services, whom each of them
+//! calls, and which service the gateway sends requests to.  Topologies can be built in, like
+//! bookinfo, or read from a JSON file, e.g.
+//!
+//! {
+//!     "name": "shop",
+//!     "entry": "frontend",
+//!     "services": [
+//!         { "id": "frontend", "calls": [["cart"], ["ads-v1", "ads-v2"]], "reply": "response" },
+//!         { "id": "cart" },
+//!         { "id": "ads-v1" },
+//!         { "id": "ads-v2" }
+//!     ]
+//! }
+//!
+//! A service calls all of its calls in parallel; a call that lists several services picks one of
+//! them uniformly at random for every request.  A service that has not heard back from its calls
+//! after "timeout" ticks (100 by default) answers with an error, so a call or reply that a full
+//! service drops does not keep the request pending forever.
+
+use crate::gateway::Gateway;
+use indexmap::map::IndexMap;
+use serde::{Deserialize, Serialize};
+use sim::routing::RoutingTable;
+use sim::service::{CallTarget, ReplyData, Service, ServiceSpec};
+use sim::simulator::Simulator;
+use sim::storage::{RunMetadata, Storage};
+use std::fs;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    pub id: String,
+    #[serde(default)]
+    pub calls: Vec<Vec<String>>,
+    #[serde(default = "default_rate")]
+    pub capacity: u32,
+    #[serde(default = "default_rate")]
+    pub egress_rate: u32,
+    #[serde(default)]
+    pub reply: Option<String>, // fixed data of every response, instead of the request's
+    #[serde(default)]
+    pub plugin: Option<String>, // the filter of this service, instead of the default one
+    #[serde(default = "default_timeout")]
+    pub timeout: u64, // ticks to wait for the replies of the calls
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Topology {
+    pub name: String,
+    pub entry: String, // the service the gateway sends requests to
+    #[serde(default)]
+    pub link_delay: u64,
+    #[serde(default = "default_storage_delay")]
+    pub storage_delay: u64,
+    pub services: Vec<ServiceConfig>,
+}
+
+fn default_rate() -> u32 {
+    5
+}
+
+fn default_storage_delay() -> u64 {
+    1
+}
+
+fn default_timeout() -> u64 {
+    100
+}
+
+// Where the results of a simulation go, and the filters it runs
+#[derive(Clone, Debug, Default)]
+pub struct BuildOptions {
+    pub record_network_usage: Option<String>,
+    pub plugin: Option<String>, // the filter of every service without its own
+    pub service_plugins: IndexMap<String, String>, // service to filter, over the topology's
+    pub ignore_topology_plugins: bool, // leave out the filters the topology gives its services
+    pub aggr_filter: Option<String>,
+    pub storage_path: Option<String>,
+}
+
+impl ServiceConfig {
+    fn new(id: &str, calls: &[&[&str]]) -> ServiceConfig {
+        ServiceConfig {
+            id: id.to_string(),
+            calls: calls
+                .iter()
+                .map(|call| call.iter().map(|service| service.to_string()).collect())
+                .collect(),
+            capacity: default_rate(),
+            egress_rate: default_rate(),
+            reply: None,
+            plugin: None,
+            timeout: default_timeout(),
+        }
+    }
+
+    // The filter of this service in particular, if it has one
+    pub fn own_plugin<'a>(&'a self, options: &'a BuildOptions) -> Option<&'a String> {
+        options.service_plugins.get(&self.id).or(self
+            .plugin
+            .as_ref()
+            .filter(|_| !options.ignore_topology_plugins))
+    }
+
+    fn spec(&self, seed: u64) -> ServiceSpec {
+        let calls: Vec<CallTarget> = self
+            .calls
+            .iter()
+            .map(|call| match call.as_slice() {
+                [service] => CallTarget::Service(service.clone()),
+                services => {
+                    let services: Vec<&str> = services.iter().map(|s| s.as_str()).collect();
+                    CallTarget::Routed(Box::new(RoutingTable::uniform(&services, seed)))
+                }
+            })
+            .collect();
+        let spec = if calls.is_empty() {
+            ServiceSpec::leaf()
+        } else {
+            ServiceSpec::parallel(calls).with_timeout(self.timeout)
+        };
+        match &self.reply {
+            Some(reply) => spec.with_reply(ReplyData::Fixed(reply.clone())),
+            None => spec,
+        }
+    }
+}
+
+impl Topology {
+    pub fn bookinfo() -> Topology {
+        let reviews = &["ratings-v1"][..];
+        let mut productpage = ServiceConfig::new(
+            "productpage-v1",
+            &[
+                &["details-v1"],
+                // productpage asks details and one of the reviews versions at the same time
+                &["reviews-v1", "reviews-v2", "reviews-v3"],
+            ],
+        );
+        productpage.reply = Some("response".to_string());
+        Topology {
+            name: "bookinfo".to_string(),
+            entry: "productpage-v1".to_string(),
+            link_delay: 0,
+            storage_delay: default_storage_delay(),
+            services: vec![
+                productpage,
+                // every reviews version forwards the request to ratings and relays its answer
+                ServiceConfig::new("reviews-v1", &[reviews]),
+                ServiceConfig::new("reviews-v2", &[reviews]),
+                ServiceConfig::new("reviews-v3", &[reviews]),
+                ServiceConfig::new("details-v1", &[]),
+                ServiceConfig::new("ratings-v1", &[]),
+            ],
+        }
+    }
+
+    // A built-in topology by name, or else a topology file
+    pub fn load(name_or_path: &str) -> Result<Topology, String> {
+        match name_or_path {
+            "bookinfo" => Ok(Topology::bookinfo()),
+            path => Topology::from_file(path),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Topology, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("could not read {0}: {1}", path, e))?;
+        Topology::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<Topology, String> {
+        let topology: Topology =
+            serde_json::from_str(text).map_err(|e| format!("invalid topology: {0}", e))?;
+        topology.validate()?;
+        Ok(topology)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut ids = Vec::new();
+        for service in &self.services {
+            if service.id == "gateway" || service.id == "storage" {
+                return Err(format!(
+                    "service id {0} is reserved for the {0} of the simulator",
+                    service.id
+                ));
+            }
+            if ids.contains(&&service.id) {
+                return Err(format!("service id {0} is used twice", service.id));
+            }
+            if service.capacity == 0 {
+                return Err(format!("{0} has no capacity", service.id));
+            }
+            if service.timeout == 0 {
+                return Err(format!("{0} has no time to wait for its calls", service.id));
+            }
+            ids.push(&service.id);
+        }
+        if !ids.contains(&&self.entry) {
+            return Err(format!("the entry {0} is not a service", self.entry));
+        }
+        for service in &self.services {
+            for callee in service.calls.iter().flatten() {
+                if !ids.contains(&callee) {
+                    return Err(format!(
+                        "{0} calls {1}, which is not a service",
+                        service.id, callee
+                    ));
+                }
+            }
+            if service.calls.iter().any(|call| call.is_empty()) {
+                return Err(format!("{0} has a call without services", service.id));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn service_ids(&self) -> Vec<&str> {
+        self.services
+            .iter()
+            .map(|service| service.id.as_str())
+            .collect()
+    }
+
+    /* A simulator with a gateway, the services of the topology, and a storage element that every
+     * service can send filter results to.  Fails if the storage cannot persist its records.
+     */
+    pub fn build(&self, seed: u64, options: &BuildOptions) -> Result<Simulator, String> {
+        let mut sim = Simulator::new(seed, options.record_network_usage.clone());
+
+        // no plugins on a gateway
+        let gateway = Gateway::new("gateway", 5, 5, 0, seed).with_entry(&self.entry);
+        sim.add_node("gateway", gateway);
+        for service in &self.services {
+            let plugin = service.own_plugin(options).or(options.plugin.as_ref());
+            let node = Service::new(
+                &service.id,
+                service.capacity,
+                service.egress_rate,
+                plugin.map(|plugin| plugin.as_str()),
+                seed,
+                service.spec(seed),
+            );
+            sim.add_node(&service.id, node);
+        }
+        let mut storage = Storage::new("storage", options.aggr_filter.as_deref());
+        if let Some(path) = &options.storage_path {
+            storage = storage
+                .with_persistence(path, RunMetadata::new(seed, &self.name))
+                .map_err(|e| format!("could not open {0} to persist the storage: {1}", path, e))?;
+        }
+
+        sim.add_edge(self.link_delay, "gateway", &self.entry, true);
+        let mut connected = Vec::new();
+        for service in &self.services {
+            for callee in service.calls.iter().flatten() {
+                if !connected.contains(&(&service.id, callee)) {
+                    sim.add_edge(self.link_delay, &service.id, callee, true);
+                    connected.push((&service.id, callee));
+                }
+            }
+        }
+        sim.add_sink(storage, self.storage_delay, &self.service_ids());
+        Ok(sim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topology_from_json() {
+        let topology = Topology::from_json(
+            r#"{
+                "name": "shop",
+                "entry": "frontend",
+                "services": [
+                    { "id": "frontend", "calls": [["cart"], ["ads-v1", "ads-v2"]], "reply": "response" },
+                    { "id": "cart", "capacity": 2 },
+                    { "id": "ads-v1" },
+                    { "id": "ads-v2" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(topology.service_ids() == vec!["frontend", "cart", "ads-v1", "ads-v2"]);
+        assert!(topology.services[1].capacity == 2 && topology.services[1].egress_rate == 5);
+        assert!(topology.storage_delay == 1 && topology.link_delay == 0);
+        assert!(topology.services[0].timeout == 100);
+
+        // a topology round-trips through JSON
+        let bookinfo = Topology::bookinfo();
+        let json = serde_json::to_string(&bookinfo).unwrap();
+        assert!(Topology::from_json(&json).unwrap() == bookinfo);
+    }
+
+    #[test]
+    fn test_invalid_topologies() {
+        let invalid = [
+            r#"{ "name": "t", "entry": "a", "services": [{ "id": "a", "calls": [["b"]] }] }"#,
+            r#"{ "name": "t", "entry": "b", "services": [{ "id": "a" }] }"#,
+            r#"{ "name": "t", "entry": "a", "services": [{ "id": "a" }, { "id": "a" }] }"#,
+            r#"{ "name": "t", "entry": "a", "services": [{ "id": "a", "calls": [[]] }] }"#,
+            r#"{ "name": "t", "entry": "a", "services": [{ "id": "a", "timeout": 0 }] }"#,
+            r#"{ "name": "t", "services": [] }"#,
+        ];
+        for text in &invalid {
+            assert!(Topology::from_json(text).is_err(), "accepted {0}", text);
+        }
+        assert!(Topology::load("no/such/topology.json").is_err());
+        let reserved = r#"{ "name": "t", "entry": "storage", "services": [{ "id": "storage" }] }"#;
+        let error = Topology::from_json(reserved).unwrap_err();
+        assert!(error.contains("reserved"), "{0}", error);
+    }
+}
@@ -1,10 +1,8 @@
 use example_envs::bookinfo::new_bookinfo;
 use example_envs::gateway::Gateway;
-use example_envs::leafnode::LeafNode;
-use example_envs::productpage::ProductPage;
-use example_envs::reviews::Reviews;
-use queues::IsQueue;
 use rpc_lib::rpc::Rpc;
+use sim::service::Service;
+use sim::storage::RecordQuery;
 use std::path::PathBuf;
 
 #[test]
@@ -15,14 +13,14 @@ fn check_bookinfo() {
     let plugin_str = cargo_dir.to_str().unwrap();
 
     // Create simulator object.
-    let mut simulator = new_bookinfo(0, None, Some(plugin_str), None);
+    let mut simulator = new_bookinfo(0, None, Some(plugin_str), None, None).unwrap();
 
     // Execute the simulator
     simulator.insert_rpc("gateway", Rpc::new("0"));
 
     // After the first tick, Productpage should receive a request
     simulator.tick(0);
-    let productpage = simulator.get_element::<ProductPage>("productpage-v1");
+    let productpage = simulator.get_element::<Service>("productpage-v1");
     let product_ingress_size = productpage.get_ingress_queue().size();
     assert!(
         product_ingress_size == 1,
@@ -33,14 +31,14 @@ fn check_bookinfo() {
     // Productpage sends to both Reviews and Ratings
     // For this seed the RPC is in Reviews-v1
     simulator.tick(1);
-    let details = simulator.get_element::<LeafNode>("details-v1");
+    let details = simulator.get_element::<Service>("details-v1");
     let details_ingress_size = details.get_ingress_queue().size();
     assert!(
         details_ingress_size == 1,
         "Expected 1 RPC in details ingress queue, received {}",
         details_ingress_size
     );
-    let reviews_v1 = simulator.get_element::<Reviews>("reviews-v1");
+    let reviews_v1 = simulator.get_element::<Service>("reviews-v1");
     let reviews_ingress_size = reviews_v1.get_ingress_queue().size();
     assert!(
         reviews_ingress_size == 1,
@@ -50,7 +48,7 @@ fn check_bookinfo() {
 
     // Reviews-v1 forwards the request to Ratings
     simulator.tick(2);
-    let ratings = simulator.get_element::<LeafNode>("ratings-v1");
+    let ratings = simulator.get_element::<Service>("ratings-v1");
     let ratings_ingress_size = ratings.get_ingress_queue().size();
     assert!(
         ratings_ingress_size == 1,
@@ -60,7 +58,7 @@ fn check_bookinfo() {
 
     // The answer from Ratings should be in the reviews queue now
     simulator.tick(3);
-    let reviews_v1 = simulator.get_element::<Reviews>("reviews-v1");
+    let reviews_v1 = simulator.get_element::<Service>("reviews-v1");
     let reviews_ingress_size = reviews_v1.get_ingress_queue().size();
     assert!(
         reviews_ingress_size == 1,
@@ -71,7 +69,7 @@ fn check_bookinfo() {
     // Productpage received a reply from both Details and Reviews
     // It should now place an RPC in its queue
     simulator.tick(4);
-    let productpage = simulator.get_element::<ProductPage>("productpage-v1");
+    let productpage = simulator.get_element::<Service>("productpage-v1");
     let product_ingress_size = productpage.get_ingress_queue().size();
     assert!(
         product_ingress_size == 1,
@@ -90,12 +88,18 @@ fn check_bookinfo() {
         "Number of responses was {}",
         response_num
     );
+    let trace_id = collected_rpcs[0].uid;
 
     // Because of the filter, we should have a return value of 2 in storage
     // after one more tick
     simulator.tick(6);
-    let storage_val = simulator.query_storage("storage");
-    assert!(storage_val == "2\n", "storage contains {0}", storage_val);
+    let records = simulator.query_records("storage", &RecordQuery::new());
+    assert!(records.len() == 1, "storage contains {:?}", records);
+    assert!(records[0].payload == "2", "storage contains {:?}", records);
+    assert!(records[0].source == "productpage-v1");
+    assert!(records[0].tick == 6);
+    let trace_records = simulator.query_records("storage", &RecordQuery::new().trace(trace_id));
+    assert!(trace_records == records);
 
     // Also check that we stay at one response
     for tick in 0..10 {
@@ -0,0 +1,90 @@
+use example_envs::compare::compare;
+use example_envs::runner::{Duration, RunConfig, Workload};
+use example_envs::topology::{BuildOptions, Topology};
+use std::path::PathBuf;
+
+#[test]
+fn check_filter_overhead() {
+    let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cargo_dir.push("../target/debug/libfilter_example");
+    let plugin = cargo_dir.to_str().unwrap().to_string();
+    let config = RunConfig {
+        seed: 3,
+        duration: Duration::UntilIdle { max_ticks: 1000 },
+        workload: Workload {
+            requests: 5,
+            ..Workload::default()
+        },
+        build: BuildOptions {
+            plugin: Some(plugin.clone()),
+            ..BuildOptions::default()
+        },
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    let comparison = compare(&Topology::bookinfo(), &config, None).unwrap();
+    assert!(comparison.baseline == "none" && comparison.candidate == plugin);
+    let metric = |name: &str| comparison.get(name).unwrap().clone();
+    assert!(metric("responses").delta == Some(0.0));
+    assert!(metric("records").baseline == Some(0.0) && metric("records").candidate == Some(5.0));
+    let bytes = metric("network_bytes");
+    assert!(bytes.delta.unwrap() > 0.0 && bytes.relative.unwrap() > 0.0);
+
+    // the baseline also leaves out the plugins the topology gives its services
+    let mut topology = Topology::bookinfo();
+    for service in &mut topology.services {
+        service.plugin = Some(plugin.clone());
+    }
+    let own_plugins = RunConfig {
+        seed: config.seed,
+        duration: config.duration,
+        workload: config.workload,
+        build: BuildOptions::default(),
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    let comparison = compare(&topology, &own_plugins, None).unwrap();
+    assert!(comparison.baseline == "none");
+    assert!(comparison
+        .candidate
+        .starts_with(&format!("productpage-v1={0}", plugin)));
+    let records = comparison.get("records").unwrap();
+    assert!(records.baseline == Some(0.0) && records.candidate == Some(5.0));
+
+    // the same plugin on both sides costs nothing
+    let comparison = compare(&Topology::bookinfo(), &config, Some(&plugin)).unwrap();
+    for delta in &comparison.metrics {
+        assert!(delta.delta.unwrap_or(0.0) == 0.0, "{:?}", delta);
+    }
+}
+
+#[test]
+fn check_congested_run_against_itself() {
+    // bursts of two requests congest bookinfo, so the order in which the simulator ticks its
+    // elements and edges matters
+    let config = RunConfig {
+        seed: 3,
+        duration: Duration::UntilIdle { max_ticks: 300 },
+        workload: Workload {
+            requests: 20,
+            burst: 2,
+            ..Workload::default()
+        },
+        build: BuildOptions::default(),
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    for _ in 0..3 {
+        let comparison = compare(&Topology::bookinfo(), &config, None).unwrap();
+        for delta in &comparison.metrics {
+            assert!(delta.delta.unwrap_or(0.0) == 0.0, "{:?}", delta);
+        }
+        // full services drop RPCs on arrival, and the report counts them
+        let dropped = comparison
+            .metrics
+            .iter()
+            .find(|delta| delta.metric == "dropped")
+            .unwrap();
+        assert!(dropped.baseline.unwrap() > 0.0, "{:?}", dropped);
+    }
+}
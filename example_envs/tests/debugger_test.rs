@@ -0,0 +1,103 @@
+use example_envs::debugger::{Breakpoint, Command, Debugger};
+use example_envs::runner::Workload;
+use example_envs::topology::{BuildOptions, Topology};
+use std::path::PathBuf;
+
+fn debugger() -> Debugger {
+    let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cargo_dir.push("../target/debug/libfilter_example");
+    let options = BuildOptions {
+        plugin: Some(cargo_dir.to_str().unwrap().to_string()),
+        ..BuildOptions::default()
+    };
+    let simulator = Topology::bookinfo().build(0, &options).unwrap();
+    Debugger::new(simulator, Workload::default(), 100)
+}
+
+#[test]
+fn check_breakpoints_and_inspection() {
+    let mut debugger = debugger();
+    let trace = debugger
+        .execute(Command::Break(Breakpoint::Receive(
+            "ratings-v1".to_string(),
+        )))
+        .unwrap();
+    assert!(trace == "breakpoint 0 (recv ratings-v1)");
+    let output = debugger.execute(Command::Continue).unwrap();
+    assert!(output.contains("breakpoint 0"), "{0}", output);
+    let tick = debugger.tick();
+    assert!(debugger
+        .simulator()
+        .deliveries()
+        .iter()
+        .any(|rpc| rpc.headers["dest"] == "ratings-v1"));
+
+    // the response of ratings carries the trace graph of its filter
+    debugger.execute(Command::Delete(0)).unwrap();
+    debugger.execute(Command::Step(1)).unwrap();
+    assert!(debugger.tick() == tick + 1);
+    let deliveries = debugger.execute(Command::Deliveries).unwrap();
+    let uid: u64 = deliveries
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .trim_end_matches(':')
+        .parse()
+        .unwrap();
+    let rpc = debugger.execute(Command::Rpc(uid, None)).unwrap();
+    assert!(rpc.contains("ferried_data: 1 nodes"), "{0}", rpc);
+    assert!(rpc.contains("ratings-v1#0"), "{0}", rpc);
+
+    let output = debugger.execute(Command::Continue).unwrap();
+    assert!(output.contains("idle"), "{0}", output);
+    let storage = debugger
+        .execute(Command::Inspect("storage".to_string()))
+        .unwrap();
+    assert!(storage.contains("1 records"), "{0}", storage);
+    assert!(debugger
+        .execute(Command::Inspect("nowhere".to_string()))
+        .is_err());
+}
+
+#[test]
+fn check_repl() {
+    let mut debugger = debugger();
+    let script = "break trace 999999\ninject reviews-v2 hello direction=request\ninject gateway hello\nstep 2\ninspect productpage-v1\nbogus\nquit\nstep\n";
+    let mut output = Vec::new();
+    debugger.repl(script.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    // reviews-v2 would not know where to answer
+    assert!(
+        output.contains("error: an RPC for reviews-v2 needs src"),
+        "{0}",
+        output
+    );
+    assert!(output.contains("injected trace"), "{0}", output);
+    assert!(
+        output.contains("error: unknown command bogus"),
+        "{0}",
+        output
+    );
+    // quit ends the session before the last step
+    assert!(debugger.tick() == 2, "{0}", output);
+}
+
+#[test]
+fn check_inject_direction() {
+    let mut debugger = debugger();
+    let script = "inject productpage-v1 hello src=gateway direction=sideways
+inject productpage-v1 hello src=gateway
+step 3
+";
+    let mut output = Vec::new();
+    debugger.repl(script.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(
+        output.contains("error: the direction has to be request or response, not sideways"),
+        "{0}",
+        output
+    );
+    // without a direction, the RPC is a request that the filter can handle
+    assert!(output.contains("injected trace"), "{0}", output);
+    assert!(debugger.tick() == 3, "{0}", output);
+}
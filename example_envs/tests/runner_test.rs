@@ -0,0 +1,135 @@
+use example_envs::runner::{run, Duration, OutputFormat, RunConfig, Workload};
+use example_envs::topology::{BuildOptions, Topology};
+use std::fs;
+use std::path::PathBuf;
+
+fn plugin() -> String {
+    let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cargo_dir.push("../target/debug/libfilter_example");
+    cargo_dir.to_str().unwrap().to_string()
+}
+
+#[test]
+fn check_run_until_idle() {
+    let output_dir = std::env::temp_dir().join("tracing_sim_runner_test");
+    let _ = fs::remove_dir_all(&output_dir);
+    let config = RunConfig {
+        seed: 0,
+        duration: Duration::UntilIdle { max_ticks: 100 },
+        workload: Workload {
+            requests: 3,
+            interval: 2,
+            burst: 1,
+            start: 0,
+        },
+        build: BuildOptions {
+            plugin: Some(plugin()),
+            ..BuildOptions::default()
+        },
+        output_dir: Some(output_dir.clone()),
+        formats: vec![OutputFormat::Ndjson, OutputFormat::Csv],
+    };
+    let result = run(&Topology::bookinfo(), &config).unwrap();
+    let summary = &result.summary;
+    assert!(summary.idle && summary.ticks < 100, "{:?}", summary);
+    assert!(
+        summary.requests == 3 && summary.responses == 3,
+        "{:?}",
+        summary
+    );
+    assert!(summary.records == 3, "{:?}", summary);
+    assert!(summary.outputs.len() == 6);
+
+    // one line per trace, and a header line in csv
+    let traces = fs::read_to_string(output_dir.join("traces.ndjson")).unwrap();
+    assert!(traces.lines().count() == 3);
+    let records = fs::read_to_string(output_dir.join("records.csv")).unwrap();
+    assert!(records.lines().count() == 4);
+    assert!(records.lines().all(|line| !line.contains("reviews")));
+}
+
+#[test]
+fn check_topology_file() {
+    // the gateway sends to frontend, which calls a single backend
+    let path = std::env::temp_dir().join("tracing_sim_topology.json");
+    fs::write(
+        &path,
+        r#"{ "name": "pair", "entry": "frontend",
+             "services": [{ "id": "frontend", "calls": [["backend"]] }, { "id": "backend" }] }"#,
+    )
+    .unwrap();
+    let topology = Topology::load(path.to_str().unwrap()).unwrap();
+    let config = RunConfig {
+        seed: 0,
+        duration: Duration::Ticks(20),
+        workload: Workload::default(),
+        build: BuildOptions::default(),
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    let result = run(&topology, &config).unwrap();
+    assert!(result.summary.ticks == 20 && !result.summary.idle);
+    assert!(result.summary.responses == 1 && result.summary.records == 0);
+    assert!(result.traces[0].error.is_none());
+    assert!(result
+        .metrics
+        .iter()
+        .map(|m| m.service.as_str())
+        .eq(["frontend", "backend"].iter().copied()));
+}
+
+#[test]
+fn check_diamond_topology() {
+    // b and c both call d, so d sees every trace twice
+    let path = std::env::temp_dir().join("tracing_sim_diamond.json");
+    fs::write(
+        &path,
+        r#"{ "name": "diamond", "entry": "a",
+             "services": [{ "id": "a", "calls": [["b"], ["c"]] },
+                          { "id": "b", "calls": [["d"]] }, { "id": "c", "calls": [["d"]] },
+                          { "id": "d", "calls": [["e"]] }, { "id": "e" }] }"#,
+    )
+    .unwrap();
+    let topology = Topology::load(path.to_str().unwrap()).unwrap();
+    let config = RunConfig {
+        seed: 0,
+        duration: Duration::UntilIdle { max_ticks: 200 },
+        workload: Workload {
+            requests: 5,
+            interval: 1,
+            burst: 1,
+            start: 0,
+        },
+        build: BuildOptions::default(),
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    let summary = run(&topology, &config).unwrap().summary;
+    assert!(summary.idle && summary.ticks < 200, "{:?}", summary);
+    assert!(
+        summary.responses == 5 && summary.errors == 0,
+        "{:?}",
+        summary
+    );
+}
+
+#[test]
+fn check_dropped_replies_time_out() {
+    // bursts overflow the services, which then drop calls and replies
+    let config = RunConfig {
+        seed: 3,
+        duration: Duration::UntilIdle { max_ticks: 1000 },
+        workload: Workload {
+            requests: 20,
+            interval: 1,
+            burst: 2,
+            start: 0,
+        },
+        build: BuildOptions::default(),
+        output_dir: None,
+        formats: Vec::new(),
+    };
+    let summary = run(&Topology::bookinfo(), &config).unwrap().summary;
+    assert!(summary.idle && summary.ticks < 1000, "{:?}", summary);
+    assert!(summary.errors > 0, "{:?}", summary);
+}
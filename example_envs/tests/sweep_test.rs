@@ -0,0 +1,60 @@
+use example_envs::sweep::{run_sweep, PluginVariant, SweepRow, SweepSpec};
+use std::path::PathBuf;
+
+fn height_plugin() -> PluginVariant {
+    let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    cargo_dir.push("../target/debug/libfilter_example");
+    PluginVariant {
+        name: "height".to_string(),
+        path: Some(cargo_dir.to_str().unwrap().to_string()),
+    }
+}
+
+#[test]
+fn check_parallel_sweep() {
+    let mut spec =
+        SweepSpec::from_json(r#"{ "seeds": [0, 1, 2], "loads": [{ "requests": 4 }] }"#).unwrap();
+    spec.plugins.push(height_plugin());
+
+    let rows = run_sweep(&spec, 4, None);
+    assert!(rows.len() == 6);
+    assert!(rows.iter().map(|row| row.run).eq(0..6));
+    for row in &rows {
+        assert!(row.error.is_none() && row.idle, "{:?}", row);
+        assert!(row.responses == 4 && row.latency_p50.is_some(), "{:?}", row);
+        // only the filter stores results, and it costs network bytes
+        if row.plugin == "height" {
+            assert!(row.records == 4 && row.network_bytes > rows[0].network_bytes);
+        } else {
+            assert!(row.records == 0);
+        }
+    }
+}
+
+#[test]
+fn check_same_seed_same_row() {
+    // congested runs, where the order of the simulation shows in every metric
+    let mut spec = SweepSpec::from_json(
+        r#"{ "seeds": [3, 3, 3, 3, 3, 3], "loads": [{ "requests": 20, "burst": 2 }], "max_ticks": 300 }"#,
+    )
+    .unwrap();
+    spec.plugins = vec![height_plugin()];
+    for jobs in &[1, 4] {
+        let rows = run_sweep(&spec, *jobs, None);
+        for row in &rows {
+            let same_run = SweepRow {
+                run: 0,
+                ..row.clone()
+            };
+            assert!(
+                same_run
+                    == SweepRow {
+                        run: 0,
+                        ..rows[0].clone()
+                    },
+                "{:?}",
+                rows
+            );
+        }
+    }
+}
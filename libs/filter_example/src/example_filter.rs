@@ -8,16 +8,32 @@ use log4rs::{
     encode::pattern::PatternEncoder,
     filter::threshold::ThresholdFilter,
 };
-use petgraph::graph::{Graph, NodeIndex};
+use petgraph::graph::NodeIndex;
 use petgraph::Incoming;
 use rpc_lib::rpc::Rpc;
 use utils::graph::graph_utils;
-use utils::graph::iso::find_mapping_shamir_centralized;
+use utils::graph::graph_utils::TraceGraph;
+use utils::graph::matcher::{matcher_by_name, SubgraphMatcher};
 use utils::graph::serde::FerriedData;
 
-extern crate serde_json;
-
 pub type CodeletType = fn(&Filter, &Rpc) -> Option<Rpc>;
+
+// The span of a call travels in this header:  a request carries the span of its caller, the
+// filter of the callee puts in its own span while the call is inside the service, and puts the
+// caller's back when the response leaves.  With it, a service called twice in one trace keeps
+// the state of both calls apart.
+pub const SPAN_HEADER: &str = "span";
+
+// The key of the state of one call
+fn call_key(uid: u64, span: &str) -> String {
+    format!("{0}/{1}", uid, span)
+}
+
+// The calls a filter keeps state for, unless the "max_open_calls" property says otherwise.  A
+// call whose request is dropped after the filter saw it never gets a response, so its state is
+// forgotten once there are too many calls, oldest first.
+const DEFAULT_MAX_OPEN_CALLS: usize = 1024;
+
 fn log_setup() {
     // Build a stderr logger.
     let stderr = ConsoleAppender::builder()
@@ -55,17 +71,23 @@ fn log_setup() {
 }
 
 fn put_ferried_data_in_hdrs(fd: &mut FerriedData, hdr: &mut IndexMap<String, String>) {
-    match serde_json::to_string(fd) {
-        Ok(stored_data_string) => {
-            hdr.insert("ferried_data".to_string(), stored_data_string);
-        }
-        Err(e) => {
-            log::error!(
-                "ERROR:  could not translate stored data to json string: {0}\n",
-                e
-            );
-        }
-    }
+    hdr.insert("ferried_data".to_string(), fd.encode());
+}
+
+// Responses only carry what the caller has not seen in its request
+fn put_delta_in_hdrs(
+    upstream: Option<String>,
+    fd: &FerriedData,
+    hdr: &mut IndexMap<String, String>,
+) {
+    let base = match upstream {
+        Some(upstream) => FerriedData::decode(&upstream).unwrap_or_else(|e| {
+            log::error!("could not parse upstream ferried data: {0}\n", e);
+            FerriedData::default()
+        }),
+        None => FerriedData::default(),
+    };
+    hdr.insert("ferried_data".to_string(), fd.delta_since(&base).encode());
 }
 
 // user defined functions:
@@ -74,15 +96,12 @@ fn put_ferried_data_in_hdrs(fd: &mut FerriedData, hdr: &mut IndexMap<String, Str
 // mid_func: mid_height
 // id: height
 
-fn leaf_height(_graph: &Graph<(String, IndexMap<String, String>), ()>) -> u32 {
+fn leaf_height(_graph: &TraceGraph) -> u32 {
     return 0;
 }
 
 // TODO:  must children's responses always be in string form?  can we generalize?
-fn mid_height(
-    _graph: &Graph<(String, IndexMap<String, String>), ()>,
-    children_responses: Vec<String>,
-) -> u32 {
+fn mid_height(_graph: &TraceGraph, children_responses: Vec<String>) -> u32 {
     let mut max = 0;
     for response in children_responses {
         let response_as_u32 = response.parse::<u32>();
@@ -100,13 +119,7 @@ fn mid_height(
     return max + 1;
 }
 
-pub fn create_target_graph() -> Graph<
-    (
-        std::string::String,
-        IndexMap<std::string::String, std::string::String>,
-    ),
-    (),
-> {
+pub fn create_target_graph() -> TraceGraph {
     let vertices = vec!["a".to_string(), "b".to_string(), "c".to_string()];
     let edges = vec![
         ("a".to_string(), "b".to_string()),
@@ -121,11 +134,14 @@ pub fn create_target_graph() -> Graph<
 
 pub fn collect_envoy_properties(_filter: &Filter, _fd: &mut FerriedData) {}
 
-pub fn execute_udfs_and_check_trace_lvl_prop(filter: &Filter, fd: &mut FerriedData) -> bool {
+pub fn execute_udfs_and_check_trace_lvl_prop(
+    _filter: &Filter,
+    span: &str,
+    fd: &mut FerriedData,
+) -> bool {
     let my_height_value;
     let child_iterator = fd.trace_graph.neighbors_directed(
-        graph_utils::get_node_with_id(&fd.trace_graph, filter.whoami.as_ref().unwrap().clone())
-            .unwrap(),
+        graph_utils::get_node_with_id(&fd.trace_graph, span.to_string()).unwrap(),
         petgraph::Outgoing,
     );
     let mut child_values = Vec::new();
@@ -138,9 +154,7 @@ pub fn execute_udfs_and_check_trace_lvl_prop(filter: &Filter, fd: &mut FerriedDa
         my_height_value = mid_height(&fd.trace_graph, child_values).to_string();
     }
 
-    let node =
-        graph_utils::get_node_with_id(&fd.trace_graph, filter.whoami.as_ref().unwrap().to_string())
-            .unwrap();
+    let node = graph_utils::get_node_with_id(&fd.trace_graph, span.to_string()).unwrap();
     // if we already have the property, don't add it
     if !(fd
         .trace_graph
@@ -161,13 +175,7 @@ pub fn execute_udfs_and_check_trace_lvl_prop(filter: &Filter, fd: &mut FerriedDa
 }
 
 pub fn get_value_for_storage(
-    target_graph: &Graph<
-        (
-            std::string::String,
-            IndexMap<std::string::String, std::string::String>,
-        ),
-        (),
-    >,
+    target_graph: &TraceGraph,
     mapping: &Vec<(NodeIndex, NodeIndex)>,
     fd: &FerriedData,
 ) -> Option<String> {
@@ -209,9 +217,12 @@ pub fn get_value_for_storage(
 #[derive(Clone, Debug)]
 pub struct Filter {
     pub whoami: Option<String>,
-    pub target_graph: Option<Graph<(String, IndexMap<String, String>), ()>>,
+    pub target_graph: Option<TraceGraph>,
     pub filter_state: IndexMap<String, String>,
-    pub envoy_shared_data: IndexMap<String, String>, // trace ID to stored ferried data as string
+    pub envoy_shared_data: IndexMap<String, String>, // call to stored ferried data as string
+    pub upstream_data: IndexMap<String, String>,     // call to the ferried data the caller sent
+    pub caller_spans: IndexMap<String, String>,      // call to the span of its caller, if any
+    pub span_count: u64,                             // spans this filter has started
     pub collected_properties: Vec<String>,           //properties to collect
 }
 
@@ -224,6 +235,9 @@ impl Filter {
             target_graph: None,
             filter_state: IndexMap::new(),
             envoy_shared_data: IndexMap::<String, String>::new(),
+            upstream_data: IndexMap::new(),
+            caller_spans: IndexMap::new(),
+            span_count: 0,
             collected_properties: vec!["height".to_string()],
         }))
     }
@@ -236,6 +250,9 @@ impl Filter {
             target_graph: None,
             filter_state: string_data,
             envoy_shared_data: IndexMap::new(),
+            upstream_data: IndexMap::new(),
+            caller_spans: IndexMap::new(),
+            span_count: 0,
             collected_properties: vec!["height".to_string()],
         }))
     }
@@ -251,6 +268,28 @@ impl Filter {
         assert!(self.whoami.is_some());
     }
 
+    // The subgraph matcher named by the "matcher" property, shamir by default
+    pub fn matcher(&self) -> Box<dyn SubgraphMatcher<String>> {
+        let name = self
+            .filter_state
+            .get("matcher")
+            .map_or("shamir", |name| name.as_str());
+        match matcher_by_name(name) {
+            Some(matcher) => matcher,
+            None => {
+                log::warn!("unknown matcher {0}, using shamir\n", name);
+                matcher_by_name("shamir").unwrap()
+            }
+        }
+    }
+
+    // The storage sink the results go to, named by the "sink" property, "storage" by default
+    pub fn sink(&self) -> &str {
+        self.filter_state
+            .get("sink")
+            .map_or("storage", |sink| sink.as_str())
+    }
+
     pub fn set_whoami(&mut self) {
         if !self
             .filter_state
@@ -264,24 +303,64 @@ impl Filter {
         assert!(self.whoami.is_some());
     }
 
-    pub fn store_headers(&mut self, uid_64: u64, headers: IndexMap<String, String>) {
+    // Every request this service receives is a new span of the trace
+    fn start_span(&mut self, x: &mut Rpc) -> String {
+        let span = graph_utils::span_id(self.whoami.as_ref().unwrap(), self.span_count);
+        self.span_count += 1;
+        if let Some(caller_span) = x.headers.insert(SPAN_HEADER.to_string(), span.clone()) {
+            self.caller_spans
+                .insert(call_key(x.uid, &span), caller_span);
+        }
+        span
+    }
+
+    // The span of the call an RPC inside this service belongs to;  an RPC that did not come
+    // in as a request starts one
+    fn current_span(&mut self, x: &mut Rpc) -> String {
+        match x.headers.get(SPAN_HEADER) {
+            Some(span) => span.clone(),
+            None => self.start_span(x),
+        }
+    }
+
+    // Forgets the oldest calls beyond the "max_open_calls" property
+    fn forget_old_calls(&mut self) {
+        let max_open_calls = self
+            .filter_state
+            .get("max_open_calls")
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_MAX_OPEN_CALLS);
+        for calls in [
+            &mut self.envoy_shared_data,
+            &mut self.upstream_data,
+            &mut self.caller_spans,
+        ] {
+            while calls.len() > max_open_calls {
+                if let Some((key, _)) = calls.shift_remove_index(0) {
+                    log::debug!("forgetting call {0}, it got no response\n", key);
+                }
+            }
+        }
+    }
+
+    pub fn store_headers(&mut self, key: &str, headers: IndexMap<String, String>) {
         // If you don't have data, nothing to store
         if !headers.contains_key("ferried_data") {
             log::warn!("no ferried data\n");
             return;
         }
-        let uid = uid_64.to_string();
+        let uid = key.to_string();
         // If there is no data stored, you needn't merge - just throw it in
         if !self.envoy_shared_data.contains_key(&uid) {
             self.envoy_shared_data
                 .insert(uid.clone(), headers["ferried_data"].clone());
         }
 
-        // Else, we merge in 2 parts, for each of the struct values
-        let mut data: FerriedData;
+        // Else, we merge it into what is stored
+        let data: FerriedData;
         let mut stored_data: FerriedData;
 
-        match serde_json::from_str(&headers["ferried_data"]) {
+        match FerriedData::decode(&headers["ferried_data"]) {
             Ok(d) => {
                 data = d;
             }
@@ -290,7 +369,7 @@ impl Filter {
                 return;
             }
         }
-        match serde_json::from_str(&self.envoy_shared_data[&uid]) {
+        match FerriedData::decode(&self.envoy_shared_data[&uid]) {
             Ok(d) => {
                 stored_data = d;
             }
@@ -300,94 +379,51 @@ impl Filter {
             }
         }
 
-        // 2. Merge the graphs by simply adding it - later, when we merge, we will
-        //    make a root
-
-        // add node
-        for node in data.trace_graph.node_indices() {
-            stored_data
-                .trace_graph
-                .add_node(data.trace_graph.node_weight(node).unwrap().clone());
-        }
-        // add edges
-        for edge in data.trace_graph.edge_indices() {
-            match data.trace_graph.edge_endpoints(edge) {
-                Some((edge0, edge1)) => {
-                    let edge0_weight = &data.trace_graph.node_weight(edge0).unwrap().0;
-                    let edge1_weight = &data.trace_graph.node_weight(edge1).unwrap().0;
-                    let edge0_in_stored_graph = graph_utils::get_node_with_id(
-                        &stored_data.trace_graph,
-                        edge0_weight.to_string(),
-                    )
-                    .unwrap();
-                    let edge1_in_stored_graph = graph_utils::get_node_with_id(
-                        &stored_data.trace_graph,
-                        edge1_weight.to_string(),
-                    )
-                    .unwrap();
-                    stored_data.trace_graph.add_edge(
-                        edge0_in_stored_graph,
-                        edge1_in_stored_graph,
-                        (),
-                    );
-                }
-                None => {
-                    log::error!("no edge endpoints found \n");
-                    return;
-                }
-            }
-        }
+        // 2. Merge the graphs - later, when we merge, we will make a root.  The data
+        //    may be a delta from a response, which merging applies
+        stored_data.merge(data);
 
-        // 3. merge unassigned properties
-        //    these are properties we have collected but are not yet in the graph
-        stored_data
-            .unassigned_properties
-            .append(&mut data.unassigned_properties);
-        stored_data.unassigned_properties.sort_unstable();
-        stored_data.unassigned_properties.dedup();
-        stored_data.assign_properties();
-
-        match serde_json::to_string(&stored_data) {
-            Ok(stored_data_string) => {
-                self.envoy_shared_data.insert(uid, stored_data_string);
-            }
-            Err(e) => {
-                log::error!("could not translate stored data to json string: {0}\n", e);
-            }
-        }
+        self.envoy_shared_data.insert(uid, stored_data.encode());
     }
 
     pub fn merge_headers(
         &mut self,
-        uid: u64,
+        key: &str,
+        span: &str,
         mut new_rpc_headers: IndexMap<String, String>,
     ) -> IndexMap<String, String> {
-        let uid_str = uid.to_string();
         let mut my_indexmap = IndexMap::new();
         my_indexmap.insert(
-            "node.metadata.WORKLOAD_NAME".to_string(),
+            graph_utils::SERVICE_NAME.to_string(),
             self.whoami.as_ref().unwrap().clone(),
         );
 
-        if self.envoy_shared_data.contains_key(&uid_str) {
-            match serde_json::from_str(&self.envoy_shared_data[&uid_str]) {
+        if self.envoy_shared_data.contains_key(key) {
+            match FerriedData::decode(&self.envoy_shared_data[key]) {
                 Ok(d) => {
                     // 1. TODO:  if needed, do things to set S
                     // 2. If response, add yourself as root
                     if new_rpc_headers["direction"] == "response" {
                         let mut data: FerriedData = d;
+                        let me = match graph_utils::get_node_with_id(
+                            &data.trace_graph,
+                            span.to_string(),
+                        ) {
+                            Some(me) => me,
+                            None => data.trace_graph.add_node((span.to_string(), my_indexmap)),
+                        };
                         let mut previous_roots = Vec::new();
                         for node in data.trace_graph.node_indices() {
-                            if data.trace_graph.neighbors_directed(node, Incoming).count() == 0 {
+                            if node != me
+                                && data.trace_graph.neighbors_directed(node, Incoming).count() == 0
+                            {
                                 previous_roots.push(node);
                             }
                         }
-                        let me = data
-                            .trace_graph
-                            .add_node((self.whoami.as_ref().unwrap().to_string(), my_indexmap));
 
                         for previous_root in previous_roots {
-                            data.trace_graph.add_edge(me, previous_root, ());
+                            data.trace_graph
+                                .add_edge(me, previous_root, IndexMap::new());
                         }
                         data.assign_properties();
 
@@ -403,27 +439,32 @@ impl Filter {
             let mut new_ferried_data = FerriedData::default();
             new_ferried_data
                 .trace_graph
-                .add_node((self.whoami.as_ref().unwrap().to_string(), my_indexmap));
+                .add_node((span.to_string(), my_indexmap));
             put_ferried_data_in_hdrs(&mut new_ferried_data, &mut new_rpc_headers);
         }
         return new_rpc_headers;
     }
 
     pub fn on_incoming_requests(&mut self, mut x: Rpc) -> Vec<Rpc> {
-        // Fetch ferried data
+        let span = self.start_span(&mut x);
+        let key = call_key(x.uid, &span);
+
+        // Fetch ferried data, and remember what the caller has seen
         let mut ferried_data: FerriedData;
         if !x.headers.contains_key("ferried_data") {
             ferried_data = FerriedData::default();
         } else {
-            match serde_json::from_str(&x.headers["ferried_data"]) {
+            match FerriedData::decode(&x.headers["ferried_data"]) {
                 Ok(fd) => {
                     ferried_data = fd;
                 }
                 Err(e) => {
-                    log::error!("could not translate stored data to json string: {0}\n", e);
+                    log::error!("could not parse ferried data: {0}\n", e);
                     return vec![x];
                 }
             }
+            self.upstream_data
+                .insert(key.clone(), x.headers["ferried_data"].clone());
         }
 
         // Insert properties to collect
@@ -431,13 +472,24 @@ impl Filter {
 
         // Return ferried data to x, and store headers
         put_ferried_data_in_hdrs(&mut ferried_data, &mut x.headers);
-        self.store_headers(x.uid, x.headers.clone());
+        self.store_headers(&key, x.headers.clone());
+        self.forget_old_calls();
         return vec![x];
     }
 
     pub fn on_outgoing_responses(&mut self, mut x: Rpc) -> Vec<Rpc> {
         // 0. Look up stored baggage, and merge it
-        x.headers = self.merge_headers(x.uid, x.headers);
+        let span = self.current_span(&mut x);
+        let key = call_key(x.uid, &span);
+        x.headers = self.merge_headers(&key, &span, x.headers);
+        // the response ends this call, also when it reports a timeout, and goes back to the
+        // span of the caller
+        self.envoy_shared_data.shift_remove(&key);
+        let upstream = self.upstream_data.shift_remove(&key);
+        match self.caller_spans.shift_remove(&key) {
+            Some(caller_span) => x.headers.insert(SPAN_HEADER.to_string(), caller_span),
+            None => x.headers.shift_remove(SPAN_HEADER),
+        };
 
         // at most, we return two rpcs:  one to continue on and one to storage
         let mut original_rpc = x.clone();
@@ -449,7 +501,7 @@ impl Filter {
         if !original_rpc.headers.contains_key("ferried_data") {
             ferried_data = FerriedData::default();
         } else {
-            match serde_json::from_str(&mut original_rpc.headers["ferried_data"]) {
+            match FerriedData::decode(&original_rpc.headers["ferried_data"]) {
                 Ok(fd) => {
                     ferried_data = fd;
                 }
@@ -461,27 +513,34 @@ impl Filter {
         }
 
         let root_id = "productpage-v1";
-        let trace_prop_sat = execute_udfs_and_check_trace_lvl_prop(self, &mut ferried_data);
+        let trace_prop_sat = execute_udfs_and_check_trace_lvl_prop(self, &span, &mut ferried_data);
         // 3. perform isomorphism and possibly return if root node
         if trace_prop_sat && self.whoami.as_ref().unwrap() == root_id {
-            let mapping = find_mapping_shamir_centralized(
+            let mapping = match self.matcher().find_mapping(
                 &ferried_data.trace_graph,
                 self.target_graph.as_ref().unwrap(),
-            );
+            ) {
+                Ok(mapping) => mapping,
+                Err(e) => {
+                    log::error!("could not match the target graph: {0}\n", e);
+                    None
+                }
+            };
             if mapping.is_some() {
                 let m = mapping.unwrap();
                 let value =
                     get_value_for_storage(self.target_graph.as_ref().unwrap(), &m, &ferried_data);
                 if value.is_none() {
-                    put_ferried_data_in_hdrs(&mut ferried_data, &mut original_rpc.headers);
+                    put_delta_in_hdrs(upstream, &ferried_data, &mut original_rpc.headers);
                     return vec![original_rpc];
                 }
                 // Now you have the return value, so
                 // 3a. Make a storage rpc
                 storage_rpc = Rpc::new_with_src(&value.unwrap(), self.whoami.as_ref().unwrap());
+                storage_rpc.uid = original_rpc.uid; // the record belongs to this trace
                 storage_rpc
                     .headers
-                    .insert("dest".to_string(), "storage".to_string());
+                    .insert("dest".to_string(), self.sink().to_string());
                 storage_rpc
                     .headers
                     .insert("direction".to_string(), "request".to_string());
@@ -490,21 +549,29 @@ impl Filter {
                     .insert("src".to_string(), self.whoami.clone().unwrap());
 
                 // 3b. Put baggage into regular rpc
-                put_ferried_data_in_hdrs(&mut ferried_data, &mut original_rpc.headers);
+                put_delta_in_hdrs(upstream, &ferried_data, &mut original_rpc.headers);
                 return vec![original_rpc, storage_rpc];
             }
         }
-        put_ferried_data_in_hdrs(&mut ferried_data, &mut original_rpc.headers);
+        put_delta_in_hdrs(upstream, &ferried_data, &mut original_rpc.headers);
         return vec![original_rpc];
     }
 
     pub fn on_outgoing_requests(&mut self, mut x: Rpc) -> Vec<Rpc> {
-        x.headers = self.merge_headers(x.uid, x.headers);
+        let span = self.current_span(&mut x);
+        x.headers = self.merge_headers(&call_key(x.uid, &span), &span, x.headers);
         return vec![x];
     }
 
-    pub fn on_incoming_responses(&mut self, x: Rpc) -> Vec<Rpc> {
-        self.store_headers(x.uid, x.headers.clone());
+    pub fn on_incoming_responses(&mut self, mut x: Rpc) -> Vec<Rpc> {
+        // the callee put back the span of this service;  a late response, e.g. to a call that
+        // timed out, has no call to go to any more
+        let key = call_key(x.uid, &self.current_span(&mut x));
+        if self.envoy_shared_data.contains_key(&key) {
+            self.store_headers(&key, x.headers.clone());
+        } else {
+            log::debug!("no open call {0} for this response\n", key);
+        }
         return vec![x];
     }
 
@@ -541,3 +608,135 @@ impl Filter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(service: &str) -> Filter {
+        let mut properties = IndexMap::new();
+        properties.insert(
+            "node.metadata.WORKLOAD_NAME".to_string(),
+            service.to_string(),
+        );
+        let mut filter = Filter {
+            whoami: None,
+            target_graph: None,
+            filter_state: properties,
+            envoy_shared_data: IndexMap::new(),
+            upstream_data: IndexMap::new(),
+            caller_spans: IndexMap::new(),
+            span_count: 0,
+            collected_properties: vec!["height".to_string()],
+        };
+        filter.init_filter();
+        filter
+    }
+
+    fn request(uid: u64, caller_span: &str) -> Rpc {
+        let mut rpc = Rpc::new("0");
+        rpc.uid = uid;
+        for (key, value) in [
+            ("direction", "request"),
+            ("location", "ingress"),
+            (SPAN_HEADER, caller_span),
+        ]
+        .iter()
+        {
+            rpc.headers.insert(key.to_string(), value.to_string());
+        }
+        rpc
+    }
+
+    // What the service answers to a request that went through the filter
+    fn response_to(request: &Rpc) -> Rpc {
+        let mut rpc = request.clone();
+        rpc.headers
+            .insert("direction".to_string(), "response".to_string());
+        rpc.headers
+            .insert("location".to_string(), "egress".to_string());
+        rpc
+    }
+
+    fn spans(response: &Rpc) -> Vec<String> {
+        let data = FerriedData::decode(&response.headers["ferried_data"]).unwrap();
+        data.trace_graph
+            .node_indices()
+            .map(|node| data.trace_graph[node].0.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_sink_property() {
+        let mut filter = filter("productpage-v1");
+        assert!(filter.sink() == "storage");
+        filter
+            .filter_state
+            .insert("sink".to_string(), "slow-storage".to_string());
+        assert!(filter.sink() == "slow-storage");
+    }
+
+    #[test]
+    fn test_two_calls_of_one_trace() {
+        let mut filter = filter("reviews-v1");
+        // both calls are inside the service at the same time
+        let first = filter.execute(&request(7, "productpage-v1#0")).remove(0);
+        let second = filter.execute(&request(7, "productpage-v1#0")).remove(0);
+        assert!(first.headers[SPAN_HEADER] == "reviews-v1#0");
+        assert!(second.headers[SPAN_HEADER] == "reviews-v1#1");
+        // the first response closes the first span
+        let response = filter.execute(&response_to(&first)).remove(0);
+        assert!(response.headers[SPAN_HEADER] == "productpage-v1#0");
+        assert!(spans(&response) == vec!["reviews-v1#0"]);
+        let response = filter.execute(&response_to(&second)).remove(0);
+        assert!(spans(&response) == vec!["reviews-v1#1"]);
+
+        // a later call of the trace does not see the spans of the earlier ones
+        let third = filter.execute(&request(7, "productpage-v1#0")).remove(0);
+        let response = filter.execute(&response_to(&third)).remove(0);
+        assert!(spans(&response) == vec!["reviews-v1#2"]);
+        assert!(filter.envoy_shared_data.is_empty() && filter.caller_spans.is_empty());
+    }
+
+    #[test]
+    fn test_calls_without_response() {
+        let mut filter = filter("reviews-v1");
+        filter
+            .filter_state
+            .insert("max_open_calls".to_string(), "2".to_string());
+        let with_data = |uid: u64| {
+            let mut rpc = request(uid, "productpage-v1#0");
+            rpc.headers
+                .insert("ferried_data".to_string(), FerriedData::default().encode());
+            rpc
+        };
+        // a call that gets its response, e.g. a timeout, leaves nothing behind
+        let call = filter.execute(&with_data(7)).remove(0);
+        assert!(filter.upstream_data.len() == 1);
+        let mut timeout = response_to(&call);
+        timeout
+            .headers
+            .insert("error".to_string(), "timeout".to_string());
+        filter.execute(&timeout);
+        assert!(filter.upstream_data.is_empty() && filter.envoy_shared_data.is_empty());
+
+        // a late response of a downstream call does not open the call again
+        let mut late = response_to(&call);
+        late.headers
+            .insert("location".to_string(), "ingress".to_string());
+        filter.execute(&late);
+        assert!(filter.envoy_shared_data.is_empty());
+
+        // requests dropped after the filter saw them are forgotten, oldest first
+        for uid in 8..11 {
+            filter.execute(&with_data(uid));
+        }
+        assert!(filter.upstream_data.len() == 2 && filter.envoy_shared_data.len() == 2);
+        assert!(!filter
+            .upstream_data
+            .contains_key(&call_key(8, "reviews-v1#1")));
+        assert!(filter
+            .upstream_data
+            .contains_key(&call_key(10, "reviews-v1#3")));
+    }
+}
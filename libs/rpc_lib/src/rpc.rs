@@ -1,4 +1,5 @@
 use indexmap::map::IndexMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(PartialEq, Clone, Debug)]
 #[repr(C)]
@@ -10,18 +11,13 @@ pub struct Rpc {
 
 impl Rpc {
     pub fn new(data: &str) -> Self {
-        static mut COUNTER: u64 = 0;
-        let ret = unsafe {
-            Rpc {
-                data: data.to_string(),
-                uid: COUNTER,
-                headers: IndexMap::new(),
-            }
-        };
-        unsafe {
-            COUNTER += 1;
+        // shared by every simulation, including those running in other threads
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        Rpc {
+            data: data.to_string(),
+            uid: COUNTER.fetch_add(1, Ordering::Relaxed),
+            headers: IndexMap::new(),
         }
-        ret
     }
 
     pub fn new_with_src(data: &str, src: &str) -> Self {
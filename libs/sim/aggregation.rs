@@ -0,0 +1,328 @@
+//! Built-in aggregations over storage records, so common queries do not need an aggregation
+//! filter.  Records can be grouped by a key (e.g. per service) and bucketed into tumbling or
+//! sliding windows of ticks; each group of each window is then reduced by an aggregator.
+//! Values are read from the payload of a record or from one of its fields.
+
+use crate::storage::{GroupKey, StorageRecord};
+use indexmap::map::IndexMap;
+
+pub enum Aggregator {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Histogram(Vec<f64>), // upper bounds of the buckets, in increasing order
+    Percentile(f64),     // between 0 and 100, nearest rank
+    DistinctCount,
+}
+
+pub enum ValueSource {
+    Payload,
+    Field(String),
+}
+
+pub enum Window {
+    All,
+    Tumbling(u64),                    // consecutive windows of this many ticks
+    Sliding { size: u64, step: u64 }, // windows of `size` ticks, starting every `step` ticks
+}
+
+impl Window {
+    // The [start, end) windows a record stored at the tick falls into
+    fn windows_of(&self, tick: u64) -> Vec<(u64, u64)> {
+        match self {
+            Window::All => vec![(0, u64::MAX)],
+            Window::Tumbling(size) => {
+                let start = tick / size * size;
+                vec![(start, start + size)]
+            }
+            Window::Sliding { size, step } => {
+                let mut windows = vec![];
+                // the first window that still contains the tick
+                let mut start = if tick >= *size {
+                    (tick - size) / step * step + step
+                } else {
+                    0
+                };
+                while start <= tick {
+                    if tick < start + size {
+                        windows.push((start, start + size));
+                    }
+                    start += step;
+                }
+                windows
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregateValue {
+    Number(f64),
+    Histogram(Vec<u64>), // one count per bucket, plus one for values above the last bound
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateRow {
+    pub window: (u64, u64),
+    pub group: Option<String>, // None if the aggregation is not grouped
+    pub value: AggregateValue,
+}
+
+// A window and the group in it
+type Bucket = ((u64, u64), Option<String>);
+
+pub struct Aggregation {
+    pub aggregator: Aggregator,
+    pub value: ValueSource,
+    pub group_by: Option<GroupKey>,
+    pub window: Window,
+}
+
+impl Aggregation {
+    // Aggregates the payloads of all records, without groups or windows
+    pub fn new(aggregator: Aggregator) -> Aggregation {
+        Aggregation {
+            aggregator,
+            value: ValueSource::Payload,
+            group_by: None,
+            window: Window::All,
+        }
+    }
+
+    pub fn of_field(mut self, field: &str) -> Aggregation {
+        self.value = ValueSource::Field(field.to_string());
+        self
+    }
+
+    pub fn by(mut self, key: GroupKey) -> Aggregation {
+        self.group_by = Some(key);
+        self
+    }
+
+    pub fn over(mut self, window: Window) -> Aggregation {
+        if let Window::Tumbling(size) | Window::Sliding { size, .. } = window {
+            assert!(size > 0);
+        }
+        if let Window::Sliding { step, .. } = window {
+            assert!(step > 0);
+        }
+        self.window = window;
+        self
+    }
+
+    /// One row per window and group that has values, ordered by the start of the window and
+    /// then by the order in which the groups were first seen.
+    pub fn apply(&self, records: &[&StorageRecord]) -> Vec<AggregateRow> {
+        let mut buckets: IndexMap<Bucket, Vec<String>> = IndexMap::new();
+        for record in records {
+            let value = match &self.value {
+                ValueSource::Payload => record.payload.clone(),
+                ValueSource::Field(field) => match record.fields.get(field) {
+                    Some(value) => value.clone(),
+                    None => continue,
+                },
+            };
+            let group = match &self.group_by {
+                Some(key) => match key.group_of(record) {
+                    Some(group) => Some(group),
+                    None => continue,
+                },
+                None => None,
+            };
+            for window in self.window.windows_of(record.tick) {
+                buckets
+                    .entry((window, group.clone()))
+                    .or_insert_with(Vec::new)
+                    .push(value.clone());
+            }
+        }
+        let mut rows = vec![];
+        for ((window, group), values) in buckets {
+            if let Some(value) = self.reduce(&values) {
+                rows.push(AggregateRow {
+                    window,
+                    group,
+                    value,
+                });
+            }
+        }
+        // stable, so groups stay in the order they were seen
+        rows.sort_by_key(|row| row.window.0);
+        rows
+    }
+
+    fn reduce(&self, values: &[String]) -> Option<AggregateValue> {
+        match &self.aggregator {
+            Aggregator::Count => return Some(AggregateValue::Number(values.len() as f64)),
+            Aggregator::DistinctCount => {
+                let mut distinct = values.to_vec();
+                distinct.sort();
+                distinct.dedup();
+                return Some(AggregateValue::Number(distinct.len() as f64));
+            }
+            _ => {}
+        }
+        let mut numbers = vec![];
+        for value in values {
+            // NaN parses, but has no place in a sum or an order
+            match value.trim().parse::<f64>() {
+                Ok(number) if !number.is_nan() => numbers.push(number),
+                _ => log::debug!("skipping non-numeric value {0} in aggregation", value),
+            }
+        }
+        if numbers.is_empty() {
+            return None;
+        }
+        let value = match &self.aggregator {
+            Aggregator::Sum => numbers.iter().sum(),
+            Aggregator::Avg => numbers.iter().sum::<f64>() / numbers.len() as f64,
+            Aggregator::Min => numbers.iter().cloned().fold(f64::INFINITY, f64::min),
+            Aggregator::Max => numbers.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            Aggregator::Percentile(p) => {
+                numbers.sort_by(|a, b| a.total_cmp(b));
+                percentile(&numbers, *p)?
+            }
+            Aggregator::Histogram(bounds) => {
+                let mut counts = vec![0; bounds.len() + 1];
+                for number in numbers {
+                    let bucket = bounds
+                        .iter()
+                        .position(|bound| number <= *bound)
+                        .unwrap_or(bounds.len());
+                    counts[bucket] += 1;
+                }
+                return Some(AggregateValue::Histogram(counts));
+            }
+            Aggregator::Count | Aggregator::DistinctCount => unreachable!(),
+        };
+        Some(AggregateValue::Number(value))
+    }
+}
+
+// The value that p percent of the sorted values are at most (nearest rank)
+pub fn percentile<T: Copy>(sorted: &[T], p: f64) -> Option<T> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: &str, tick: u64, payload: &str) -> StorageRecord {
+        StorageRecord {
+            trace_id: tick,
+            source: source.to_string(),
+            tick,
+            fields: IndexMap::new(),
+            payload: payload.to_string(),
+        }
+    }
+
+    fn numbers(rows: &[AggregateRow]) -> Vec<f64> {
+        rows.iter()
+            .map(|row| match row.value {
+                AggregateValue::Number(number) => number,
+                AggregateValue::Histogram(_) => panic!("expected a number"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keyed_aggregators() {
+        let records = [
+            record("reviews-v1", 0, "4"),
+            record("reviews-v2", 1, "1"),
+            record("reviews-v1", 2, "2"),
+            record("reviews-v1", 3, "not a number"),
+        ];
+        let refs: Vec<&StorageRecord> = records.iter().collect();
+        let by_source = |aggregator| Aggregation::new(aggregator).by(GroupKey::Source);
+
+        let counts = by_source(Aggregator::Count).apply(&refs);
+        assert!(counts[0].group == Some("reviews-v1".to_string()));
+        assert!(numbers(&counts) == vec![3.0, 1.0]);
+        assert!(numbers(&by_source(Aggregator::Sum).apply(&refs)) == vec![6.0, 1.0]);
+        assert!(numbers(&by_source(Aggregator::Avg).apply(&refs)) == vec![3.0, 1.0]);
+        assert!(numbers(&by_source(Aggregator::Min).apply(&refs)) == vec![2.0, 1.0]);
+        assert!(numbers(&by_source(Aggregator::Max).apply(&refs)) == vec![4.0, 1.0]);
+        let distinct = Aggregation::new(Aggregator::DistinctCount).apply(&refs);
+        assert!(distinct[0].group.is_none() && numbers(&distinct) == vec![4.0]);
+    }
+
+    #[test]
+    fn test_windows() {
+        let records: Vec<StorageRecord> = (0..6).map(|tick| record("a", tick, "1")).collect();
+        let refs: Vec<&StorageRecord> = records.iter().collect();
+        let tumbling = Aggregation::new(Aggregator::Sum)
+            .over(Window::Tumbling(4))
+            .apply(&refs);
+        assert!(tumbling[0].window == (0, 4) && tumbling[1].window == (4, 8));
+        assert!(numbers(&tumbling) == vec![4.0, 2.0]);
+
+        let sliding = Aggregation::new(Aggregator::Count)
+            .over(Window::Sliding { size: 4, step: 2 })
+            .apply(&refs);
+        let windows: Vec<(u64, u64)> = sliding.iter().map(|row| row.window).collect();
+        assert!(windows == vec![(0, 4), (2, 6), (4, 8)]);
+        assert!(numbers(&sliding) == vec![4.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn test_percentile_and_histogram() {
+        let records: Vec<StorageRecord> = (1..=10)
+            .map(|value| {
+                let mut record = record("a", 0, "");
+                record
+                    .fields
+                    .insert("latency".to_string(), value.to_string());
+                record
+            })
+            .collect();
+        let refs: Vec<&StorageRecord> = records.iter().collect();
+        let p90 = Aggregation::new(Aggregator::Percentile(90.0))
+            .of_field("latency")
+            .apply(&refs);
+        assert!(numbers(&p90) == vec![9.0]);
+        let p0 = Aggregation::new(Aggregator::Percentile(0.0))
+            .of_field("latency")
+            .apply(&refs);
+        assert!(numbers(&p0) == vec![1.0]);
+        let latencies = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert!(percentile(&latencies, 50.0) == Some(5));
+        assert!(percentile(&latencies, 99.0) == Some(10));
+        assert!(percentile::<u64>(&[], 50.0).is_none());
+        let histogram = Aggregation::new(Aggregator::Histogram(vec![2.0, 5.0]))
+            .of_field("latency")
+            .apply(&refs);
+        assert!(histogram[0].value == AggregateValue::Histogram(vec![2, 3, 5]));
+        // records without the field are left out
+        let missing = Aggregation::new(Aggregator::Count)
+            .of_field("size")
+            .apply(&refs);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_non_finite_values() {
+        let records = [
+            record("a", 0, "NaN"),
+            record("a", 1, "inf"),
+            record("a", 2, "3"),
+            record("a", 3, "-inf"),
+        ];
+        let refs: Vec<&StorageRecord> = records.iter().collect();
+        // NaN is skipped, and infinities sort at the ends
+        let p50 = Aggregation::new(Aggregator::Percentile(50.0)).apply(&refs);
+        assert!(numbers(&p50) == vec![3.0]);
+        let max = Aggregation::new(Aggregator::Max).apply(&refs);
+        assert!(numbers(&max) == vec![f64::INFINITY]);
+        let only_nan = Aggregation::new(Aggregator::Percentile(50.0)).apply(&refs[..1]);
+        assert!(only_nan.is_empty());
+    }
+}
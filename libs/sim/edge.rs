@@ -98,9 +98,16 @@ impl SimElement for Edge {
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn is_idle(&self) -> bool {
+        self.queue.size() == 0
+    }
 }
 
 impl Edge {
+    // the number of RPCs on the edge that have not arrived yet
+    pub fn in_flight(&self) -> usize {
+        self.queue.size()
+    }
     pub fn enqueue(&mut self, x: Rpc, now: u64) {
         self.queue
             .add(TimestampedRpc {
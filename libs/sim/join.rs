@@ -0,0 +1,295 @@
+//! A scatter-gather join tracks the calls a node has fanned out for a request and decides when
+//! enough replies have come back to continue.  Joins are keyed by the node's own key for the
+//! request rather than by trace id, since one trace can reach a node more than once.
+//! Once a join completes, its replies are merged into a single RPC that keeps the trace id and
+//! the union of the reply headers, so filters still find the headers they ferry along.  The
+//! trace data that filters ferry in the ferried_data header is merged, so no branch loses it.
+
+use indexmap::map::IndexMap;
+use rpc_lib::rpc::Rpc;
+use std::cmp::min;
+use utils::graph::serde::FerriedData;
+
+const FERRIED_DATA: &str = "ferried_data";
+
+#[derive(Clone)]
+pub enum JoinPolicy {
+    WaitAll,
+    WaitAny,
+    Quorum(usize), // complete once this many replies have arrived
+}
+
+// How the data of the replies is combined
+#[derive(Clone)]
+pub enum MergeRule {
+    FirstReply,
+    LastReply,
+    Concat(String), // join the data of all replies with the separator
+}
+
+struct PendingJoin {
+    expected: usize,
+    needed: usize,
+    replies: Vec<Rpc>,
+    deadline: Option<u64>,
+}
+
+pub struct ScatterGather {
+    policy: JoinPolicy,
+    merge: MergeRule,
+    timeout: Option<u64>, // in ticks, measured from the start of the join
+    joins: IndexMap<u64, PendingJoin>,
+}
+
+impl ScatterGather {
+    pub fn new(policy: JoinPolicy, merge: MergeRule, timeout: Option<u64>) -> ScatterGather {
+        ScatterGather {
+            policy,
+            merge,
+            timeout,
+            joins: IndexMap::new(),
+        }
+    }
+
+    // Starts waiting for `expected` replies for the request
+    pub fn start(&mut self, key: u64, expected: usize, now: u64) {
+        let needed = match self.policy {
+            JoinPolicy::WaitAll => expected,
+            JoinPolicy::WaitAny => min(1, expected),
+            JoinPolicy::Quorum(quorum) => min(quorum, expected),
+        };
+        self.joins.insert(
+            key,
+            PendingJoin {
+                expected,
+                needed,
+                replies: Vec::new(),
+                deadline: self.timeout.map(|timeout| now + timeout),
+            },
+        );
+    }
+
+    pub fn is_pending(&self, key: u64) -> bool {
+        self.joins.contains_key(&key)
+    }
+
+    // Replies still missing before the join would have heard from every call
+    pub fn outstanding(&self, key: u64) -> usize {
+        match self.joins.get(&key) {
+            Some(join) => join.expected - join.replies.len(),
+            None => 0,
+        }
+    }
+
+    /// Records a reply to the request with the key.  Returns the merged RPC if the reply
+    /// completes the join; the join is then forgotten and later replies are not accepted.
+    pub fn add_reply(&mut self, key: u64, reply: Rpc) -> Option<Rpc> {
+        let join = self.joins.get_mut(&key)?;
+        join.replies.push(reply);
+        if join.replies.len() < join.needed {
+            return None;
+        }
+        let join = self.joins.remove(&key).unwrap();
+        self.merge_replies(join.replies)
+    }
+
+    /// Removes all joins whose deadline has passed.  Returns their keys together with the
+    /// merge of the replies that did arrive, if any.
+    pub fn expire(&mut self, now: u64) -> Vec<(u64, Option<Rpc>)> {
+        let mut expired_keys = vec![];
+        for (key, join) in &self.joins {
+            if let Some(deadline) = join.deadline {
+                if now >= deadline {
+                    expired_keys.push(*key);
+                }
+            }
+        }
+        let mut expired = vec![];
+        for key in expired_keys {
+            let join = self.joins.remove(&key).unwrap();
+            expired.push((key, self.merge_replies(join.replies)));
+        }
+        expired
+    }
+
+    // The replies all belong to the same trace, so the merge keeps its trace id
+    fn merge_replies(&self, replies: Vec<Rpc>) -> Option<Rpc> {
+        let mut merged = replies.last()?.clone();
+        // later replies win if they carry the same header, except for the ferried data
+        for reply in &replies {
+            for (key, value) in &reply.headers {
+                if key != FERRIED_DATA {
+                    merged.headers.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        if let Some(ferried_data) = merge_ferried_data(&replies) {
+            merged
+                .headers
+                .insert(FERRIED_DATA.to_string(), ferried_data);
+        }
+        merged.data = match &self.merge {
+            MergeRule::FirstReply => replies[0].data.clone(),
+            MergeRule::LastReply => replies[replies.len() - 1].data.clone(),
+            MergeRule::Concat(separator) => replies
+                .iter()
+                .map(|reply| reply.data.clone())
+                .collect::<Vec<String>>()
+                .join(separator),
+        };
+        Some(merged)
+    }
+}
+
+/* The ferried data of all the replies, merged into one trace.  If a reply's data cannot be
+ * decoded, it cannot be merged, and the data of the last reply that has some is kept instead.
+ */
+fn merge_ferried_data(replies: &[Rpc]) -> Option<String> {
+    let encoded: Vec<&String> = replies
+        .iter()
+        .filter_map(|reply| reply.headers.get(FERRIED_DATA))
+        .collect();
+    if encoded.len() <= 1 {
+        return encoded.first().map(|data| data.to_string());
+    }
+    let mut merged = FerriedData::default();
+    for data in &encoded {
+        match FerriedData::decode(data) {
+            Ok(data) => {
+                merged.merge(data);
+            }
+            Err(e) => {
+                log::warn!("could not merge the ferried data of a reply: {0}", e);
+                return encoded.last().map(|data| data.to_string());
+            }
+        }
+    }
+    Some(merged.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(uid: u64, data: &str, src: &str) -> Rpc {
+        let mut rpc = Rpc::new_with_src(data, src);
+        rpc.uid = uid;
+        rpc
+    }
+
+    #[test]
+    fn test_wait_all() {
+        let mut join = ScatterGather::new(JoinPolicy::WaitAll, MergeRule::LastReply, None);
+        join.start(1, 3, 0);
+        assert!(join.add_reply(1, reply(1, "a", "details-v1")).is_none());
+        assert!(join.add_reply(1, reply(1, "b", "reviews-v1")).is_none());
+        assert!(join.outstanding(1) == 1);
+        let merged = join.add_reply(1, reply(1, "c", "ratings-v1")).unwrap();
+        assert!(merged.uid == 1 && merged.data == "c");
+        assert!(!join.is_pending(1));
+        // late replies are not accepted once the join is done
+        assert!(join.add_reply(1, reply(1, "d", "ratings-v1")).is_none());
+    }
+
+    #[test]
+    fn test_wait_any_and_quorum() {
+        let mut any = ScatterGather::new(JoinPolicy::WaitAny, MergeRule::FirstReply, None);
+        any.start(1, 3, 0);
+        assert!(any.add_reply(1, reply(1, "fast", "a")).unwrap().data == "fast");
+
+        let mut quorum = ScatterGather::new(
+            JoinPolicy::Quorum(2),
+            MergeRule::Concat(",".to_string()),
+            None,
+        );
+        quorum.start(2, 3, 0);
+        assert!(quorum.add_reply(2, reply(2, "x", "a")).is_none());
+        assert!(quorum.add_reply(2, reply(2, "y", "b")).unwrap().data == "x,y");
+    }
+
+    #[test]
+    fn test_joins_of_one_trace() {
+        // a node that gets two requests of the same trace waits for each on its own
+        let mut join = ScatterGather::new(JoinPolicy::WaitAll, MergeRule::LastReply, None);
+        join.start(1, 1, 0);
+        join.start(2, 2, 0);
+        let merged = join.add_reply(1, reply(7, "a", "e")).unwrap();
+        assert!(merged.uid == 7 && merged.data == "a");
+        assert!(join.add_reply(2, reply(7, "b", "e")).is_none());
+        assert!(join.is_pending(2) && join.outstanding(2) == 1);
+    }
+
+    #[test]
+    fn test_merge_keeps_headers() {
+        let mut join = ScatterGather::new(JoinPolicy::WaitAll, MergeRule::LastReply, None);
+        join.start(1, 2, 0);
+        let mut details = reply(1, "a", "details-v1");
+        details
+            .headers
+            .insert("ferried_data".to_string(), "details".to_string());
+        let mut reviews = reply(1, "b", "reviews-v1");
+        reviews
+            .headers
+            .insert("user-cohort".to_string(), "beta".to_string());
+        join.add_reply(1, details);
+        let merged = join.add_reply(1, reviews).unwrap();
+        assert!(merged.headers["ferried_data"] == "details");
+        assert!(merged.headers["user-cohort"] == "beta");
+    }
+
+    #[test]
+    fn test_merge_ferried_data() {
+        let ferried = |spans: &[&str]| {
+            let mut data = FerriedData::default();
+            for span in spans {
+                data.trace_graph
+                    .add_node((span.to_string(), IndexMap::new()));
+            }
+            data.encode()
+        };
+        let mut join = ScatterGather::new(JoinPolicy::WaitAll, MergeRule::LastReply, None);
+        join.start(1, 2, 0);
+        // both replies carry ferried data and the same other header
+        let mut details = reply(1, "a", "details-v1");
+        details
+            .headers
+            .insert("ferried_data".to_string(), ferried(&["details-v1#0"]));
+        details
+            .headers
+            .insert("location".to_string(), "ingress".to_string());
+        let mut reviews = reply(1, "b", "reviews-v1");
+        reviews.headers.insert(
+            "ferried_data".to_string(),
+            ferried(&["reviews-v1#0", "ratings-v1#0"]),
+        );
+        reviews
+            .headers
+            .insert("location".to_string(), "egress".to_string());
+        join.add_reply(1, details);
+        let merged = join.add_reply(1, reviews).unwrap();
+        assert!(merged.headers["location"] == "egress");
+        let data = FerriedData::decode(&merged.headers["ferried_data"]).unwrap();
+        let mut spans: Vec<&str> = data
+            .trace_graph
+            .node_weights()
+            .map(|(id, _)| id.as_str())
+            .collect();
+        spans.sort_unstable();
+        assert!(spans == vec!["details-v1#0", "ratings-v1#0", "reviews-v1#0"]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut join = ScatterGather::new(JoinPolicy::WaitAll, MergeRule::LastReply, Some(3));
+        join.start(1, 2, 0);
+        join.start(2, 2, 1);
+        join.add_reply(1, reply(1, "partial", "a"));
+        assert!(join.expire(2).is_empty());
+        let expired = join.expire(3);
+        assert!(expired.len() == 1);
+        assert!(expired[0].0 == 1);
+        assert!(expired[0].1.as_ref().unwrap().data == "partial");
+        let expired = join.expire(4);
+        assert!(expired.len() == 1 && expired[0].1.is_none());
+    }
+}
@@ -1,11 +1,17 @@
 #![feature(test)]
 #![feature(extern_types)]
+pub mod aggregation;
+pub mod join;
 pub mod node;
+pub mod queue_discipline;
+pub mod routing;
+pub mod sampling;
+pub mod service;
 pub mod sim_element;
 pub mod simulator;
+pub mod storage;
 
 mod edge;
 mod filter_types;
 mod load_lib;
 mod plugin_wrapper;
-mod storage;
@@ -2,9 +2,11 @@
 //! A node is a sim_element.
 
 use crate::plugin_wrapper::PluginWrapper;
+use crate::queue_discipline::{Fifo, QueueDiscipline};
+use crate::routing::RoutingTable;
+use crate::sampling::{Decision, Sampler, SamplingPolicy, SamplingStats};
 use crate::sim_element::SimElement;
 use core::any::Any;
-use queues::*;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use rpc_lib::rpc::Rpc;
 use std::cmp::max;
@@ -12,15 +14,21 @@ use std::cmp::min;
 use std::fmt;
 
 pub struct Node {
-    pub ingress_queue: Queue<Rpc>,     // queue of incoming rpcs
-    pub egress_queue: Queue<Rpc>,      // queue of outgoing rpcs
-    pub id: String,                    // id of the node
-    pub capacity: u32,                 // capacity of the node;  how much it can hold at once
-    pub egress_rate: u32,              // rate at which the node can send out rpcs
+    pub ingress_queue: Box<dyn QueueDiscipline>, // queue of incoming rpcs
+    pub egress_queue: Box<dyn QueueDiscipline>,  // queue of outgoing rpcs
+    pub id: String,                              // id of the node
+    pub capacity: u32,        // capacity of the node;  how much it can hold at once
+    pub egress_rate: u32,     // rate at which the node can send out rpcs
     pub generation_rate: u32, // rate at which the node can generate rpcs, which are generated regardless of input to the node
     pub plugin: Option<PluginWrapper>, // filter to the node
     pub neighbors: Vec<String>, // who is the node connected to
     pub seed: u64,
+    pub routing_table: Option<RoutingTable>, // if not set, neighbors are picked uniformly at random
+    rng: StdRng,                             // persistent random stream for routing decisions
+    pub sampler: Option<Sampler>,            // if not set, the node follows the decisions of others
+    pub sampling_stats: SamplingStats,
+    pub capacity_dropped: u64, // RPCs dropped on arrival because the node was full
+    pub sinks: Vec<String>,    // storage elements the filter can send its output to
 }
 
 pub trait NodeTraits {
@@ -88,13 +96,16 @@ impl SimElement for Node {
                 self.pass_through_plugin(rpc, &mut queued_rpcs, tick, "egress");
             }
             for outgoing_rpc in &queued_rpcs {
-                self.enqueue_egress(outgoing_rpc.clone())
+                self.enqueue_egress(outgoing_rpc.clone(), tick)
             }
         }
         let max_output = min(self.egress_queue.size(), self.egress_rate as usize);
         let mut outbound_rpcs: Vec<Rpc> = vec![];
         for _ in 0..max_output {
-            outbound_rpcs.push(self.dequeue_egress().unwrap())
+            match self.dequeue_egress(tick) {
+                Some(rpc) => outbound_rpcs.push(rpc),
+                None => break, // the queue discipline dropped the rest
+            }
         }
         outbound_rpcs
     }
@@ -103,14 +114,24 @@ impl SimElement for Node {
     // the RPC once again goes through the plugin, this time as an outbound rpc, and then it is
     // placed in the outbound queue
     fn recv(&mut self, rpc: Rpc, tick: u64) {
-        // drop packets you cannot accept
-        if ((self.ingress_queue.size() + self.egress_queue.size()) as u32) < self.capacity {
-            let mut inbound_rpcs: Vec<Rpc> = vec![];
-            self.pass_through_plugin(rpc, &mut inbound_rpcs, tick, "ingress");
-            for inbound_rpc in inbound_rpcs {
-                self.enqueue_ingress(inbound_rpc, tick);
+        // let the routing table know the destination answered
+        if let (Some(table), Some(direction)) =
+            (self.routing_table.as_mut(), rpc.headers.get("direction"))
+        {
+            if direction == "response" && rpc.headers.contains_key("src") {
+                table.record_reply(&rpc.headers["src"]);
             }
         }
+        // drop packets you cannot accept
+        if self.is_full() {
+            self.capacity_dropped += 1;
+            return;
+        }
+        let mut inbound_rpcs: Vec<Rpc> = vec![];
+        self.pass_through_plugin(rpc, &mut inbound_rpcs, tick, "ingress");
+        for inbound_rpc in inbound_rpcs {
+            self.enqueue_ingress(inbound_rpc, tick);
+        }
     }
 
     fn add_connection(&mut self, neighbor: String) {
@@ -126,6 +147,12 @@ impl SimElement for Node {
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn add_sink(&mut self, sink: String) {
+        self.sinks.push(sink);
+    }
+    fn is_idle(&self) -> bool {
+        self.generation_rate == 0 && self.ingress_queue.size() == 0 && self.egress_queue.size() == 0
+    }
 }
 
 impl NodeTraits for Node {
@@ -133,14 +160,20 @@ impl NodeTraits for Node {
         // Set yourself as the source
         rpc.headers.insert("src".to_string(), self.id.to_string());
 
-        // Select a new destination at random
-        if self.neighbors.len() > 0 {
-            let mut rng: StdRng = SeedableRng::seed_from_u64(self.seed);
-            let idx = rng.gen_range(0, self.neighbors.len());
-            rpc.headers
-                .insert("dest".to_string(), self.neighbors[idx].to_string());
-        } else {
-            panic!("Node has no neighbors and no one to send to");
+        // Select a new destination, either through the routing table or at random
+        let destination = match self.routing_table.as_mut() {
+            Some(table) => table.route(rpc),
+            None if !self.neighbors.is_empty() => {
+                let idx = self.rng.gen_range(0, self.neighbors.len());
+                Some(self.neighbors[idx].to_string())
+            }
+            None => None,
+        };
+        match destination {
+            Some(dest) => {
+                rpc.headers.insert("dest".to_string(), dest);
+            }
+            None => panic!("Node has no neighbors and no one to send to"),
         }
         new_rpcs.push(rpc.clone());
     }
@@ -165,8 +198,8 @@ impl Node {
             created_plugin = Some(unwrapped_plugin);
         }
         Node {
-            ingress_queue: queue![],
-            egress_queue: queue![],
+            ingress_queue: Box::new(Fifo::default()),
+            egress_queue: Box::new(Fifo::default()),
             id: id.to_string(),
             capacity,
             egress_rate,
@@ -174,6 +207,12 @@ impl Node {
             plugin: created_plugin,
             neighbors: Vec::new(),
             seed,
+            routing_table: None,
+            rng: SeedableRng::seed_from_u64(seed),
+            sampler: None,
+            sampling_stats: SamplingStats::default(),
+            capacity_dropped: 0,
+            sinks: Vec::new(),
         }
     }
 
@@ -184,15 +223,36 @@ impl Node {
         tick: u64,
         direction: &str,
     ) {
+        // Follow the sampling decision of the trace, or make one
+        let decision = match self.sampler.as_mut() {
+            Some(sampler) => sampler.decide(&mut input_rcp, tick),
+            None => Decision::from_header(&input_rcp),
+        };
+        if decision == Some(Decision::Drop) {
+            self.sampling_stats.unsampled += 1;
+            processed_rpcs.push(input_rcp);
+            return;
+        }
+        // A deferred trace ends when this node responds to it
+        let keep = match self.sampler.as_mut() {
+            Some(sampler) if direction == "egress" => sampler.keep(&input_rcp, tick),
+            _ => true,
+        };
+
         // If the plugin exists, run the RPC through
         // Otherwise just push it into the egress queue
         if let Some(plugin) = self.plugin.as_mut() {
+            self.sampling_stats.sampled += 1;
             input_rcp
                 .headers
                 .insert("location".to_string(), direction.to_string());
             plugin.recv(input_rcp, tick);
             let filtered_rpcs = plugin.tick(tick);
             for filtered_rpc in filtered_rpcs {
+                if !keep && self.is_sink_output(&filtered_rpc) {
+                    self.sampling_stats.discarded += 1;
+                    continue;
+                }
                 processed_rpcs.push(filtered_rpc.clone());
             }
         } else {
@@ -200,31 +260,61 @@ impl Node {
         }
     }
 
-    pub fn enqueue_ingress(&mut self, x: Rpc, _now: u64) {
-        let _res = self.ingress_queue.add(x);
+    pub fn set_routing_table(&mut self, routing_table: RoutingTable) {
+        self.routing_table = Some(routing_table);
     }
-    pub fn dequeue_ingress(&mut self, _now: u64) -> Option<Rpc> {
-        if self.ingress_queue.size() == 0 {
-            return None;
-        } else {
-            return Some(self.ingress_queue.remove().unwrap());
-        }
+
+    // Make this node decide whether to sample the traces that reach it without a decision
+    pub fn set_sampling_policy(&mut self, policy: Box<dyn SamplingPolicy>) {
+        self.sampler = Some(Sampler::new(policy));
     }
-    pub fn enqueue_egress(&mut self, x: Rpc) {
-        let _res = self.egress_queue.add(x);
+
+    // Swap the discipline of the ingress queue.  Only do this before the node receives traffic.
+    pub fn set_ingress_discipline(&mut self, discipline: Box<dyn QueueDiscipline>) {
+        assert!(self.ingress_queue.size() == 0);
+        self.ingress_queue = discipline;
     }
-    pub fn dequeue_egress(&mut self) -> Option<Rpc> {
-        if self.egress_queue.size() == 0 {
-            return None;
-        } else {
-            return Some(self.egress_queue.remove().unwrap());
-        }
+    // Swap the discipline of the egress queue.  Only do this before the node receives traffic.
+    pub fn set_egress_discipline(&mut self, discipline: Box<dyn QueueDiscipline>) {
+        assert!(self.egress_queue.size() == 0);
+        self.egress_queue = discipline;
+    }
+
+    pub fn enqueue_ingress(&mut self, x: Rpc, now: u64) {
+        self.ingress_queue.enqueue(x, now);
+    }
+    pub fn dequeue_ingress(&mut self, now: u64) -> Option<Rpc> {
+        self.ingress_queue.dequeue(now)
+    }
+    pub fn enqueue_egress(&mut self, x: Rpc, now: u64) {
+        self.egress_queue.enqueue(x, now);
+    }
+    pub fn dequeue_egress(&mut self, now: u64) -> Option<Rpc> {
+        self.egress_queue.dequeue(now)
+    }
+
+    // Whether the filter sends the RPC to one of the storage sinks of the node
+    pub fn is_sink_output(&self, rpc: &Rpc) -> bool {
+        rpc.headers
+            .get("dest")
+            .is_some_and(|dest| self.sinks.contains(dest))
+    }
+
+    // Whether the queues hold as many RPCs as the node can take
+    pub fn is_full(&self) -> bool {
+        (self.ingress_queue.size() + self.egress_queue.size()) as u32 >= self.capacity
+    }
+
+    // All RPCs the node has dropped, because it was full or by its queue disciplines
+    pub fn dropped(&self) -> u64 {
+        self.capacity_dropped + self.ingress_queue.dropped() + self.egress_queue.dropped()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sampling::{Probabilistic, SAMPLING_HEADER};
     use std::path::PathBuf;
 
     #[test]
@@ -255,11 +345,77 @@ mod tests {
             "Queue size was `{}`",
             queue_size
         );
+        assert!(node.capacity_dropped == 1 && node.dropped() == 1);
         node.tick(0);
         queue_size = node.egress_queue.size();
         assert!(queue_size == 1, "Queue size was `{}`", queue_size);
     }
 
+    #[test]
+    fn test_sink_output() {
+        let mut node = Node::new("productpage-v1", 5, 5, 0, None, 1);
+        node.add_sink("storage".to_string());
+        node.add_sink("slow-storage".to_string());
+        // output to any of the sinks can be discarded, but not the calls of the node
+        for (dest, is_output) in [
+            ("storage", true),
+            ("slow-storage", true),
+            ("reviews-v1", false),
+        ] {
+            let mut rpc = Rpc::new_with_src("0", "productpage-v1");
+            rpc.headers.insert("dest".to_string(), dest.to_string());
+            assert!(node.is_sink_output(&rpc) == is_output, "{0}", dest);
+        }
+        assert!(!node.is_sink_output(&Rpc::new("0")));
+    }
+
+    #[test]
+    fn test_node_routing_table() {
+        let mut node = Node::new("0", 5, 5, 0, None, 1);
+        node.add_connection("reviews-v1".to_string());
+        node.add_connection("reviews-v3".to_string());
+        let mut table = RoutingTable::uniform(&["reviews-v1"], 1);
+        table.add_header_rule("user-cohort", "beta", "reviews-v3");
+        node.set_routing_table(table);
+        let mut rpc = Rpc::new("0");
+        rpc.headers
+            .insert("user-cohort".to_string(), "beta".to_string());
+        node.recv(rpc, 0);
+        node.recv(Rpc::new("0"), 0);
+        let outbound = node.tick(0);
+        assert!(outbound[0].headers["dest"] == "reviews-v3");
+        assert!(outbound[1].headers["dest"] == "reviews-v1");
+    }
+
+    #[test]
+    fn test_node_sampling() {
+        let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        cargo_dir.push("../../target/debug/libfilter_example");
+        let library_str = cargo_dir.to_str().unwrap();
+        let mut node = Node::new("0", 5, 5, 0, Some(library_str), 1);
+        node.add_connection("foo".to_string());
+        node.set_sampling_policy(Box::new(Probabilistic::new(0.0, 1)));
+
+        // the node drops the trace, and tells the next hop
+        node.recv(Rpc::new("0"), 0);
+        let outbound = node.tick(0);
+        assert!(outbound[0].headers[SAMPLING_HEADER] == "0");
+        assert!(!outbound[0].headers.contains_key("location"));
+
+        // a decision made upstream wins
+        let mut sampled = Rpc::new("0");
+        sampled
+            .headers
+            .insert("direction".to_string(), "request".to_string());
+        sampled
+            .headers
+            .insert(SAMPLING_HEADER.to_string(), "1".to_string());
+        node.recv(sampled, 1);
+        let outbound = node.tick(1);
+        assert!(outbound[0].headers[SAMPLING_HEADER] == "1");
+        assert!(node.sampling_stats.unsampled == 2 && node.sampling_stats.sampled == 2);
+    }
+
     #[test]
     fn test_plugin_initialization() {
         let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -0,0 +1,471 @@
+//! Queue disciplines decide in which order a node serves the RPCs waiting in its ingress and
+//! egress queues, and which RPCs are dropped when a queue is congested.
+//! Every node starts out with FIFO queues; other disciplines can be selected per node.
+
+use indexmap::map::IndexMap;
+use rpc_lib::rpc::Rpc;
+use std::cmp::{max, Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+
+pub trait QueueDiscipline {
+    fn enqueue(&mut self, rpc: Rpc, now: u64);
+
+    fn dequeue(&mut self, now: u64) -> Option<Rpc>;
+
+    fn size(&self) -> usize;
+
+    // the RPCs waiting in the queue, in the order they would be served if nothing changed
+    fn rpcs(&self) -> Vec<&Rpc>;
+
+    // number of RPCs the discipline itself has dropped so far
+    fn dropped(&self) -> u64;
+
+    fn name(&self) -> &str;
+}
+
+// ------------------------------- FIFO ----------------------------------------
+
+#[derive(Default)]
+pub struct Fifo {
+    queue: VecDeque<Rpc>,
+}
+
+impl Fifo {
+    pub fn new() -> Fifo {
+        Fifo {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl QueueDiscipline for Fifo {
+    fn enqueue(&mut self, rpc: Rpc, _now: u64) {
+        self.queue.push_back(rpc);
+    }
+    fn dequeue(&mut self, _now: u64) -> Option<Rpc> {
+        self.queue.pop_front()
+    }
+    fn size(&self) -> usize {
+        self.queue.len()
+    }
+    fn rpcs(&self) -> Vec<&Rpc> {
+        self.queue.iter().collect()
+    }
+    fn dropped(&self) -> u64 {
+        0
+    }
+    fn name(&self) -> &str {
+        "fifo"
+    }
+}
+
+// ------------------------------- LIFO ----------------------------------------
+
+#[derive(Default)]
+pub struct Lifo {
+    stack: Vec<Rpc>,
+}
+
+impl Lifo {
+    pub fn new() -> Lifo {
+        Lifo { stack: Vec::new() }
+    }
+}
+
+impl QueueDiscipline for Lifo {
+    fn enqueue(&mut self, rpc: Rpc, _now: u64) {
+        self.stack.push(rpc);
+    }
+    fn dequeue(&mut self, _now: u64) -> Option<Rpc> {
+        self.stack.pop()
+    }
+    fn size(&self) -> usize {
+        self.stack.len()
+    }
+    fn rpcs(&self) -> Vec<&Rpc> {
+        self.stack.iter().rev().collect()
+    }
+    fn dropped(&self) -> u64 {
+        0
+    }
+    fn name(&self) -> &str {
+        "lifo"
+    }
+}
+
+// ------------------------- Priority by header --------------------------------
+
+struct PrioritizedRpc {
+    priority: i64,
+    seq: Reverse<u64>, // ties are broken in arrival order
+    rpc: Rpc,
+}
+
+impl PartialEq for PrioritizedRpc {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+impl Eq for PrioritizedRpc {}
+impl PartialOrd for PrioritizedRpc {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for PrioritizedRpc {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+/// Serves the RPC with the highest numeric value in `header` first.  RPCs without the header
+/// (or with a value that is not an integer) get `default_priority`.
+pub struct HeaderPriority {
+    header: String,
+    default_priority: i64,
+    heap: BinaryHeap<PrioritizedRpc>,
+    seq: u64,
+}
+
+impl HeaderPriority {
+    pub fn new(header: &str, default_priority: i64) -> HeaderPriority {
+        HeaderPriority {
+            header: header.to_string(),
+            default_priority,
+            heap: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+}
+
+impl QueueDiscipline for HeaderPriority {
+    fn enqueue(&mut self, rpc: Rpc, _now: u64) {
+        let priority = match rpc.headers.get(&self.header) {
+            Some(value) => value.parse::<i64>().unwrap_or(self.default_priority),
+            None => self.default_priority,
+        };
+        self.heap.push(PrioritizedRpc {
+            priority,
+            seq: Reverse(self.seq),
+            rpc,
+        });
+        self.seq += 1;
+    }
+    fn dequeue(&mut self, _now: u64) -> Option<Rpc> {
+        self.heap.pop().map(|entry| entry.rpc)
+    }
+    fn size(&self) -> usize {
+        self.heap.len()
+    }
+    fn rpcs(&self) -> Vec<&Rpc> {
+        let mut entries: Vec<&PrioritizedRpc> = self.heap.iter().collect();
+        entries.sort_by(|a, b| b.cmp(a));
+        entries.into_iter().map(|entry| &entry.rpc).collect()
+    }
+    fn dropped(&self) -> u64 {
+        0
+    }
+    fn name(&self) -> &str {
+        "priority"
+    }
+}
+
+// ------------------- Weighted fair queuing per source ------------------------
+
+/// Weighted fair queuing over the `src` header.  Each RPC gets a virtual finish time of
+/// `max(virtual_time, last finish time of its source) + len / weight`, and the RPC with the
+/// smallest finish time is served first.  Sources without a configured weight get weight 1.
+pub struct WeightedFair {
+    weights: IndexMap<String, u32>,
+    flows: IndexMap<String, VecDeque<(f64, Rpc)>>,
+    last_finish: IndexMap<String, f64>,
+    virtual_time: f64,
+    size: usize,
+}
+
+impl WeightedFair {
+    pub fn new(weights: IndexMap<String, u32>) -> WeightedFair {
+        WeightedFair {
+            weights,
+            flows: IndexMap::new(),
+            last_finish: IndexMap::new(),
+            virtual_time: 0.0,
+            size: 0,
+        }
+    }
+
+    fn weight_of(&self, source: &str) -> f64 {
+        match self.weights.get(source) {
+            Some(weight) => max(*weight, 1) as f64,
+            None => 1.0,
+        }
+    }
+}
+
+impl QueueDiscipline for WeightedFair {
+    fn enqueue(&mut self, rpc: Rpc, _now: u64) {
+        let source = match rpc.headers.get("src") {
+            Some(src) => src.clone(),
+            None => String::new(),
+        };
+        let start = match self.last_finish.get(&source) {
+            Some(finish) => finish.max(self.virtual_time),
+            None => self.virtual_time,
+        };
+        // Zero-sized RPCs still take up a slot
+        let finish = start + max(rpc.len(), 1) as f64 / self.weight_of(&source);
+        self.last_finish.insert(source.clone(), finish);
+        self.flows
+            .entry(source)
+            .or_insert_with(VecDeque::new)
+            .push_back((finish, rpc));
+        self.size += 1;
+    }
+    fn dequeue(&mut self, _now: u64) -> Option<Rpc> {
+        let mut next_flow = None;
+        let mut next_finish = f64::INFINITY;
+        for (source, flow) in &self.flows {
+            if let Some((finish, _)) = flow.front() {
+                if *finish < next_finish {
+                    next_finish = *finish;
+                    next_flow = Some(source.clone());
+                }
+            }
+        }
+        let (finish, rpc) = self.flows.get_mut(&next_flow?)?.pop_front()?;
+        self.virtual_time = finish;
+        self.size -= 1;
+        Some(rpc)
+    }
+    fn size(&self) -> usize {
+        self.size
+    }
+    fn rpcs(&self) -> Vec<&Rpc> {
+        let mut entries: Vec<&(f64, Rpc)> = self.flows.values().flatten().collect();
+        entries.sort_by(|a, b| a.0.total_cmp(&b.0));
+        entries.into_iter().map(|(_, rpc)| rpc).collect()
+    }
+    fn dropped(&self) -> u64 {
+        0
+    }
+    fn name(&self) -> &str {
+        "wfq"
+    }
+}
+
+// ----------------------------- CoDel AQM -------------------------------------
+
+/// Controlled delay active queue management, following RFC 8289 with ticks as the unit of
+/// time.  Once the sojourn time of served RPCs stays above `target` for at least `interval`
+/// ticks, the queue starts dropping RPCs at its head, increasing the drop rate with the
+/// inverse square root of the number of drops until the delay falls below `target` again.
+pub struct CoDel {
+    queue: VecDeque<(u64, Rpc)>,
+    target: u64,
+    interval: u64,
+    first_above_time: u64,
+    drop_next: u64,
+    count: u64,
+    last_count: u64,
+    dropping: bool,
+    dropped: u64,
+}
+
+impl CoDel {
+    pub fn new(target: u64, interval: u64) -> CoDel {
+        assert!(interval >= 1);
+        CoDel {
+            queue: VecDeque::new(),
+            target,
+            interval,
+            first_above_time: 0,
+            drop_next: 0,
+            count: 0,
+            last_count: 0,
+            dropping: false,
+            dropped: 0,
+        }
+    }
+
+    fn control_law(&self, t: u64) -> u64 {
+        let step = self.interval as f64 / (self.count as f64).sqrt();
+        t + max(step as u64, 1)
+    }
+
+    // Returns the head of the queue and whether it may be dropped
+    fn do_dequeue(&mut self, now: u64) -> (Option<Rpc>, bool) {
+        let (enqueue_time, rpc) = match self.queue.pop_front() {
+            Some(entry) => entry,
+            None => {
+                self.first_above_time = 0;
+                return (None, false);
+            }
+        };
+        let mut ok_to_drop = false;
+        if now.saturating_sub(enqueue_time) < self.target {
+            self.first_above_time = 0;
+        } else if self.first_above_time == 0 {
+            self.first_above_time = now + self.interval;
+        } else if now >= self.first_above_time {
+            ok_to_drop = true;
+        }
+        (Some(rpc), ok_to_drop)
+    }
+
+    fn drop_rpc(&mut self, rpc: Rpc) {
+        log::debug!("CoDel dropped rpc {0}", rpc.uid);
+        self.dropped += 1;
+    }
+}
+
+impl QueueDiscipline for CoDel {
+    fn enqueue(&mut self, rpc: Rpc, now: u64) {
+        self.queue.push_back((now, rpc));
+    }
+    fn dequeue(&mut self, now: u64) -> Option<Rpc> {
+        let (mut rpc, mut ok_to_drop) = self.do_dequeue(now);
+        if self.dropping {
+            if !ok_to_drop {
+                self.dropping = false;
+            }
+            while self.dropping && now >= self.drop_next {
+                self.drop_rpc(rpc?);
+                self.count += 1;
+                let (next_rpc, next_ok_to_drop) = self.do_dequeue(now);
+                rpc = next_rpc;
+                ok_to_drop = next_ok_to_drop;
+                if !ok_to_drop {
+                    self.dropping = false;
+                } else {
+                    self.drop_next = self.control_law(self.drop_next);
+                }
+            }
+        } else if ok_to_drop {
+            self.drop_rpc(rpc?);
+            let (next_rpc, _) = self.do_dequeue(now);
+            rpc = next_rpc;
+            self.dropping = true;
+            // If we were dropping recently, resume at the previous rate
+            let delta = self.count.saturating_sub(self.last_count);
+            if delta > 1 && now.saturating_sub(self.drop_next) < 16 * self.interval {
+                self.count = delta;
+            } else {
+                self.count = 1;
+            }
+            self.drop_next = self.control_law(now);
+            self.last_count = self.count;
+        }
+        rpc
+    }
+    fn size(&self) -> usize {
+        self.queue.len()
+    }
+    fn rpcs(&self) -> Vec<&Rpc> {
+        self.queue.iter().map(|(_, rpc)| rpc).collect()
+    }
+    fn dropped(&self) -> u64 {
+        self.dropped
+    }
+    fn name(&self) -> &str {
+        "codel"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_with_header(data: &str, key: &str, value: &str) -> Rpc {
+        let mut rpc = Rpc::new(data);
+        rpc.headers.insert(key.to_string(), value.to_string());
+        rpc
+    }
+
+    fn waiting(discipline: &dyn QueueDiscipline) -> Vec<String> {
+        discipline
+            .rpcs()
+            .iter()
+            .map(|rpc| rpc.data.clone())
+            .collect()
+    }
+
+    fn drain(discipline: &mut dyn QueueDiscipline, now: u64) -> Vec<String> {
+        let mut order = vec![];
+        while let Some(rpc) = discipline.dequeue(now) {
+            order.push(rpc.data);
+        }
+        order
+    }
+
+    #[test]
+    fn test_fifo_and_lifo_order() {
+        let mut fifo = Fifo::new();
+        let mut lifo = Lifo::new();
+        for data in &["0", "1", "2"] {
+            fifo.enqueue(Rpc::new(data), 0);
+            lifo.enqueue(Rpc::new(data), 0);
+        }
+        assert!(fifo.size() == 3 && lifo.size() == 3);
+        // the queues can be inspected without serving them
+        assert_eq!(waiting(&fifo), vec!["0", "1", "2"]);
+        assert_eq!(waiting(&lifo), vec!["2", "1", "0"]);
+        assert_eq!(drain(&mut fifo, 0), vec!["0", "1", "2"]);
+        assert_eq!(drain(&mut lifo, 0), vec!["2", "1", "0"]);
+    }
+
+    #[test]
+    fn test_header_priority() {
+        let mut queue = HeaderPriority::new("priority", 0);
+        queue.enqueue(Rpc::new("default"), 0);
+        queue.enqueue(rpc_with_header("low", "priority", "-1"), 0);
+        queue.enqueue(rpc_with_header("high", "priority", "5"), 0);
+        queue.enqueue(rpc_with_header("high_later", "priority", "5"), 0);
+        assert_eq!(
+            waiting(&queue),
+            vec!["high", "high_later", "default", "low"]
+        );
+        assert_eq!(
+            drain(&mut queue, 0),
+            vec!["high", "high_later", "default", "low"]
+        );
+    }
+
+    #[test]
+    fn test_weighted_fair_shares() {
+        let mut weights = IndexMap::new();
+        weights.insert("app".to_string(), 3);
+        let mut queue = WeightedFair::new(weights);
+        for _ in 0..6 {
+            queue.enqueue(rpc_with_header("x", "src", "app"), 0);
+            queue.enqueue(rpc_with_header("x", "src", "log"), 0);
+        }
+        // With a 3:1 weight, the first four RPCs served should contain three app RPCs
+        let mut app_served = 0;
+        for _ in 0..4 {
+            if queue.dequeue(0).unwrap().headers["src"] == "app" {
+                app_served += 1;
+            }
+        }
+        assert!(app_served == 3, "app served {} times", app_served);
+        assert!(queue.size() == 8);
+    }
+
+    #[test]
+    fn test_codel_drops_standing_queue() {
+        let mut queue = CoDel::new(2, 4);
+        for tick in 0..40 {
+            // two arrivals per tick, one departure: the queue keeps growing
+            queue.enqueue(Rpc::new("0"), tick);
+            queue.enqueue(Rpc::new("0"), tick);
+            queue.dequeue(tick);
+        }
+        assert!(queue.dropped() > 0);
+
+        let mut short_queue = CoDel::new(2, 4);
+        for tick in 0..40 {
+            short_queue.enqueue(Rpc::new("0"), tick);
+            assert!(short_queue.dequeue(tick).is_some());
+        }
+        assert!(short_queue.dropped() == 0);
+    }
+}
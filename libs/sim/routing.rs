@@ -0,0 +1,246 @@
+//! A routing table decides which downstream service receives an RPC.  Header rules are checked
+//! first (e.g. route a user cohort to a canary version), and RPCs that match no rule are sent
+//! according to the default policy.  The table owns its random number generator, so the
+//! sequence of random decisions is reproducible from the seed and persists across calls.
+
+use indexmap::map::IndexMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rpc_lib::rpc::Rpc;
+
+pub enum RoutingPolicy {
+    Weighted(Vec<(String, u32)>), // destinations and their relative share of traffic
+    RoundRobin(Vec<String>),
+    LeastLoaded(Vec<String>), // fewest requests without a reply yet
+}
+
+pub struct HeaderRule {
+    pub header: String,
+    pub value: String,
+    pub destination: String,
+}
+
+pub struct RoutingTable {
+    rules: Vec<HeaderRule>,
+    policy: RoutingPolicy,
+    rng: StdRng,
+    next_round_robin: usize,
+    outstanding: IndexMap<String, u64>, // requests sent per destination without a reply yet
+}
+
+impl RoutingTable {
+    /// Panics if the policy is weighted and its weights add up to zero, e.g. because it has no
+    /// destinations, since it could never pick one.  The other policies route RPCs that match
+    /// no header rule nowhere if they have no destinations.
+    pub fn new(policy: RoutingPolicy, seed: u64) -> RoutingTable {
+        if let RoutingPolicy::Weighted(weights) = &policy {
+            assert!(weights.iter().map(|(_, weight)| weight).sum::<u32>() > 0);
+        }
+        RoutingTable {
+            rules: Vec::new(),
+            policy,
+            rng: SeedableRng::seed_from_u64(seed),
+            next_round_robin: 0,
+            outstanding: IndexMap::new(),
+        }
+    }
+
+    // Splits traffic evenly between the destinations, of which there must be at least one
+    pub fn uniform(destinations: &[&str], seed: u64) -> RoutingTable {
+        let weights = destinations
+            .iter()
+            .map(|dest| (dest.to_string(), 1))
+            .collect();
+        RoutingTable::new(RoutingPolicy::Weighted(weights), seed)
+    }
+
+    // Rules are checked in the order they were added
+    pub fn add_header_rule(&mut self, header: &str, value: &str, destination: &str) {
+        self.rules.push(HeaderRule {
+            header: header.to_string(),
+            value: value.to_string(),
+            destination: destination.to_string(),
+        });
+    }
+
+    pub fn destinations(&self) -> Vec<String> {
+        let mut destinations: Vec<String> = match &self.policy {
+            RoutingPolicy::Weighted(weights) => {
+                weights.iter().map(|(dest, _)| dest.clone()).collect()
+            }
+            RoutingPolicy::RoundRobin(dests) | RoutingPolicy::LeastLoaded(dests) => dests.clone(),
+        };
+        for rule in &self.rules {
+            if !destinations.contains(&rule.destination) {
+                destinations.push(rule.destination.clone());
+            }
+        }
+        destinations
+    }
+
+    pub fn outstanding(&self, destination: &str) -> u64 {
+        *self.outstanding.get(destination).unwrap_or(&0)
+    }
+
+    /// Picks the destination for the RPC and counts it as outstanding until
+    /// `record_reply` is called for that destination.
+    pub fn route(&mut self, rpc: &Rpc) -> Option<String> {
+        let destination = match self.match_rules(rpc) {
+            Some(dest) => Some(dest),
+            None => self.pick_by_policy(),
+        }?;
+        *self.outstanding.entry(destination.clone()).or_insert(0) += 1;
+        Some(destination)
+    }
+
+    pub fn record_reply(&mut self, source: &str) {
+        if let Some(count) = self.outstanding.get_mut(source) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn match_rules(&self, rpc: &Rpc) -> Option<String> {
+        for rule in &self.rules {
+            if rpc.headers.get(&rule.header) == Some(&rule.value) {
+                return Some(rule.destination.clone());
+            }
+        }
+        None
+    }
+
+    fn pick_by_policy(&mut self) -> Option<String> {
+        match &self.policy {
+            RoutingPolicy::Weighted(weights) => {
+                let total: u32 = weights.iter().map(|(_, weight)| weight).sum();
+                let mut pick = self.rng.gen_range(0, total as usize);
+                for (dest, weight) in weights {
+                    if pick < *weight as usize {
+                        return Some(dest.clone());
+                    }
+                    pick -= *weight as usize;
+                }
+                None
+            }
+            RoutingPolicy::RoundRobin(dests) => {
+                if dests.is_empty() {
+                    return None;
+                }
+                let dest = dests[self.next_round_robin % dests.len()].clone();
+                self.next_round_robin += 1;
+                Some(dest)
+            }
+            RoutingPolicy::LeastLoaded(dests) => {
+                // ties go to the destination listed first
+                let outstanding = &self.outstanding;
+                dests
+                    .iter()
+                    .min_by_key(|dest| *outstanding.get(*dest).unwrap_or(&0))
+                    .cloned()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_split() {
+        let mut table = RoutingTable::new(
+            RoutingPolicy::Weighted(vec![
+                ("reviews-v1".to_string(), 9),
+                ("reviews-v3".to_string(), 1),
+            ]),
+            0,
+        );
+        let mut canary_picks = 0;
+        for _ in 0..1000 {
+            if table.route(&Rpc::new("0")).unwrap() == "reviews-v3" {
+                canary_picks += 1;
+            }
+        }
+        assert!(
+            canary_picks > 50 && canary_picks < 150,
+            "canary picked {} times",
+            canary_picks
+        );
+    }
+
+    #[test]
+    fn test_rng_persists_across_calls() {
+        let mut table = RoutingTable::uniform(&["reviews-v1", "reviews-v2", "reviews-v3"], 0);
+        let mut picks: Vec<String> = (0..30)
+            .map(|_| table.route(&Rpc::new("0")).unwrap())
+            .collect();
+        picks.sort();
+        picks.dedup();
+        assert!(picks.len() == 3, "only picked {:?}", picks);
+
+        // the same seed reproduces the same sequence
+        let mut table_1 = RoutingTable::uniform(&["a", "b", "c"], 7);
+        let mut table_2 = RoutingTable::uniform(&["a", "b", "c"], 7);
+        for _ in 0..10 {
+            assert!(table_1.route(&Rpc::new("0")) == table_2.route(&Rpc::new("0")));
+        }
+    }
+
+    #[test]
+    fn test_header_rule() {
+        let mut table = RoutingTable::uniform(&["reviews-v1", "reviews-v2"], 0);
+        table.add_header_rule("user-cohort", "beta", "reviews-v3");
+        let mut rpc = Rpc::new("0");
+        rpc.headers
+            .insert("user-cohort".to_string(), "beta".to_string());
+        for _ in 0..10 {
+            assert!(table.route(&rpc).unwrap() == "reviews-v3");
+        }
+        assert!(table.destinations().len() == 3);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let dests = vec!["a".to_string(), "b".to_string()];
+        let mut table = RoutingTable::new(RoutingPolicy::RoundRobin(dests), 0);
+        let picks: Vec<String> = (0..4)
+            .map(|_| table.route(&Rpc::new("0")).unwrap())
+            .collect();
+        assert_eq!(picks, vec!["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn test_least_loaded() {
+        let dests = vec!["a".to_string(), "b".to_string()];
+        let mut table = RoutingTable::new(RoutingPolicy::LeastLoaded(dests), 0);
+        assert!(table.route(&Rpc::new("0")).unwrap() == "a");
+        assert!(table.route(&Rpc::new("0")).unwrap() == "b");
+        assert!(table.route(&Rpc::new("0")).unwrap() == "a");
+        assert!(table.outstanding("a") == 2);
+        table.record_reply("a");
+        table.record_reply("a");
+        assert!(table.route(&Rpc::new("0")).unwrap() == "a");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_uniform_without_destinations() {
+        RoutingTable::uniform(&[], 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_weights_of_zero() {
+        let weights = vec![("a".to_string(), 0), ("b".to_string(), 0)];
+        RoutingTable::new(RoutingPolicy::Weighted(weights), 0);
+    }
+
+    #[test]
+    fn test_no_destinations() {
+        let mut table = RoutingTable::new(RoutingPolicy::RoundRobin(Vec::new()), 0);
+        assert!(table.route(&Rpc::new("0")).is_none());
+        table.add_header_rule("user-cohort", "beta", "reviews-v3");
+        let mut rpc = Rpc::new("0");
+        rpc.headers
+            .insert("user-cohort".to_string(), "beta".to_string());
+        assert!(table.route(&rpc).unwrap() == "reviews-v3");
+    }
+}
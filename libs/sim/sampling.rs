@@ -0,0 +1,305 @@
+//! Sampling policies decide which traces go through the filters of the nodes they visit, so that
+//! the accuracy of sampled tracing queries can be weighed against their overhead.
+//! The first node of a trace that has a policy decides, and the decision travels downstream (and
+//! back with the responses) in the SAMPLING_HEADER of every RPC of the trace.  Nodes follow the
+//! decision in the header, whether they have a policy or not.  RPCs without a decision go through
+//! the filter, as they do without sampling.
+
+use indexmap::map::IndexMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rpc_lib::rpc::Rpc;
+
+pub const SAMPLING_HEADER: &str = "sampled";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Sample, // the trace goes through the filters
+    Drop,   // the trace skips the filters
+    Defer,  // the trace goes through the filters, and the deciding node keeps the result or not
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Sample => "1",
+            Decision::Drop => "0",
+            Decision::Defer => "deferred",
+        }
+    }
+
+    pub fn from_header(rpc: &Rpc) -> Option<Decision> {
+        match rpc.headers.get(SAMPLING_HEADER).map(|value| value.as_str()) {
+            Some("1") => Some(Decision::Sample),
+            Some("0") => Some(Decision::Drop),
+            Some("deferred") => Some(Decision::Defer),
+            _ => None,
+        }
+    }
+}
+
+pub trait SamplingPolicy {
+    // The decision for a trace that arrives at this node without one
+    fn decide(&mut self, rpc: &Rpc, now: u64) -> Decision;
+
+    // For a deferred trace, once the deciding node responds:  whether to keep what the filter of
+    // this node produced for the trace (e.g. the records it sends to storage);  the output of
+    // the filters downstream is kept either way.  @duration is the number of
+    // ticks since the node decided.
+    fn keep(&mut self, _response: &Rpc, _duration: u64) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str;
+}
+
+// RPCs that went through the filter or skipped it, to measure the overhead of a policy
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SamplingStats {
+    pub sampled: u64,
+    pub unsampled: u64,
+    pub discarded: u64, // filter output of deferred traces that the policy did not keep
+}
+
+// -------------------------- Head-based, probabilistic ------------------------
+
+/// Samples every trace with the same probability, decided when the trace starts.
+pub struct Probabilistic {
+    probability: f64,
+    rng: StdRng,
+}
+
+impl Probabilistic {
+    pub fn new(probability: f64, seed: u64) -> Probabilistic {
+        assert!((0.0..=1.0).contains(&probability));
+        Probabilistic {
+            probability,
+            rng: SeedableRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl SamplingPolicy for Probabilistic {
+    fn decide(&mut self, _rpc: &Rpc, _now: u64) -> Decision {
+        if self.rng.gen::<f64>() < self.probability {
+            Decision::Sample
+        } else {
+            Decision::Drop
+        }
+    }
+    fn name(&self) -> &str {
+        "probabilistic"
+    }
+}
+
+// ---------------------------- Per-service rate limit -------------------------
+
+/// Samples at most `max_traces` of the traces that start at this service in every window of
+/// `window` ticks.
+pub struct RateLimited {
+    max_traces: u64,
+    window: u64,
+    window_start: u64,
+    sampled: u64, // traces sampled in the current window
+}
+
+impl RateLimited {
+    pub fn new(max_traces: u64, window: u64) -> RateLimited {
+        assert!(window >= 1);
+        RateLimited {
+            max_traces,
+            window,
+            window_start: 0,
+            sampled: 0,
+        }
+    }
+}
+
+impl SamplingPolicy for RateLimited {
+    fn decide(&mut self, _rpc: &Rpc, now: u64) -> Decision {
+        if now >= self.window_start + self.window {
+            self.window_start = now - (now - self.window_start) % self.window;
+            self.sampled = 0;
+        }
+        if self.sampled < self.max_traces {
+            self.sampled += 1;
+            Decision::Sample
+        } else {
+            Decision::Drop
+        }
+    }
+    fn name(&self) -> &str {
+        "rate_limited"
+    }
+}
+
+// --------------------------------- Tail-based --------------------------------
+
+pub type TailPredicate = Box<dyn Fn(&Rpc, u64) -> bool>;
+
+/// Traces every request, and decides at the root, after seeing its response, whether to keep
+/// what the filter of the root found.
+pub struct TailBased {
+    predicate: TailPredicate,
+}
+
+impl TailBased {
+    pub fn new(predicate: TailPredicate) -> TailBased {
+        TailBased { predicate }
+    }
+
+    // Keeps the traces whose response is an error, e.g. a timeout
+    pub fn errors() -> TailBased {
+        TailBased::new(Box::new(|response, _| {
+            response.headers.contains_key("error")
+        }))
+    }
+
+    // Keeps the traces that took more than @ticks ticks
+    pub fn slower_than(ticks: u64) -> TailBased {
+        TailBased::new(Box::new(move |_, duration| duration > ticks))
+    }
+}
+
+impl SamplingPolicy for TailBased {
+    fn decide(&mut self, _rpc: &Rpc, _now: u64) -> Decision {
+        Decision::Defer
+    }
+    fn keep(&mut self, response: &Rpc, duration: u64) -> bool {
+        (self.predicate)(response, duration)
+    }
+    fn name(&self) -> &str {
+        "tail_based"
+    }
+}
+
+// The policy of a node, and the traces it has deferred
+pub struct Sampler {
+    pub policy: Box<dyn SamplingPolicy>,
+    deferred: IndexMap<u64, u64>, // trace ID to the tick the node decided
+}
+
+impl Sampler {
+    pub fn new(policy: Box<dyn SamplingPolicy>) -> Sampler {
+        Sampler {
+            policy,
+            deferred: IndexMap::new(),
+        }
+    }
+
+    /* The decision for this RPC:  the one in its header, or else a new one for a request, which
+     * is written to the header so that it is propagated.  A response without a decision has
+     * none, since its trace did not start here.
+     */
+    pub fn decide(&mut self, rpc: &mut Rpc, now: u64) -> Option<Decision> {
+        if let Some(decision) = Decision::from_header(rpc) {
+            return Some(decision);
+        }
+        if rpc.headers.get("direction").map(|d| d.as_str()) == Some("response") {
+            return None;
+        }
+        let decision = self.policy.decide(rpc, now);
+        if decision == Decision::Defer {
+            self.deferred.insert(rpc.uid, now);
+        }
+        rpc.headers
+            .insert(SAMPLING_HEADER.to_string(), decision.as_str().to_string());
+        Some(decision)
+    }
+
+    // Whether to keep the filter output for this response;  only this node's own deferred traces
+    // can be discarded
+    pub fn keep(&mut self, response: &Rpc, now: u64) -> bool {
+        if response.headers.get("direction").map(|d| d.as_str()) != Some("response") {
+            return true;
+        }
+        match self.deferred.remove(&response.uid) {
+            Some(start) => self.policy.keep(response, now - start),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probabilistic() {
+        let mut never = Probabilistic::new(0.0, 1);
+        let mut always = Probabilistic::new(1.0, 1);
+        let mut half = Probabilistic::new(0.5, 1);
+        let rpc = Rpc::new("0");
+        let mut sampled = 0;
+        for tick in 0..1000 {
+            assert!(never.decide(&rpc, tick) == Decision::Drop);
+            assert!(always.decide(&rpc, tick) == Decision::Sample);
+            if half.decide(&rpc, tick) == Decision::Sample {
+                sampled += 1;
+            }
+        }
+        assert!(
+            sampled > 400 && sampled < 600,
+            "sampled {0} of 1000",
+            sampled
+        );
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let mut policy = RateLimited::new(2, 10);
+        let rpc = Rpc::new("0");
+        let decisions: Vec<Decision> = [0, 1, 5, 9, 10, 12, 25, 25, 25]
+            .iter()
+            .map(|tick| policy.decide(&rpc, *tick))
+            .collect();
+        use Decision::{Drop, Sample};
+        assert!(
+            decisions == vec![Sample, Sample, Drop, Drop, Sample, Sample, Sample, Sample, Drop]
+        );
+    }
+
+    #[test]
+    fn test_decision_is_propagated() {
+        let mut sampler = Sampler::new(Box::new(Probabilistic::new(0.0, 1)));
+        let mut rpc = Rpc::new("0");
+        assert!(sampler.decide(&mut rpc, 0) == Some(Decision::Drop));
+        assert!(rpc.headers[SAMPLING_HEADER] == "0");
+
+        // a decision made upstream wins
+        let mut upstream = Rpc::new("0");
+        upstream
+            .headers
+            .insert(SAMPLING_HEADER.to_string(), "1".to_string());
+        assert!(sampler.decide(&mut upstream, 0) == Some(Decision::Sample));
+
+        // a response without a decision does not start a trace
+        let mut response = Rpc::new("0");
+        response
+            .headers
+            .insert("direction".to_string(), "response".to_string());
+        assert!(sampler.decide(&mut response, 0).is_none());
+        assert!(!response.headers.contains_key(SAMPLING_HEADER));
+    }
+
+    #[test]
+    fn test_tail_based() {
+        let mut sampler = Sampler::new(Box::new(TailBased::slower_than(3)));
+        let mut fast = Rpc::new("0");
+        let mut slow = Rpc::new("0");
+        assert!(sampler.decide(&mut fast, 0) == Some(Decision::Defer));
+        assert!(sampler.decide(&mut slow, 0) == Some(Decision::Defer));
+        for rpc in &mut [&mut fast, &mut slow] {
+            rpc.headers
+                .insert("direction".to_string(), "response".to_string());
+        }
+        assert!(!sampler.keep(&fast, 2));
+        assert!(sampler.keep(&slow, 5));
+        // a trace another node deferred is kept
+        assert!(sampler.keep(&fast, 10));
+
+        let mut errors = TailBased::errors();
+        fast.headers
+            .insert("error".to_string(), "timeout".to_string());
+        assert!(errors.keep(&fast, 0) && !errors.keep(&slow, 0));
+    }
+}
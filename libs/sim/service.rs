@@ -0,0 +1,766 @@
+//! A service is a node whose behaviour is described as data instead of code: for each incoming
+//! request, which downstream services to call, how to join their replies, and what to send back
+//! to the caller.  Calls are grouped into ordered steps: the calls of a step are issued in
+//! parallel, and a step only starts once the previous one has been joined, so its request can be
+//! built from the previous reply.  A service without downstream calls just
+//! answers every request, like a leaf node.
+//! A service is a sim_element.
+
+use crate::join::{JoinPolicy, MergeRule, ScatterGather};
+use crate::node::node_fmt_with_name;
+use crate::node::Node;
+use crate::node::NodeTraits;
+use crate::queue_discipline::QueueDiscipline;
+use crate::routing::RoutingTable;
+use crate::sim_element::SimElement;
+use core::any::Any;
+use indexmap::map::IndexMap;
+use rpc_lib::rpc::Rpc;
+use std::cmp::min;
+use std::fmt;
+
+pub enum CallTarget {
+    Service(String),           // always call this service
+    Routed(Box<RoutingTable>), // let the routing table pick the service for every request
+}
+
+impl CallTarget {
+    fn resolve(&mut self, request: &Rpc) -> Option<String> {
+        match self {
+            CallTarget::Service(id) => Some(id.clone()),
+            CallTarget::Routed(table) => table.route(request),
+        }
+    }
+}
+
+// What the service puts in the data of its response
+pub enum ReplyData {
+    Request, // echo the data of the request
+    Merged,  // the downstream replies of the last step, combined by the merge rule
+    Fixed(String),
+}
+
+// How the request of a step is built.  By default every step sends the original request;
+// later steps can instead take data and headers from the merged reply of the previous step.
+#[derive(Default)]
+pub struct StepInput {
+    pub data_from_reply: bool,           // send the data of the previous reply
+    pub headers_from_reply: Vec<String>, // copy these headers of the previous reply
+}
+
+impl StepInput {
+    fn build(&self, request: &Rpc, previous_reply: Option<&Rpc>) -> Rpc {
+        let mut step_request = request.clone();
+        if let Some(reply) = previous_reply {
+            if self.data_from_reply {
+                step_request.data = reply.data.clone();
+            }
+            for header in &self.headers_from_reply {
+                if let Some(value) = reply.headers.get(header) {
+                    step_request.headers.insert(header.clone(), value.clone());
+                }
+            }
+        }
+        step_request
+    }
+}
+
+// The calls of a step are issued at once and joined; the next step starts after the join
+pub struct Step {
+    pub calls: Vec<CallTarget>,
+    pub input: StepInput,
+}
+
+impl Step {
+    pub fn new(calls: Vec<CallTarget>) -> Step {
+        assert!(!calls.is_empty());
+        Step {
+            calls,
+            input: StepInput::default(),
+        }
+    }
+
+    pub fn call(target: CallTarget) -> Step {
+        Step::new(vec![target])
+    }
+
+    pub fn with_input(mut self, input: StepInput) -> Step {
+        self.input = input;
+        self
+    }
+}
+
+pub struct ServiceSpec {
+    pub steps: Vec<Step>,
+    pub join: JoinPolicy,
+    pub merge: MergeRule,
+    pub timeout: Option<u64>, // ticks to wait for the replies of a step before answering with an error
+    pub reply: ReplyData,
+}
+
+impl ServiceSpec {
+    // Answers every request directly
+    pub fn leaf() -> ServiceSpec {
+        ServiceSpec::plan(Vec::new()).with_reply(ReplyData::Request)
+    }
+
+    // All calls in a single step
+    pub fn parallel(calls: Vec<CallTarget>) -> ServiceSpec {
+        if calls.is_empty() {
+            return ServiceSpec::plan(Vec::new());
+        }
+        ServiceSpec::plan(vec![Step::new(calls)])
+    }
+
+    // One step per call, each sending the original request
+    pub fn sequential(calls: Vec<CallTarget>) -> ServiceSpec {
+        ServiceSpec::plan(calls.into_iter().map(Step::call).collect())
+    }
+
+    pub fn plan(steps: Vec<Step>) -> ServiceSpec {
+        ServiceSpec {
+            steps,
+            join: JoinPolicy::WaitAll,
+            merge: MergeRule::LastReply,
+            timeout: None,
+            reply: ReplyData::Merged,
+        }
+    }
+
+    pub fn with_join(mut self, join: JoinPolicy) -> ServiceSpec {
+        self.join = join;
+        self
+    }
+
+    pub fn with_merge(mut self, merge: MergeRule) -> ServiceSpec {
+        self.merge = merge;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: u64) -> ServiceSpec {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_reply(mut self, reply: ReplyData) -> ServiceSpec {
+        self.reply = reply;
+        self
+    }
+}
+
+// The calls of a request carry the service's key for the request in this header, and the
+// replies echo it back.  The uid of the RPC is not enough: one trace can reach a service more
+// than once, e.g. when two services it calls both call a third one.
+const CALL_HEADER: &str = "call";
+// Marks a request in the ingress queue whose previous step has been joined
+const NEXT_STEP_HEADER: &str = "next_step";
+
+// A request this service is still working on, keyed by the service's own key for it
+struct PendingRequest {
+    caller: String,
+    request: Rpc,
+    next_step: usize,
+    last_reply: Option<Rpc>,      // merged reply of the previous step
+    routed: Vec<(String, usize)>, // the service and call index of the step's routed calls
+}
+
+pub struct Service {
+    core_node: Node,
+    spec: ServiceSpec,
+    pending: IndexMap<u64, PendingRequest>,
+    next_key: u64,
+    joins: ScatterGather, // replies of the calls in flight
+    now: u64,             // the current tick, for the timeouts of calls issued in process_rpc
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        node_fmt_with_name(&self.core_node, f, "Service")
+    }
+}
+
+impl SimElement for Service {
+    fn tick(&mut self, tick: u64) -> Vec<Rpc> {
+        self.now = tick;
+        // Give up on calls that took too long
+        for (key, partial_reply) in self.joins.expire(tick) {
+            let response = self.timeout_response(key, partial_reply);
+            self.core_node.enqueue_ingress(response, tick);
+        }
+        while let Some(mut rpc) = self.core_node.dequeue_ingress(tick) {
+            let mut queued_rpcs: Vec<Rpc> = vec![];
+            // Process the RPC
+            let mut new_rpcs: Vec<Rpc> = vec![];
+            self.process_rpc(&mut rpc, &mut new_rpcs);
+
+            // Pass the RPCs we have through the plugin
+            for rpc in new_rpcs {
+                self.core_node
+                    .pass_through_plugin(rpc, &mut queued_rpcs, tick, "egress");
+            }
+            for queued_rpc in queued_rpcs {
+                self.core_node.enqueue_egress(queued_rpc, tick)
+            }
+        }
+
+        let max_output = min(
+            self.core_node.egress_queue.size(),
+            self.core_node.egress_rate as usize,
+        );
+        let mut outbound_rpcs: Vec<Rpc> = vec![];
+        for _ in 0..max_output {
+            match self.core_node.dequeue_egress(tick) {
+                Some(rpc) => outbound_rpcs.push(rpc),
+                None => break,
+            }
+        }
+        outbound_rpcs
+    }
+
+    fn recv(&mut self, rpc: Rpc, tick: u64) {
+        // drop packets you cannot accept
+        if self.core_node.is_full() {
+            self.core_node.capacity_dropped += 1;
+            return;
+        }
+        let mut inbound_rpcs: Vec<Rpc> = vec![];
+        self.core_node
+            .pass_through_plugin(rpc, &mut inbound_rpcs, tick, "ingress");
+
+        for inbound_rpc in inbound_rpcs {
+            let is_reply = inbound_rpc.headers.get("direction") == Some(&"response".to_string());
+            let key = call_key(&inbound_rpc).filter(|key| self.joins.is_pending(*key));
+            if !is_reply {
+                self.core_node.enqueue_ingress(inbound_rpc, tick);
+            } else if let Some(key) = key {
+                // We only enqueue if the reply completes a step of the request
+                if let Some(next_rpc) = self.handle_reply(key, inbound_rpc) {
+                    self.core_node.enqueue_ingress(next_rpc, tick);
+                }
+            } else {
+                log::debug!(
+                    "{0} dropped a late reply for trace {1}",
+                    self.core_node.id,
+                    inbound_rpc.uid
+                );
+            }
+        }
+    }
+
+    fn add_connection(&mut self, neighbor: String) {
+        self.core_node.add_connection(neighbor)
+    }
+    fn whoami(&self) -> &str {
+        self.core_node.whoami()
+    }
+    fn neighbors(&self) -> &Vec<String> {
+        self.core_node.neighbors()
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn add_sink(&mut self, sink: String) {
+        self.core_node.add_sink(sink)
+    }
+    fn is_idle(&self) -> bool {
+        self.core_node.is_idle() && self.pending.is_empty()
+    }
+}
+
+impl NodeTraits for Service {
+    fn process_rpc(&mut self, rpc: &mut Rpc, new_rpcs: &mut Vec<Rpc>) {
+        // Responses in the ingress queue were assembled by handle_reply and
+        // already know where to go
+        if rpc.headers.get("direction") == Some(&"response".to_string()) {
+            rpc.headers
+                .insert("src".to_string(), self.core_node.id.to_string());
+            new_rpcs.push(rpc.clone());
+            return;
+        }
+        if let Some(key) = rpc.headers.get(NEXT_STEP_HEADER) {
+            // A request whose previous step has replied
+            let key = key.parse().unwrap();
+            self.issue_calls(key, new_rpcs, self.now);
+            return;
+        }
+        if !rpc.headers.contains_key("src") {
+            log::error!(
+                "{0} dropped an RPC of trace {1} without a source",
+                self.core_node.id,
+                rpc.uid
+            );
+            return;
+        }
+        if self.spec.steps.is_empty() {
+            let mut response = rpc.clone();
+            response
+                .headers
+                .insert("dest".to_string(), rpc.headers["src"].to_string());
+            self.set_reply_data(&mut response, rpc);
+            response
+                .headers
+                .insert("direction".to_string(), "response".to_string());
+            response
+                .headers
+                .insert("src".to_string(), self.core_node.id.to_string());
+            new_rpcs.push(response);
+            return;
+        }
+        let key = self.next_key;
+        self.next_key += 1;
+        self.pending.insert(
+            key,
+            PendingRequest {
+                caller: rpc.headers["src"].to_string(),
+                request: rpc.clone(),
+                next_step: 0,
+                last_reply: None,
+                routed: Vec::new(),
+            },
+        );
+        self.issue_calls(key, new_rpcs, self.now);
+    }
+}
+
+impl Service {
+    pub fn new(
+        id: &str,
+        capacity: u32,
+        egress_rate: u32,
+        plugin: Option<&str>,
+        seed: u64,
+        spec: ServiceSpec,
+    ) -> Service {
+        assert!(capacity >= 1);
+        let core_node = Node::new(id, capacity, egress_rate, 0, plugin, seed);
+        let joins = ScatterGather::new(spec.join.clone(), spec.merge.clone(), spec.timeout);
+        Service {
+            core_node,
+            spec,
+            pending: IndexMap::new(),
+            next_key: 0,
+            joins,
+            now: 0,
+        }
+    }
+
+    // Issues the calls of the next step of the request
+    fn issue_calls(&mut self, key: u64, new_rpcs: &mut Vec<Rpc>, now: u64) {
+        let pending = self.pending.get_mut(&key).unwrap();
+        let step = &mut self.spec.steps[pending.next_step];
+        let step_request = step
+            .input
+            .build(&pending.request, pending.last_reply.as_ref());
+        pending.routed.clear();
+        for (index, call) in step.calls.iter_mut().enumerate() {
+            let mut call_rpc = step_request.clone();
+            match call.resolve(&step_request) {
+                Some(dest) => {
+                    if let CallTarget::Routed(_) = call {
+                        pending.routed.push((dest.clone(), index));
+                    }
+                    call_rpc.headers.insert("dest".to_string(), dest);
+                }
+                None => panic!("{0} has no service to call", self.core_node.id),
+            }
+            call_rpc
+                .headers
+                .insert("direction".to_string(), "request".to_string());
+            call_rpc
+                .headers
+                .insert("src".to_string(), self.core_node.id.to_string());
+            call_rpc
+                .headers
+                .insert(CALL_HEADER.to_string(), key.to_string());
+            new_rpcs.push(call_rpc);
+        }
+        self.joins.start(key, step.calls.len(), now);
+        pending.next_step += 1;
+    }
+
+    // Records a downstream reply.  Returns the RPC to process next once the reply
+    // completes a step: the original request if there are steps left, or the response
+    // to the caller otherwise.
+    fn handle_reply(&mut self, key: u64, reply: Rpc) -> Option<Rpc> {
+        let pending = self.pending.get_mut(&key).unwrap();
+        match reply.headers.get("src") {
+            Some(src) => {
+                // only the table that routed the call learns that it is done
+                let step = &mut self.spec.steps[pending.next_step - 1];
+                if let Some(position) = pending.routed.iter().position(|(dest, _)| dest == src) {
+                    let (_, index) = pending.routed.remove(position);
+                    if let CallTarget::Routed(table) = &mut step.calls[index] {
+                        table.record_reply(src);
+                    }
+                }
+            }
+            None => log::warn!(
+                "{0} got a reply for trace {1} without a src;  no routing table records it",
+                self.core_node.id,
+                reply.uid
+            ),
+        }
+        let mut merged = self.joins.add_reply(key, reply)?;
+        let pending = self.pending.get_mut(&key).unwrap();
+        if pending.next_step < self.spec.steps.len() {
+            pending.last_reply = Some(merged);
+            let mut next_rpc = pending.request.clone();
+            next_rpc
+                .headers
+                .insert(NEXT_STEP_HEADER.to_string(), key.to_string());
+            return Some(next_rpc);
+        }
+        let pending = self.pending.remove(&key).unwrap();
+        self.set_reply_data(&mut merged, &pending.request);
+        restore_call_key(&mut merged, &pending.request);
+        merged
+            .headers
+            .insert("direction".to_string(), "response".to_string());
+        merged
+            .headers
+            .insert("dest".to_string(), pending.caller.to_string());
+        Some(merged)
+    }
+
+    // The response to the caller if the calls of a request timed out
+    fn timeout_response(&mut self, key: u64, partial_reply: Option<Rpc>) -> Rpc {
+        let pending = self.pending.remove(&key).unwrap();
+        let mut response = match partial_reply {
+            Some(reply) => reply,
+            None => pending.request.clone(),
+        };
+        restore_call_key(&mut response, &pending.request);
+        response
+            .headers
+            .insert("error".to_string(), "timeout".to_string());
+        response
+            .headers
+            .insert("direction".to_string(), "response".to_string());
+        response
+            .headers
+            .insert("dest".to_string(), pending.caller.to_string());
+        response
+    }
+
+    fn set_reply_data(&self, response: &mut Rpc, request: &Rpc) {
+        match &self.spec.reply {
+            ReplyData::Request => response.data = request.data.clone(),
+            ReplyData::Merged => {}
+            ReplyData::Fixed(data) => response.data = data.clone(),
+        }
+    }
+
+    pub fn core_node(&self) -> &Node {
+        &self.core_node
+    }
+
+    // e.g. to change the queue disciplines of the service
+    pub fn core_node_mut(&mut self) -> &mut Node {
+        &mut self.core_node
+    }
+
+    pub fn get_ingress_queue(&self) -> &dyn QueueDiscipline {
+        self.core_node.ingress_queue.as_ref()
+    }
+
+    pub fn get_egress_queue(&self) -> &dyn QueueDiscipline {
+        self.core_node.egress_queue.as_ref()
+    }
+}
+
+// The key a reply carries for the request of this service it belongs to
+fn call_key(reply: &Rpc) -> Option<u64> {
+    reply.headers.get(CALL_HEADER)?.parse().ok()
+}
+
+// A response carries the caller's key for the request, not the one of this service's calls
+fn restore_call_key(response: &mut Rpc, request: &Rpc) {
+    match request.headers.get(CALL_HEADER) {
+        Some(key) => {
+            response
+                .headers
+                .insert(CALL_HEADER.to_string(), key.to_string());
+        }
+        None => {
+            response.headers.remove(CALL_HEADER);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn reply_to(request: &Rpc, from: &str) -> Rpc {
+        let mut reply = request.clone();
+        reply
+            .headers
+            .insert("dest".to_string(), request.headers["src"].to_string());
+        reply.headers.insert("src".to_string(), from.to_string());
+        reply
+            .headers
+            .insert("direction".to_string(), "response".to_string());
+        reply
+    }
+
+    fn request_from(src: &str) -> Rpc {
+        let mut rpc = Rpc::new_with_src("0", src);
+        rpc.headers
+            .insert("direction".to_string(), "request".to_string());
+        rpc
+    }
+
+    #[test]
+    fn test_service_creation() {
+        let _service = Service::new("0", 2, 2, None, 0, ServiceSpec::leaf());
+    }
+
+    #[test]
+    fn test_service_capacity_and_egress_rate() {
+        let mut service = Service::new("0", 2, 1, None, 0, ServiceSpec::leaf());
+        service.recv(request_from("gateway"), 0);
+        service.recv(request_from("gateway"), 0);
+        assert!(service.get_ingress_queue().size() == 2);
+        // over capacity, so this one is dropped
+        service.recv(request_from("gateway"), 0);
+        assert!(service.get_ingress_queue().size() == 2);
+        assert!(service.core_node().capacity_dropped == 1);
+        let outbound = service.tick(0);
+        assert!(outbound.len() == 1);
+        assert!(service.get_egress_queue().size() == 1);
+    }
+
+    #[test]
+    fn test_leaf_reflects_requests() {
+        let mut service = Service::new("details-v1", 5, 5, None, 0, ServiceSpec::leaf());
+        service.recv(request_from("productpage-v1"), 0);
+        let outbound = service.tick(0);
+        assert!(outbound.len() == 1);
+        assert!(outbound[0].headers["dest"] == "productpage-v1");
+        assert!(outbound[0].headers["src"] == "details-v1");
+        assert!(outbound[0].headers["direction"] == "response");
+    }
+
+    #[test]
+    fn test_parallel_calls_join_all_replies() {
+        let spec = ServiceSpec::parallel(vec![
+            CallTarget::Service("details-v1".to_string()),
+            CallTarget::Service("reviews-v1".to_string()),
+        ])
+        .with_reply(ReplyData::Fixed("response".to_string()));
+        let mut service = Service::new("productpage-v1", 5, 5, None, 0, spec);
+        let request = request_from("gateway");
+        service.recv(request.clone(), 0);
+        let calls = service.tick(0);
+        assert!(calls.len() == 2);
+        assert!(calls[0].headers["dest"] == "details-v1");
+        assert!(calls[1].headers["dest"] == "reviews-v1");
+
+        service.recv(reply_to(&calls[0], "details-v1"), 1);
+        assert!(service.tick(1).is_empty());
+        service.recv(reply_to(&calls[1], "reviews-v1"), 2);
+        let response = service.tick(2);
+        assert!(response.len() == 1);
+        assert!(response[0].uid == request.uid);
+        assert!(response[0].data == "response");
+        assert!(response[0].headers["dest"] == "gateway");
+        assert!(response[0].headers["src"] == "productpage-v1");
+    }
+
+    #[test]
+    fn test_sequential_calls() {
+        let spec = ServiceSpec::sequential(vec![
+            CallTarget::Service("auth".to_string()),
+            CallTarget::Service("db".to_string()),
+        ]);
+        let mut service = Service::new("frontend", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        let first = service.tick(0);
+        assert!(first.len() == 1 && first[0].headers["dest"] == "auth");
+        service.recv(reply_to(&first[0], "auth"), 1);
+        let second = service.tick(1);
+        assert!(second.len() == 1 && second[0].headers["dest"] == "db");
+        service.recv(reply_to(&second[0], "db"), 2);
+        let response = service.tick(2);
+        assert!(response.len() == 1 && response[0].headers["dest"] == "gateway");
+    }
+
+    #[test]
+    fn test_step_uses_previous_reply() {
+        let spec = ServiceSpec::plan(vec![
+            Step::call(CallTarget::Service("auth".to_string())),
+            Step::call(CallTarget::Service("db".to_string())).with_input(StepInput {
+                data_from_reply: true,
+                headers_from_reply: vec!["token".to_string()],
+            }),
+        ]);
+        let mut service = Service::new("frontend", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        let first = service.tick(0);
+        let mut auth_reply = reply_to(&first[0], "auth");
+        auth_reply.data = "user-42".to_string();
+        auth_reply
+            .headers
+            .insert("token".to_string(), "secret".to_string());
+        service.recv(auth_reply, 1);
+        let second = service.tick(1);
+        assert!(second.len() == 1 && second[0].headers["dest"] == "db");
+        assert!(second[0].data == "user-42");
+        assert!(second[0].headers["token"] == "secret");
+        assert!(second[0].headers["src"] == "frontend");
+    }
+
+    #[test]
+    fn test_parallel_step_then_call() {
+        let spec = ServiceSpec::plan(vec![
+            Step::new(vec![
+                CallTarget::Service("a".to_string()),
+                CallTarget::Service("b".to_string()),
+            ]),
+            Step::call(CallTarget::Service("c".to_string())),
+        ]);
+        let mut service = Service::new("frontend", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        let first = service.tick(0);
+        assert!(first.len() == 2);
+        service.recv(reply_to(&first[0], "a"), 1);
+        assert!(service.tick(1).is_empty());
+        service.recv(reply_to(&first[1], "b"), 2);
+        let second = service.tick(2);
+        assert!(second.len() == 1 && second[0].headers["dest"] == "c");
+        // without a StepInput the step sends the original request
+        assert!(second[0].data == "0");
+        service.recv(reply_to(&second[0], "c"), 3);
+        let response = service.tick(3);
+        assert!(response.len() == 1 && response[0].headers["dest"] == "gateway");
+    }
+
+    #[test]
+    fn test_wait_any_drops_late_replies() {
+        let spec = ServiceSpec::parallel(vec![
+            CallTarget::Service("replica-1".to_string()),
+            CallTarget::Service("replica-2".to_string()),
+        ])
+        .with_join(JoinPolicy::WaitAny);
+        let mut service = Service::new("frontend", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        let calls = service.tick(0);
+        service.recv(reply_to(&calls[1], "replica-2"), 1);
+        let response = service.tick(1);
+        assert!(response.len() == 1 && response[0].headers["dest"] == "gateway");
+        service.recv(reply_to(&calls[0], "replica-1"), 2);
+        assert!(service.get_ingress_queue().size() == 0);
+        assert!(service.tick(2).is_empty());
+    }
+
+    #[test]
+    fn test_call_timeout() {
+        let spec =
+            ServiceSpec::parallel(vec![CallTarget::Service("slow".to_string())]).with_timeout(2);
+        let mut service = Service::new("frontend", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        assert!(service.tick(0).len() == 1);
+        assert!(service.tick(1).is_empty());
+        let response = service.tick(2);
+        assert!(response.len() == 1);
+        assert!(response[0].headers["error"] == "timeout");
+        assert!(response[0].headers["dest"] == "gateway");
+    }
+
+    #[test]
+    fn test_routed_call() {
+        let mut routing = RoutingTable::uniform(&["reviews-v1", "reviews-v2"], 0);
+        routing.add_header_rule("user-cohort", "beta", "reviews-v3");
+        let spec = ServiceSpec::parallel(vec![CallTarget::Routed(Box::new(routing))]);
+        let mut service = Service::new("productpage-v1", 5, 5, None, 0, spec);
+        let mut request = request_from("gateway");
+        request
+            .headers
+            .insert("user-cohort".to_string(), "beta".to_string());
+        service.recv(request, 0);
+        let calls = service.tick(0);
+        assert!(calls[0].headers["dest"] == "reviews-v3");
+    }
+
+    #[test]
+    fn test_reply_without_src() {
+        let routing = RoutingTable::uniform(&["reviews-v1"], 0);
+        let spec = ServiceSpec::parallel(vec![CallTarget::Routed(Box::new(routing))]);
+        let mut service = Service::new("productpage-v1", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        let calls = service.tick(0);
+        let mut reply = reply_to(&calls[0], "reviews-v1");
+        reply.headers.remove("src");
+        service.recv(reply, 1);
+        // the reply still completes the call
+        let response = service.tick(1);
+        assert!(response.len() == 1 && response[0].headers["dest"] == "gateway");
+    }
+
+    #[test]
+    fn test_one_trace_from_two_callers() {
+        // b and c both call d for the same trace, as in a diamond
+        let spec = ServiceSpec::parallel(vec![CallTarget::Service("e".to_string())]);
+        let mut service = Service::new("d", 5, 5, None, 0, spec);
+        let from_b = request_from("b");
+        let mut from_c = request_from("c");
+        from_c.uid = from_b.uid;
+        service.recv(from_b, 0);
+        service.recv(from_c, 0);
+        let calls = service.tick(0);
+        assert!(calls.len() == 2);
+        assert!(calls[0].headers["call"] != calls[1].headers["call"]);
+
+        service.recv(reply_to(&calls[1], "e"), 1);
+        let response = service.tick(1);
+        assert!(response.len() == 1 && response[0].headers["dest"] == "c");
+        service.recv(reply_to(&calls[0], "e"), 2);
+        let response = service.tick(2);
+        assert!(response.len() == 1 && response[0].headers["dest"] == "b");
+        // the callers sent no key, so the responses carry none
+        assert!(!response[0].headers.contains_key("call"));
+        assert!(service.is_idle());
+    }
+
+    #[test]
+    fn test_reply_goes_to_its_routing_table() {
+        let spec = ServiceSpec::parallel(vec![
+            CallTarget::Routed(Box::new(RoutingTable::uniform(&["reviews-v1"], 0))),
+            CallTarget::Routed(Box::new(RoutingTable::uniform(&["reviews-v1"], 0))),
+        ]);
+        let mut service = Service::new("productpage-v1", 5, 5, None, 0, spec);
+        service.recv(request_from("gateway"), 0);
+        let calls = service.tick(0);
+        service.recv(reply_to(&calls[0], "reviews-v1"), 1);
+        let outstanding: Vec<u64> = service.spec.steps[0]
+            .calls
+            .iter()
+            .map(|call| match call {
+                CallTarget::Routed(table) => table.outstanding("reviews-v1"),
+                CallTarget::Service(_) => 0,
+            })
+            .collect();
+        assert!(outstanding == vec![0, 1]);
+    }
+
+    #[test]
+    fn test_request_without_src() {
+        let mut service = Service::new("0", 2, 1, None, 0, ServiceSpec::leaf());
+        let mut request = request_from("gateway");
+        request.headers.remove("src");
+        service.recv(request, 0);
+        // the request is dropped
+        assert!(service.tick(0).is_empty());
+        assert!(service.is_idle());
+    }
+
+    #[test]
+    fn test_plugin_initialization() {
+        let mut cargo_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        cargo_dir.push("../../target/debug/libfilter_example");
+        let library_str = cargo_dir.to_str().unwrap();
+        let service = Service::new("0", 2, 1, Some(library_str), 0, ServiceSpec::leaf());
+        assert!(service.core_node().plugin.is_some());
+    }
+}
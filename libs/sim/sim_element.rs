@@ -16,4 +16,12 @@ pub trait SimElement {
     fn neighbors(&self) -> &Vec<String>;
 
     fn as_any(&self) -> &dyn Any;
+
+    // a storage element that the filter of the element can send its output to
+    fn add_sink(&mut self, _sink: String) {}
+
+    // whether the element holds no RPCs, so it sends nothing until it receives something
+    fn is_idle(&self) -> bool {
+        true
+    }
 }
@@ -1,15 +1,16 @@
 //! This defines the simulator and coordinates all of the sim_elements.  It is a tick-based simulator, so at every tick,
 //! each sim_element will produce some RPCs and where they should go, and receive any in its own buffer.
 
+use crate::aggregation::{AggregateRow, Aggregation};
 use crate::edge::Edge;
 use crate::node::Node;
 use crate::sim_element::SimElement;
-use crate::storage::Storage;
+use crate::storage::{GroupKey, RecordQuery, Storage, StorageRecord};
 use csv::Writer;
+use indexmap::map::IndexMap;
 use petgraph::dot::{Config, Dot};
 use petgraph::graph::{Graph, NodeIndex};
 use rpc_lib::rpc::Rpc;
-use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs;
 use std::fs::OpenOptions;
@@ -23,12 +24,17 @@ impl<T: SimElement + Display> PrintableElement for T {}
 
 #[derive(Default)]
 pub struct Simulator {
-    elements: HashMap<String, Box<dyn PrintableElement>>, // elements of the simulator
+    // elements tick and edges deliver in the order they were added, so that a seed always gives
+    // the same simulation
+    elements: IndexMap<String, Box<dyn PrintableElement>>, // elements of the simulator
     graph: Graph<String, String>, // petgraph graph of our network, for printing and debugging
-    petgraph_id_map: HashMap<String, NodeIndex>, // maps nodes for graph
-    edge_matrix: HashMap<(String, String), Edge>,
+    petgraph_id_map: IndexMap<String, NodeIndex>, // maps nodes for graph
+    edge_matrix: IndexMap<(String, String), Edge>,
     seed: u64,                           // seed for random routing
     record_network_data: Option<String>, // the file name to write network usage data
+    network_bytes: u64,                  // the size of every RPC sent so far
+    record_deliveries: bool,             // whether to keep the RPCs delivered in a tick
+    deliveries: Vec<Rpc>,                // the RPCs delivered to elements in the last tick
 }
 
 impl<'a> Simulator {
@@ -38,8 +44,8 @@ impl<'a> Simulator {
     }
     fn get_from_edge_matrix(&mut self, left: String, right: String) -> &mut Edge {
         let key_tuple = &(left.clone(), right.clone());
-        if self.edge_matrix.contains_key(&key_tuple) {
-            return self.edge_matrix.get_mut(&key_tuple).unwrap();
+        if self.edge_matrix.contains_key(key_tuple) {
+            return self.edge_matrix.get_mut(key_tuple).unwrap();
         } else {
             log::error!("Edge connecting {:?} and {:?} not found", left, right);
             std::process::exit(1);
@@ -48,12 +54,15 @@ impl<'a> Simulator {
 
     pub fn new(seed: u64, record_network_data: Option<String>) -> Self {
         Simulator {
-            elements: HashMap::new(),
+            elements: IndexMap::new(),
             graph: Graph::new(),
-            petgraph_id_map: HashMap::new(),
-            edge_matrix: HashMap::new(),
+            petgraph_id_map: IndexMap::new(),
+            edge_matrix: IndexMap::new(),
             seed,
             record_network_data,
+            network_bytes: 0,
+            record_deliveries: false,
+            deliveries: Vec::new(),
         }
     }
 
@@ -65,6 +74,37 @@ impl<'a> Simulator {
         };
     }
 
+    pub fn query_records(&self, storage_id: &str, query: &RecordQuery) -> Vec<StorageRecord> {
+        self.get_element::<Storage>(storage_id)
+            .select(query)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn group_records(
+        &self,
+        storage_id: &str,
+        query: &RecordQuery,
+        key: &GroupKey,
+    ) -> IndexMap<String, Vec<StorageRecord>> {
+        let mut groups = IndexMap::new();
+        for (group, records) in self.get_element::<Storage>(storage_id).group_by(query, key) {
+            groups.insert(group, records.into_iter().cloned().collect());
+        }
+        groups
+    }
+
+    pub fn aggregate_records(
+        &self,
+        storage_id: &str,
+        query: &RecordQuery,
+        aggregation: &Aggregation,
+    ) -> Vec<AggregateRow> {
+        self.get_element::<Storage>(storage_id)
+            .aggregate(query, aggregation)
+    }
+
     pub fn add_node<T: 'static + PrintableElement>(&mut self, id: &str, node: T) {
         self.add_element(id, node);
         self.petgraph_id_map
@@ -79,6 +119,29 @@ impl<'a> Simulator {
         };
     }
 
+    // The element with this id, whatever its type
+    pub fn element(&self, id: &str) -> Option<&dyn PrintableElement> {
+        self.elements.get(id).map(|element| element.as_ref())
+    }
+
+    pub fn element_ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.elements.keys().map(|id| id.as_str()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    // The ids of the storage elements, sorted
+    pub fn storage_ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self
+            .elements
+            .iter()
+            .filter(|(_, element)| element.as_any().is::<Storage>())
+            .map(|(id, _)| id.as_str())
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
     pub fn add_random_node(
         &mut self,
         id: &str,
@@ -99,8 +162,13 @@ impl<'a> Simulator {
     }
 
     pub fn insert_rpc(&mut self, target: &str, rpc: Rpc) {
+        self.insert_rpc_at(target, rpc, 0);
+    }
+
+    // Delivers an RPC to an element as if it arrived at this tick
+    pub fn insert_rpc_at(&mut self, target: &str, rpc: Rpc, tick: u64) {
         let node = self.elements.get_mut(target).unwrap();
-        node.recv(rpc, 0);
+        node.recv(rpc, tick);
     }
 
     pub fn add_edge(&mut self, delay: u64, left: &str, right: &str, bidirectional: bool) {
@@ -140,6 +208,18 @@ impl<'a> Simulator {
             .insert(id.to_string(), self.graph.add_node(id.to_string()));
     }
 
+    /// Adds a storage element as a named sink for filter output.  Each of the sources gets an
+    /// edge to the sink with the given delay and learns that it is a sink; filters target the
+    /// sink by putting its id in the dest header.
+    pub fn add_sink(&mut self, storage: Storage, delay: u64, sources: &[&str]) {
+        let id = storage.whoami().to_string();
+        self.add_node(&id, storage);
+        for source in sources {
+            self.add_edge(delay, source, &id, true);
+            self.elements.get_mut(*source).unwrap().add_sink(id.clone());
+        }
+    }
+
     fn add_element<T: 'static + PrintableElement>(&mut self, id: &str, element: T) -> usize {
         self.elements.insert(id.to_string(), Box::new(element));
         return self.elements.len() - 1;
@@ -186,6 +266,40 @@ impl<'a> Simulator {
             .expect("failed to execute process");
     }
 
+    // How much data the application has sent over the network
+    pub fn network_bytes(&self) -> u64 {
+        self.network_bytes
+    }
+
+    // The RPCs on every edge that have not arrived yet, by source and destination
+    pub fn in_flight(&self) -> Vec<(&str, &str, usize)> {
+        let mut edges: Vec<(&str, &str, usize)> = self
+            .edge_matrix
+            .iter()
+            .map(|((src, dest), edge)| (src.as_str(), dest.as_str(), edge.in_flight()))
+            .collect();
+        edges.sort_unstable();
+        edges
+    }
+
+    // Whether no element holds or sends an RPC, e.g. once every request has been answered
+    pub fn is_idle(&self) -> bool {
+        self.elements.values().all(|element| element.is_idle())
+            && self.edge_matrix.values().all(|edge| edge.is_idle())
+    }
+
+    /* Keep a copy of every RPC that an element receives in a tick, until the next tick, e.g. to
+     * break when an RPC reaches a node.  The dest header of each RPC says which element got it.
+     */
+    pub fn set_record_deliveries(&mut self, record: bool) {
+        self.record_deliveries = record;
+        self.deliveries.clear();
+    }
+
+    pub fn deliveries(&self) -> &[Rpc] {
+        &self.deliveries
+    }
+
     // if self.record_network_data is set, returns amt of data used per
     // tick
     pub fn tick(&mut self, tick: u64) {
@@ -203,6 +317,7 @@ impl<'a> Simulator {
         }
 
         // collect network data if necessary
+        self.network_bytes += rpc_buffer.iter().map(|rpc| rpc.len() as u64).sum::<u64>();
         if self.record_network_data.is_some() {
             self.write_to_network_usage_file(tick, &rpc_buffer);
         }
@@ -223,7 +338,11 @@ impl<'a> Simulator {
             edge_buffer.extend(edge.tick(tick));
         }
         // finally, start the receive phase on the nodes
+        self.deliveries.clear();
         for rpc in edge_buffer {
+            if self.record_deliveries {
+                self.deliveries.push(rpc.clone());
+            }
             let dst = &rpc.headers["dest"];
             match self.elements.get_mut(dst) {
                 Some(elem) => elem.recv(rpc, tick),
@@ -236,3 +355,65 @@ impl<'a> Simulator {
         log::info!("################# TICK {0} END #################", tick);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{CallTarget, Service, ServiceSpec};
+
+    #[test]
+    fn test_sinks_keep_outputs_separate() {
+        let mut simulator = Simulator::new(0, None);
+        // the service "reports" every request to both sinks
+        let spec = ServiceSpec::parallel(vec![
+            CallTarget::Service("traces".to_string()),
+            CallTarget::Service("metrics".to_string()),
+        ]);
+        simulator.add_node("frontend", Service::new("frontend", 5, 5, None, 0, spec));
+        simulator.add_sink(Storage::new("traces", None), 0, &["frontend"]);
+        simulator.add_sink(Storage::new("metrics", None), 2, &["frontend"]);
+        assert!(simulator.storage_ids() == vec!["metrics", "traces"]);
+        let frontend = simulator.get_element::<Service>("frontend").core_node();
+        assert!(frontend.sinks == vec!["traces", "metrics"]);
+        simulator.insert_rpc("frontend", Rpc::new_with_src("0", "client"));
+        for tick in 0..3 {
+            simulator.tick(tick);
+            if tick == 1 {
+                // the slow sink has not received its RPC yet
+                assert!(simulator.in_flight().contains(&("frontend", "metrics", 1)));
+            }
+        }
+        let traces = simulator.query_records("traces", &RecordQuery::new());
+        let metrics = simulator.query_records("metrics", &RecordQuery::new());
+        assert!(traces.len() == 1 && traces[0].tick == 0);
+        assert!(metrics.len() == 1 && metrics[0].tick == 2);
+    }
+
+    #[test]
+    fn test_record_deliveries() {
+        let mut simulator = Simulator::new(0, None);
+        let spec = ServiceSpec::parallel(vec![CallTarget::Service("traces".to_string())]);
+        simulator.add_node("frontend", Service::new("frontend", 5, 5, None, 0, spec));
+        simulator.add_sink(Storage::new("traces", None), 1, &["frontend"]);
+        simulator.insert_rpc("frontend", Rpc::new_with_src("0", "client"));
+        simulator.tick(0);
+        assert!(simulator.deliveries().is_empty());
+
+        simulator.set_record_deliveries(true);
+        simulator.tick(1);
+        let destinations: Vec<&str> = simulator
+            .deliveries()
+            .iter()
+            .map(|rpc| rpc.headers["dest"].as_str())
+            .collect();
+        assert!(destinations == vec!["traces"], "{:?}", destinations);
+        simulator.tick(2);
+        assert!(simulator
+            .in_flight()
+            .iter()
+            .all(|(_, _, in_flight)| *in_flight == 0));
+        assert!(simulator.element_ids() == vec!["frontend", "traces"]);
+        assert!(simulator.element("traces").unwrap().whoami() == "traces");
+        assert!(simulator.element("backend").is_none());
+    }
+}
@@ -1,18 +1,181 @@
-//! An abstraction of a node.  The node can have a plugin, which is meant to reprsent a WebAssembly filter
-//! A node is a sim_element.
+//! A storage element is the sim_element that filters send their results to.  It can have an
+//! aggregation filter, which sees every RPC before it is stored.
+//! Storage keeps every RPC sent to it as a structured record, which can be filtered and grouped
+//! with a RecordQuery.  Records can also be appended to a newline-delimited JSON file as they are
+//! stored, so they outlive the simulation.
 
+use crate::aggregation::{AggregateRow, Aggregation};
 use crate::plugin_wrapper::PluginWrapper;
 use crate::sim_element::SimElement;
 use core::any::Any;
+use indexmap::map::IndexMap;
 use rpc_lib::rpc::Rpc;
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Headers that only route the RPC to storage and are not stored as fields
+const ROUTING_HEADERS: [&str; 4] = ["src", "dest", "direction", "location"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StorageRecord {
+    pub trace_id: u64,
+    pub source: String, // the node that sent the record
+    pub tick: u64,      // when the record was stored
+    pub fields: IndexMap<String, String>,
+    pub payload: String, // the data of the RPC
+}
+
+impl StorageRecord {
+    fn from_rpc(rpc: &Rpc, default_source: &str, tick: u64) -> StorageRecord {
+        let mut fields = IndexMap::new();
+        for (key, value) in &rpc.headers {
+            if !ROUTING_HEADERS.contains(&key.as_str()) {
+                fields.insert(key.clone(), value.clone());
+            }
+        }
+        StorageRecord {
+            trace_id: rpc.uid,
+            source: match rpc.headers.get("src") {
+                Some(src) => src.clone(),
+                None => default_source.to_string(),
+            },
+            tick,
+            fields,
+            payload: rpc.data.clone(),
+        }
+    }
+}
+
+// Runs started by this process, so that runs started at the same time get different ids
+static RUNS: AtomicU64 = AtomicU64::new(0);
+
+// The run a persisted record comes from, so that the records of many runs in one file can be
+// told apart
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub run_id: String,
+    pub seed: u64,
+    pub topology: String,
+}
+
+impl RunMetadata {
+    // A new run of the topology, with an id that no other run gets, in this process or another
+    pub fn new(seed: u64, topology: &str) -> RunMetadata {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos());
+        RunMetadata {
+            run_id: format!(
+                "{0:x}-{1}-{2}",
+                started,
+                std::process::id(),
+                RUNS.fetch_add(1, Ordering::Relaxed)
+            ),
+            seed,
+            topology: topology.to_string(),
+        }
+    }
+}
+
+// A line of the persisted file: the record, the storage element that kept it and the run
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PersistedRecord {
+    pub storage: String,
+    #[serde(flatten)]
+    pub run: RunMetadata,
+    #[serde(flatten)]
+    pub record: StorageRecord,
+}
+
+// What to group records by
+pub enum GroupKey {
+    Trace,
+    Source,
+    Tick,
+    Field(String), // records without the field are left out
+}
+
+impl GroupKey {
+    pub fn group_of(&self, record: &StorageRecord) -> Option<String> {
+        match self {
+            GroupKey::Trace => Some(record.trace_id.to_string()),
+            GroupKey::Source => Some(record.source.clone()),
+            GroupKey::Tick => Some(record.tick.to_string()),
+            GroupKey::Field(field) => record.fields.get(field).cloned(),
+        }
+    }
+}
+
+/// Selects records.  Every condition that is set has to hold; an empty query selects all
+/// records.
+#[derive(Clone, Default)]
+pub struct RecordQuery {
+    trace_id: Option<u64>,
+    source: Option<String>,
+    ticks: Option<(u64, u64)>,
+    fields: Vec<(String, String)>,
+}
+
+impl RecordQuery {
+    pub fn new() -> RecordQuery {
+        RecordQuery::default()
+    }
+
+    pub fn trace(mut self, trace_id: u64) -> RecordQuery {
+        self.trace_id = Some(trace_id);
+        self
+    }
+
+    pub fn source(mut self, source: &str) -> RecordQuery {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    // Records stored from tick `start` up to, but not including, tick `end`
+    pub fn between(mut self, start: u64, end: u64) -> RecordQuery {
+        self.ticks = Some((start, end));
+        self
+    }
+
+    pub fn field(mut self, key: &str, value: &str) -> RecordQuery {
+        self.fields.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn matches(&self, record: &StorageRecord) -> bool {
+        if let Some(trace_id) = self.trace_id {
+            if record.trace_id != trace_id {
+                return false;
+            }
+        }
+        if let Some(source) = &self.source {
+            if &record.source != source {
+                return false;
+            }
+        }
+        if let Some((start, end)) = self.ticks {
+            if record.tick < start || record.tick >= end {
+                return false;
+            }
+        }
+        self.fields
+            .iter()
+            .all(|(key, value)| record.fields.get(key) == Some(value))
+    }
+}
 
 #[derive(Default)]
 pub struct Storage {
-    data: String,                  // all the data that has been stored
+    records: Vec<StorageRecord>,   // all the data that has been stored
     id: String,                    // id
     neighbors: Vec<String>,        // who is the node connected to
     plugin: Option<PluginWrapper>, // aggregation filter
+    persist_file: Option<LineWriter<File>>, // records are also appended here
+    run: RunMetadata,              // persisted with every record
 }
 
 impl fmt::Display for Storage {
@@ -62,8 +225,9 @@ impl SimElement for Storage {
 
 impl Storage {
     pub fn store(&mut self, x: Rpc, now: u64) {
-        // we don't want to store everything, just the stuff that was sent to us
-        if x.headers.contains_key("dest") && x.headers["dest"].contains(&self.id) {
+        // we don't want to store everything, just the stuff that was sent to us.
+        // Sinks can have similar names (storage, storage-metrics), so the id must match exactly
+        if x.headers.get("dest") == Some(&self.id) {
             let new_rpcs;
             if let Some(plugin) = self.plugin.as_mut() {
                 plugin.recv(x, now);
@@ -72,31 +236,112 @@ impl Storage {
                 new_rpcs = vec![x];
             }
             for rpc in new_rpcs {
-                self.data.push_str(&rpc.data);
-                self.data.push_str("\n");
+                let record = StorageRecord::from_rpc(&rpc, &self.id, now);
+                self.persist(&record);
+                self.records.push(record);
             }
         }
     }
+
+    fn persist(&mut self, record: &StorageRecord) {
+        if let Some(file) = self.persist_file.as_mut() {
+            let line = PersistedRecord {
+                storage: self.id.clone(),
+                run: self.run.clone(),
+                record: record.clone(),
+            };
+            let json = serde_json::to_string(&line).unwrap();
+            if let Err(e) = writeln!(file, "{}", json) {
+                log::error!("could not persist record of {0}, {1}", self.id, e);
+            }
+        }
+    }
+
+    /// Appends every record stored from now on to the file at `path`, one JSON object per
+    /// line, together with the run.  Existing contents are kept, so several runs can share a
+    /// file.
+    pub fn with_persistence(mut self, path: &str, run: RunMetadata) -> std::io::Result<Storage> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.persist_file = Some(LineWriter::new(file));
+        self.run = run;
+        Ok(self)
+    }
+
+    // Reads back the records of a persisted file, with the storage element and run of each
+    pub fn load_records(path: &str) -> std::io::Result<Vec<PersistedRecord>> {
+        let mut records = vec![];
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+        Ok(records)
+    }
+
+    // The payloads of all records, one per line
     pub fn query(&self) -> String {
-        return self.data.clone();
+        let mut data = String::new();
+        for record in &self.records {
+            data.push_str(&record.payload);
+            data.push('\n');
+        }
+        data
+    }
+
+    pub fn records(&self) -> &Vec<StorageRecord> {
+        &self.records
+    }
+
+    pub fn select(&self, query: &RecordQuery) -> Vec<&StorageRecord> {
+        self.records
+            .iter()
+            .filter(|record| query.matches(record))
+            .collect()
+    }
+
+    pub fn aggregate(&self, query: &RecordQuery, aggregation: &Aggregation) -> Vec<AggregateRow> {
+        aggregation.apply(&self.select(query))
+    }
+
+    // Groups the selected records, in the order the groups were first seen
+    pub fn group_by(
+        &self,
+        query: &RecordQuery,
+        key: &GroupKey,
+    ) -> IndexMap<String, Vec<&StorageRecord>> {
+        let mut groups: IndexMap<String, Vec<&StorageRecord>> = IndexMap::new();
+        for record in self.select(query) {
+            let group = match key.group_of(record) {
+                Some(group) => group,
+                None => continue,
+            };
+            groups.entry(group).or_insert_with(Vec::new).push(record);
+        }
+        groups
     }
 
     pub fn new(id: &str, aggregation_file: Option<&str>) -> Storage {
         if aggregation_file.is_none() {
             Storage {
-                data: String::new(),
+                records: Vec::new(),
                 id: id.to_string(),
                 neighbors: Vec::new(),
                 plugin: None,
+                persist_file: None,
+                run: RunMetadata::default(),
             }
         } else {
             let mut aggr_plugin = PluginWrapper::new(id, aggregation_file.unwrap());
             aggr_plugin.add_connection(id.to_string());
             Storage {
-                data: String::new(),
+                records: Vec::new(),
                 id: id.to_string(),
                 neighbors: Vec::new(),
                 plugin: Some(aggr_plugin),
+                persist_file: None,
+                run: RunMetadata::default(),
             }
         }
     }
@@ -161,4 +406,103 @@ mod tests {
             storage.query()
         );
     }
+
+    fn record_rpc(data: &str, src: &str, uid: u64) -> Rpc {
+        let mut rpc = Rpc::new_with_src(data, src);
+        rpc.uid = uid;
+        rpc.headers
+            .insert("dest".to_string(), "storage".to_string());
+        rpc
+    }
+
+    #[test]
+    fn test_structured_records() {
+        let mut storage = Storage::new("storage", None);
+        let mut rpc = record_rpc("2", "productpage-v1", 7);
+        rpc.headers
+            .insert("query".to_string(), "height".to_string());
+        storage.recv(rpc, 3);
+        // not for us
+        storage.recv(Rpc::new_with_src("1", "details-v1"), 4);
+        let records = storage.records();
+        assert!(records.len() == 1);
+        assert!(records[0].trace_id == 7);
+        assert!(records[0].source == "productpage-v1");
+        assert!(records[0].tick == 3);
+        assert!(records[0].payload == "2");
+        // routing headers are not fields
+        assert!(records[0].fields.len() == 1 && records[0].fields["query"] == "height");
+    }
+
+    #[test]
+    fn test_exact_destination() {
+        let mut storage = Storage::new("storage", None);
+        let mut rpc = record_rpc("1", "productpage-v1", 1);
+        rpc.headers
+            .insert("dest".to_string(), "storage-metrics".to_string());
+        storage.recv(rpc, 0);
+        assert!(storage.records().is_empty());
+    }
+
+    #[test]
+    fn test_persist_records() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("storage_test_{}.ndjson", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let first_run = RunMetadata::new(7, "bookinfo");
+        {
+            let mut storage = Storage::new("storage", None)
+                .with_persistence(path_str, first_run.clone())
+                .unwrap();
+            storage.recv(record_rpc("2", "productpage-v1", 1), 3);
+        }
+        // a second run with the same seed appends to the same file
+        let second_run = RunMetadata::new(7, "bookinfo");
+        assert!(first_run.run_id != second_run.run_id);
+        let mut storage = Storage::new("storage", None)
+            .with_persistence(path_str, second_run.clone())
+            .unwrap();
+        let mut rpc = record_rpc("5", "reviews-v1", 2);
+        rpc.headers
+            .insert("query".to_string(), "height".to_string());
+        storage.recv(rpc, 4);
+        let loaded = Storage::load_records(path_str).unwrap();
+        assert!(loaded.len() == 2);
+        assert!(loaded[0].record.payload == "2" && loaded[0].record.tick == 3);
+        assert!(loaded[0].run == first_run && loaded[0].storage == "storage");
+        assert!(&loaded[1].record == storage.records().last().unwrap());
+        assert!(loaded[1].run == second_run && loaded[1].run.seed == 7);
+        std::fs::remove_file(&path).unwrap();
+
+        // the caller decides what to do about a file that cannot be opened
+        let missing = std::env::temp_dir().join("no_such_directory/records.ndjson");
+        assert!(Storage::new("storage", None)
+            .with_persistence(missing.to_str().unwrap(), RunMetadata::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_select_and_group() {
+        let mut storage = Storage::new("storage", None);
+        for (tick, (src, uid)) in [("a", 1), ("b", 1), ("a", 2), ("c", 3)].iter().enumerate() {
+            let mut rpc = record_rpc(&tick.to_string(), src, *uid);
+            if *src != "c" {
+                rpc.headers.insert("kind".to_string(), src.to_string());
+            }
+            storage.recv(rpc, tick as u64);
+        }
+        assert!(storage.select(&RecordQuery::new()).len() == 4);
+        assert!(storage.select(&RecordQuery::new().trace(1)).len() == 2);
+        assert!(storage.select(&RecordQuery::new().source("a")).len() == 2);
+        assert!(storage.select(&RecordQuery::new().between(1, 3)).len() == 2);
+        let selected = storage.select(&RecordQuery::new().source("a").field("kind", "a").trace(2));
+        assert!(selected.len() == 1 && selected[0].payload == "2");
+
+        let by_trace = storage.group_by(&RecordQuery::new(), &GroupKey::Trace);
+        assert!(by_trace.keys().collect::<Vec<_>>() == vec!["1", "2", "3"]);
+        assert!(by_trace["1"].len() == 2);
+        let by_kind = storage.group_by(&RecordQuery::new(), &GroupKey::Field("kind".to_string()));
+        assert!(by_kind.len() == 2 && by_kind["a"].len() == 2);
+    }
 }
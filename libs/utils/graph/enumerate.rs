@@ -0,0 +1,368 @@
+/* This file enumerates every mapping of a target (user-given) graph into a trace graph, for
+ * queries that aggregate over all matches instead of stopping at the first one.
+ *
+ * A mapping sends each target node to a distinct trace node with matching properties, such
+ * that every direct target edge has a trace edge in the same direction with matching
+ * properties, and every transitive target edge has a path in the same direction.
+ * Mappings are returned as (target node, trace node) pairs ordered by target node, and two
+ * mappings are distinct if they send some target node to different trace nodes.
+ */
+
+use super::graph_utils::{
+    edge_satisfied, has_property_subset, has_transitive_edges, reachability, EdgeKind,
+    PropertyGraph, TraceGraph,
+};
+use super::predicate::PropertyMatcher;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::{Incoming, Outgoing};
+use std::collections::VecDeque;
+
+pub type Mapping = Vec<(NodeIndex, NodeIndex)>;
+
+/* A backtracking search over the target nodes.  Target nodes are visited so that each one
+ * (except the first of a connected component) is adjacent to one visited before it, which
+ * limits its candidates to the neighbors of an already mapped trace node.
+ */
+pub struct Mappings<'a, P: PropertyMatcher> {
+    graph_g: &'a TraceGraph,
+    graph_h: &'a PropertyGraph<P>,
+    order: Vec<NodeIndex>, // the order in which target nodes are mapped
+    candidates: Vec<Vec<NodeIndex>>, // trace nodes to try, per depth of the search
+    next_candidate: Vec<usize>,
+    assigned: Vec<Option<NodeIndex>>,  // by target node index
+    used: Vec<bool>,                   // by trace node index
+    reachable: Option<Vec<Vec<bool>>>, // only needed for transitive edges
+}
+
+pub fn mappings<'a, P: PropertyMatcher>(
+    graph_g: &'a TraceGraph,
+    graph_h: &'a PropertyGraph<P>,
+) -> Mappings<'a, P> {
+    let mut mappings = Mappings {
+        graph_g,
+        graph_h,
+        order: search_order(graph_h),
+        candidates: Vec::new(),
+        next_candidate: Vec::new(),
+        assigned: vec![None; graph_h.node_count()],
+        used: vec![false; graph_g.node_count()],
+        reachable: None,
+    };
+    if has_transitive_edges(graph_h) {
+        mappings.reachable = Some(reachability(graph_g));
+    }
+    if !mappings.order.is_empty() && graph_h.node_count() <= graph_g.node_count() {
+        let first = mappings.candidates_for(mappings.order[0]);
+        mappings.candidates.push(first);
+        mappings.next_candidate.push(0);
+    }
+    mappings
+}
+
+// At most `limit` mappings, or all of them
+pub fn find_all_mappings<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+    limit: Option<usize>,
+) -> Vec<Mapping> {
+    mappings(graph_g, graph_h)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+// Breadth first over the undirected graph, starting a new search for every component
+fn search_order<N, E>(graph: &petgraph::Graph<N, E>) -> Vec<NodeIndex> {
+    let mut order = Vec::new();
+    let mut seen = vec![false; graph.node_count()];
+    for start in graph.node_indices() {
+        if seen[start.index()] {
+            continue;
+        }
+        seen[start.index()] = true;
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+            for neighbor in graph.neighbors_undirected(node) {
+                if !seen[neighbor.index()] {
+                    seen[neighbor.index()] = true;
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+    order
+}
+
+/* The trace nodes a target node can be mapped to, given the nodes mapped so far: the callees
+ * (or callers) of where a caller (or callee) over a direct edge is mapped, else the descendants
+ * (or ancestors) of where one over a transitive edge is mapped, else all trace nodes.
+ */
+pub(crate) fn anchored_candidates<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+    node_h: NodeIndex,
+    assigned: &[Option<NodeIndex>],
+    reachable: Option<&[Vec<bool>]>,
+) -> Vec<NodeIndex> {
+    let mapped_neighbor = |kind| {
+        let callers = graph_h
+            .edges_directed(node_h, Incoming)
+            .map(|edge| (edge, edge.source(), Outgoing));
+        let callees = graph_h
+            .edges_directed(node_h, Outgoing)
+            .map(|edge| (edge, edge.target(), Incoming));
+        callers
+            .chain(callees)
+            .filter(|(edge, _, _)| EdgeKind::of(edge.weight()) == kind)
+            .find_map(|(_, neighbor, direction)| {
+                assigned[neighbor.index()].map(|neighbor_g| (neighbor_g, direction))
+            })
+    };
+    if let Some((neighbor_g, direction)) = mapped_neighbor(EdgeKind::Direct) {
+        return graph_g.neighbors_directed(neighbor_g, direction).collect();
+    }
+    if let (Some((neighbor_g, direction)), Some(reachable)) =
+        (mapped_neighbor(EdgeKind::Transitive), reachable)
+    {
+        return graph_g
+            .node_indices()
+            .filter(|node_g| match direction {
+                Outgoing => reachable[neighbor_g.index()][node_g.index()],
+                Incoming => reachable[node_g.index()][neighbor_g.index()],
+            })
+            .collect();
+    }
+    graph_g.node_indices().collect()
+}
+
+impl<'a, P: PropertyMatcher> Mappings<'a, P> {
+    fn candidates_for(&self, node_h: NodeIndex) -> Vec<NodeIndex> {
+        let mut candidates = anchored_candidates(
+            self.graph_g,
+            self.graph_h,
+            node_h,
+            &self.assigned,
+            self.reachable.as_deref(),
+        );
+        // parallel edges list the same neighbor more than once
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    // Where a target node is mapped to, if we also map node_h to node_g
+    fn image(&self, node: NodeIndex, node_h: NodeIndex, node_g: NodeIndex) -> Option<NodeIndex> {
+        if node == node_h {
+            return Some(node_g);
+        }
+        self.assigned[node.index()]
+    }
+
+    fn feasible(&self, node_h: NodeIndex, node_g: NodeIndex) -> bool {
+        if self.used[node_g.index()]
+            || !has_property_subset(&self.graph_g[node_g].1, &self.graph_h[node_h].1)
+        {
+            return false;
+        }
+        // every target edge between node_h and a mapped node needs a matching trace edge or path
+        let outgoing = self.graph_h.edges_directed(node_h, Outgoing);
+        let incoming = self.graph_h.edges_directed(node_h, Incoming);
+        for edge_h in outgoing.chain(incoming) {
+            let source = self.image(edge_h.source(), node_h, node_g);
+            let target = self.image(edge_h.target(), node_h, node_g);
+            if let (Some(source), Some(target)) = (source, target) {
+                let reachable = self.reachable.as_deref();
+                if !edge_satisfied(self.graph_g, edge_h.weight(), source, target, reachable) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn current_mapping(&self) -> Mapping {
+        self.graph_h
+            .node_indices()
+            .map(|node_h| (node_h, self.assigned[node_h.index()].unwrap()))
+            .collect()
+    }
+}
+
+impl<'a, P: PropertyMatcher> Iterator for Mappings<'a, P> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        while !self.candidates.is_empty() {
+            let depth = self.candidates.len() - 1;
+            let node_h = self.order[depth];
+            // undo the choice we made at this depth last time
+            if let Some(previous) = self.assigned[node_h.index()].take() {
+                self.used[previous.index()] = false;
+            }
+            let mut chosen = None;
+            while self.next_candidate[depth] < self.candidates[depth].len() {
+                let node_g = self.candidates[depth][self.next_candidate[depth]];
+                self.next_candidate[depth] += 1;
+                if self.feasible(node_h, node_g) {
+                    chosen = Some(node_g);
+                    break;
+                }
+            }
+            match chosen {
+                None => {
+                    // no candidates left, so backtrack
+                    self.candidates.pop();
+                    self.next_candidate.pop();
+                }
+                Some(node_g) => {
+                    self.assigned[node_h.index()] = Some(node_g);
+                    self.used[node_g.index()] = true;
+                    if depth + 1 == self.order.len() {
+                        return Some(self.current_mapping());
+                    }
+                    let next_candidates = self.candidates_for(self.order[depth + 1]);
+                    self.candidates.push(next_candidates);
+                    self.next_candidate.push(0);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::graph_utils::{get_node_with_id, graph_from_edges};
+
+    // Nodes named by their index
+    fn names(node_count: usize) -> Vec<usize> {
+        (0..node_count).collect()
+    }
+
+    // Tries every injective assignment of target nodes to trace nodes
+    fn brute_force_count(graph_g: &TraceGraph, graph_h: &TraceGraph) -> usize {
+        fn extend(
+            graph_g: &TraceGraph,
+            graph_h: &TraceGraph,
+            assignment: &mut Vec<NodeIndex>,
+        ) -> usize {
+            if assignment.len() == graph_h.node_count() {
+                let valid = graph_h.edge_indices().all(|edge| {
+                    let (source, target) = graph_h.edge_endpoints(edge).unwrap();
+                    graph_g
+                        .find_edge(assignment[source.index()], assignment[target.index()])
+                        .is_some()
+                }) && graph_h.node_indices().all(|node| {
+                    has_property_subset(&graph_g[assignment[node.index()]].1, &graph_h[node].1)
+                });
+                return valid as usize;
+            }
+            let mut count = 0;
+            for node_g in graph_g.node_indices() {
+                if !assignment.contains(&node_g) {
+                    assignment.push(node_g);
+                    count += extend(graph_g, graph_h, assignment);
+                    assignment.pop();
+                }
+            }
+            count
+        }
+        extend(graph_g, graph_h, &mut Vec::new())
+    }
+
+    // A deterministic pseudo-random DAG, edges only go from lower to higher indices
+    fn pseudo_random_dag(node_count: usize, seed: u64) -> TraceGraph {
+        let mut state = seed;
+        let mut edges = Vec::new();
+        for target in 1..node_count {
+            for source in 0..target {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                if (state >> 33) % 3 == 1 {
+                    edges.push((source, target));
+                }
+            }
+        }
+        graph_from_edges(&names(node_count), &edges)
+    }
+
+    #[test]
+    fn test_all_calls_in_bookinfo() {
+        // productpage -> reviews, productpage -> details, reviews -> ratings
+        let graph_g = graph_from_edges(&names(4), &[(0, 1), (0, 3), (1, 2)]);
+        let single_call = graph_from_edges(&names(2), &[(0, 1)]);
+        assert!(find_all_mappings(&graph_g, &single_call, None).len() == 3);
+        let fan_out = graph_from_edges(&names(3), &[(0, 1), (0, 2)]);
+        assert!(find_all_mappings(&graph_g, &fan_out, None).len() == 2);
+        let chain = graph_from_edges(&names(3), &[(0, 1), (1, 2)]);
+        let chains = find_all_mappings(&graph_g, &chain, None);
+        assert!(chains.len() == 1);
+        assert!(
+            chains[0]
+                == vec![
+                    (NodeIndex::new(0), NodeIndex::new(0)),
+                    (NodeIndex::new(1), NodeIndex::new(1)),
+                    (NodeIndex::new(2), NodeIndex::new(2)),
+                ]
+        );
+    }
+
+    #[test]
+    fn test_limit_and_distinct() {
+        let graph_g = graph_from_edges(&names(5), &[(0, 1), (0, 2), (0, 3), (0, 4)]);
+        let single_call = graph_from_edges(&names(2), &[(0, 1)]);
+        assert!(find_all_mappings(&graph_g, &single_call, Some(2)).len() == 2);
+        let mut all = find_all_mappings(&graph_g, &single_call, None);
+        assert!(all.len() == 4);
+        all.sort();
+        all.dedup();
+        assert!(all.len() == 4);
+        // a pattern bigger than the trace has no mappings
+        assert!(find_all_mappings(&single_call, &graph_g, None).is_empty());
+    }
+
+    #[test]
+    fn test_properties_restrict_mappings() {
+        let mut graph_g = graph_from_edges(&names(3), &[(0, 1), (0, 2)]);
+        graph_g[NodeIndex::new(2)]
+            .1
+            .insert("service_name".to_string(), "ratings-v1".to_string());
+        let mut graph_h = graph_from_edges(&names(2), &[(0, 1)]);
+        graph_h[NodeIndex::new(1)]
+            .1
+            .insert("service_name".to_string(), "ratings-v1".to_string());
+        let all = find_all_mappings(&graph_g, &graph_h, None);
+        assert!(all.len() == 1);
+        let ratings = get_node_with_id(&graph_g, "2".to_string()).unwrap();
+        assert!(all[0][1] == (NodeIndex::new(1), ratings));
+    }
+
+    #[test]
+    fn test_against_brute_force() {
+        let patterns = vec![
+            graph_from_edges(&names(2), &[(0, 1)]),
+            graph_from_edges(&names(3), &[(0, 1), (0, 2)]),
+            graph_from_edges(&names(3), &[(0, 1), (1, 2)]),
+            graph_from_edges(&names(3), &[(0, 2), (1, 2)]),
+            graph_from_edges(&names(3), &[(0, 1), (0, 2), (1, 2)]),
+            graph_from_edges(&names(4), &[(0, 1), (1, 2), (1, 3)]),
+            graph_from_edges(&names(3), &[(0, 1)]), // with an isolated node
+        ];
+        for seed in 0..5 {
+            let graph_g = pseudo_random_dag(6, seed);
+            for graph_h in &patterns {
+                assert_eq!(
+                    find_all_mappings(&graph_g, graph_h, None).len(),
+                    brute_force_count(&graph_g, graph_h),
+                    "seed {}, pattern {:?}",
+                    seed,
+                    graph_h
+                );
+            }
+        }
+    }
+}
@@ -1,9 +1,45 @@
 /* This file contains functions relating to creating and comparing trace and target (user-given) graphs */
 
+use super::predicate::PropertyMatcher;
 use indexmap::map::IndexMap;
+use petgraph::algo::{has_path_connecting, is_cyclic_directed};
 use petgraph::graph::{Graph, NodeIndex};
 use petgraph::visit::DfsPostOrder;
-use petgraph::Incoming;
+use petgraph::{Incoming, Outgoing};
+use std::fmt;
+
+// Trace graphs and target graphs: nodes are (name, properties) and edges carry the properties of
+// the call between them.  A target graph can use predicates (P) instead of exact values.
+pub type PropertyGraph<P> = Graph<(String, IndexMap<String, P>), IndexMap<String, P>>;
+pub type TraceGraph = PropertyGraph<String>;
+
+/* An edge of a target graph is direct (one call) unless its EDGE_KIND property says it is
+ * transitive: then the target of the edge only has to be a descendant of its source, through
+ * any number of calls.  A transitive edge places no other constraints on the calls.
+ */
+pub const EDGE_KIND: &str = "edge.kind";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Direct,
+    Transitive,
+}
+
+impl EdgeKind {
+    pub fn of<P: PropertyMatcher>(edge: &IndexMap<String, P>) -> EdgeKind {
+        match edge.get(EDGE_KIND).and_then(|kind| kind.exact_value()) {
+            Some("transitive") => EdgeKind::Transitive,
+            _ => EdgeKind::Direct,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EdgeKind::Direct => "direct",
+            EdgeKind::Transitive => "transitive",
+        }
+    }
+}
 
 /* This function creates a petgraph graph representing the query given by the user.
  * For example, if the cql query were MATCH n -> m, e WHERE ... the input to this function
@@ -12,16 +48,33 @@ use petgraph::Incoming;
  * Arguments:
  * @vertices:  the vertices of the graph to construct
  * @edges:  the edges of the graph to construct
+ * @ids_to_properties:  what the properties of each vertex have to match, either exact values
+ *                      (Strings) or predicates
  *
  * Return Value:
  * @graph: the constructed graph reprsenting the inputs
  */
 
-pub fn generate_target_graph(
+pub fn generate_target_graph<P: Clone>(
     vertices: Vec<String>,
     edges: Vec<(String, String)>,
-    ids_to_properties: IndexMap<String, IndexMap<String, String>>,
-) -> Graph<(String, IndexMap<String, String>), ()> {
+    ids_to_properties: IndexMap<String, IndexMap<String, P>>,
+) -> PropertyGraph<P> {
+    let edges = edges
+        .into_iter()
+        .map(|edge| (edge, IndexMap::new()))
+        .collect();
+    generate_target_graph_with_edge_properties(vertices, edges, ids_to_properties)
+}
+
+/* Like generate_target_graph, but every edge also has the properties (or predicates) the
+ * matching call in the trace must have, e.g. MATCH n -[latency > 50]-> m.
+ */
+pub fn generate_target_graph_with_edge_properties<P: Clone>(
+    vertices: Vec<String>,
+    edges: Vec<((String, String), IndexMap<String, P>)>,
+    ids_to_properties: IndexMap<String, IndexMap<String, P>>,
+) -> PropertyGraph<P> {
     let mut graph = Graph::new();
 
     // In order to make edges, we have to know the handles of the nodes, and you
@@ -43,21 +96,104 @@ pub fn generate_target_graph(
     }
 
     // Make edges with handles instead of the vertex names
-    let mut edge_handles = Vec::new();
-    for edge in edges {
+    for (edge, properties) in edges {
         let node0 = nodes_to_node_handles[&edge.0];
         let node1 = nodes_to_node_handles[&edge.1];
-        let new_edge = (node0, node1);
-        edge_handles.push(new_edge);
+        graph.add_edge(node0, node1, properties);
     }
-    graph.extend_with_edges(edge_handles);
     graph
 }
 
-pub fn get_node_with_id(
-    graph: &Graph<(String, IndexMap<String, String>), ()>,
-    node_name: String,
-) -> Option<NodeIndex> {
+/* Like generate_target_graph, but every edge is marked as direct or transitive, e.g.
+ * MATCH n -> m, m ->* o makes (n, m) direct and (m, o) transitive.
+ */
+pub fn generate_target_graph_with_edge_kinds<P: Clone + From<String>>(
+    vertices: Vec<String>,
+    edges: Vec<((String, String), EdgeKind)>,
+    ids_to_properties: IndexMap<String, IndexMap<String, P>>,
+) -> PropertyGraph<P> {
+    let edges = edges
+        .into_iter()
+        .map(|(edge, kind)| {
+            let mut properties = IndexMap::new();
+            if kind == EdgeKind::Transitive {
+                properties.insert(EDGE_KIND.to_string(), P::from(kind.as_str().to_string()));
+            }
+            (edge, properties)
+        })
+        .collect();
+    generate_target_graph_with_edge_properties(vertices, edges, ids_to_properties)
+}
+
+pub fn has_transitive_edges<P: PropertyMatcher>(graph: &PropertyGraph<P>) -> bool {
+    graph
+        .edge_weights()
+        .any(|edge| EdgeKind::of(edge) == EdgeKind::Transitive)
+}
+
+// reachable[a][b] is whether b can be reached from a over one or more edges
+pub fn reachability<N, E>(graph: &Graph<N, E>) -> Vec<Vec<bool>> {
+    let mut reachable = vec![vec![false; graph.node_count()]; graph.node_count()];
+    for start in graph.node_indices() {
+        let row = &mut reachable[start.index()];
+        let mut stack: Vec<NodeIndex> = graph.neighbors_directed(start, Outgoing).collect();
+        while let Some(node) = stack.pop() {
+            if !row[node.index()] {
+                row[node.index()] = true;
+                stack.extend(graph.neighbors_directed(node, Outgoing));
+            }
+        }
+    }
+    reachable
+}
+
+/* Whether the trace has what the edge of the target graph asks for between the trace nodes
+ * source and target: a call with matching properties for a direct edge, a path for a
+ * transitive one.  Paths are looked up in `reachable` if it was computed beforehand.
+ */
+pub fn edge_satisfied<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    edge_h: &IndexMap<String, P>,
+    source: NodeIndex,
+    target: NodeIndex,
+    reachable: Option<&[Vec<bool>]>,
+) -> bool {
+    match EdgeKind::of(edge_h) {
+        EdgeKind::Direct => graph_g
+            .edges_connecting(source, target)
+            .any(|edge_g| edge_properties_match(edge_g.weight(), edge_h)),
+        EdgeKind::Transitive => match reachable {
+            Some(reachable) => reachable[source.index()][target.index()],
+            None => source != target && has_path_connecting(graph_g, source, target, None),
+        },
+    }
+}
+
+// Like has_property_subset, but for edges, whose EDGE_KIND is not a property of the call
+pub fn edge_properties_match<P: PropertyMatcher>(
+    trace_edge: &IndexMap<String, String>,
+    target_edge: &IndexMap<String, P>,
+) -> bool {
+    target_edge
+        .iter()
+        .filter(|(property, _)| property.as_str() != EDGE_KIND)
+        .all(|(property, matcher)| {
+            matcher.matches(trace_edge.get(property).map(|value| value.as_str()))
+        })
+}
+
+/* A node of a trace graph is a span:  its name is a span id that is unique within the trace,
+ * and the service it ran in is its SERVICE_NAME property.  A trace that calls the same service
+ * more than once (retries, or one service called from two others) has a node for every call.
+ */
+pub const SERVICE_NAME: &str = "node.metadata.WORKLOAD_NAME";
+
+// The span id of the sequence-th span a service has started
+pub fn span_id(service_name: &str, sequence: u64) -> String {
+    format!("{0}#{1}", service_name, sequence)
+}
+
+pub fn get_node_with_id<P>(graph: &PropertyGraph<P>, node_name: String) -> Option<NodeIndex> {
     for index in graph.node_indices() {
         if graph.node_weight(index).unwrap().0 == node_name {
             return Some(index);
@@ -66,10 +202,25 @@ pub fn get_node_with_id(
     None
 }
 
-pub fn find_leaves(
-    node: NodeIndex,
-    graph: &Graph<(String, IndexMap<String, String>), ()>,
-) -> Vec<NodeIndex> {
+pub fn service_name(graph: &TraceGraph, node: NodeIndex) -> Option<&str> {
+    graph[node].1.get(SERVICE_NAME).map(|name| name.as_str())
+}
+
+// Every span of a service, in the order they were added
+pub fn get_nodes_with_service(graph: &TraceGraph, name: &str) -> Vec<NodeIndex> {
+    graph
+        .node_indices()
+        .filter(|node| service_name(graph, *node) == Some(name))
+        .collect()
+}
+
+// The span with this id, or else the first span of the service with this name
+pub fn get_node_with_id_or_service(graph: &TraceGraph, id_or_name: &str) -> Option<NodeIndex> {
+    get_node_with_id(graph, id_or_name.to_string())
+        .or_else(|| get_nodes_with_service(graph, id_or_name).first().copied())
+}
+
+pub fn find_leaves<N, E>(node: NodeIndex, graph: &Graph<N, E>) -> Vec<NodeIndex> {
     let mut post_order = DfsPostOrder::new(&graph, node);
     let mut to_return = Vec::new();
     while let Some(visited) = post_order.next(&graph) {
@@ -80,35 +231,133 @@ pub fn find_leaves(
     to_return
 }
 
-pub fn find_root(graph: &Graph<(String, IndexMap<String, String>), ()>) -> NodeIndex {
-    for node in graph.node_indices() {
-        if graph.neighbors_directed(node, Incoming).count() == 0 {
-            return node;
+// Why a graph does not have the shape an algorithm needs
+#[derive(Clone, Debug, PartialEq)]
+pub enum GraphError {
+    Empty,
+    Cycle,
+    MultipleRoots(Vec<NodeIndex>),
+    SharedDescendant(NodeIndex), // a node with more than one parent
+    TransitiveEdges,             // for algorithms that only match direct edges
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphError::Empty => write!(f, "the graph has no nodes"),
+            GraphError::Cycle => write!(f, "the graph has a cycle"),
+            GraphError::MultipleRoots(roots) => {
+                write!(f, "the graph has {0} roots: {1:?}", roots.len(), roots)
+            }
+            GraphError::SharedDescendant(node) => {
+                write!(f, "node {0:?} has more than one parent", node)
+            }
+            GraphError::TransitiveEdges => write!(f, "the graph has transitive edges"),
         }
     }
-    panic!("no root found");
 }
 
-pub fn has_property_subset(
+impl std::error::Error for GraphError {}
+
+/* Traces are usually trees, but async follow-from links make DAGs (a node with several
+ * parents), merging partial traces makes forests, and broken instrumentation can make cycles.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphShape {
+    Tree,
+    Forest, // several trees, or none at all
+    Dag,
+    Cyclic,
+}
+
+pub fn graph_shape<N, E>(graph: &Graph<N, E>) -> GraphShape {
+    if is_cyclic_directed(graph) {
+        return GraphShape::Cyclic;
+    }
+    // without directed cycles, at most one parent per node means there are no cycles at all
+    if graph
+        .node_indices()
+        .any(|node| graph.neighbors_directed(node, Incoming).count() > 1)
+    {
+        return GraphShape::Dag;
+    }
+    if find_roots(graph).len() == 1 {
+        GraphShape::Tree
+    } else {
+        GraphShape::Forest
+    }
+}
+
+// The roots of all trees in a forest, as needed by algorithms that work on trees
+pub fn forest_roots<N, E>(graph: &Graph<N, E>) -> Result<Vec<NodeIndex>, GraphError> {
+    match graph_shape(graph) {
+        GraphShape::Cyclic => Err(GraphError::Cycle),
+        GraphShape::Dag => Err(GraphError::SharedDescendant(
+            graph
+                .node_indices()
+                .find(|node| graph.neighbors_directed(*node, Incoming).count() > 1)
+                .unwrap(),
+        )),
+        GraphShape::Tree | GraphShape::Forest => Ok(find_roots(graph)),
+    }
+}
+
+// All nodes without parents; a component that is a cycle has none
+pub fn find_roots<N, E>(graph: &Graph<N, E>) -> Vec<NodeIndex> {
+    graph
+        .node_indices()
+        .filter(|node| graph.neighbors_directed(*node, Incoming).count() == 0)
+        .collect()
+}
+
+pub fn find_root<N, E>(graph: &Graph<N, E>) -> Result<NodeIndex, GraphError> {
+    if graph.node_count() == 0 {
+        return Err(GraphError::Empty);
+    }
+    let mut roots = find_roots(graph);
+    match roots.len() {
+        0 => Err(GraphError::Cycle),
+        1 => Ok(roots.remove(0)),
+        _ => Err(GraphError::MultipleRoots(roots)),
+    }
+}
+
+pub fn has_property_subset<P: PropertyMatcher>(
     property_set_1: &IndexMap<String, String>, // set
-    property_set_2: &IndexMap<String, String>, // subset
+    property_set_2: &IndexMap<String, P>,      // subset, or predicates on the set
 ) -> bool {
-    for property in property_set_2.keys() {
-        if !property_set_1.contains_key(property) {
-            return false;
-        }
-        if property_set_1[property] != property_set_2[property] {
+    for (property, matcher) in property_set_2 {
+        if !matcher.matches(property_set_1.get(property).map(|value| value.as_str())) {
             return false;
         }
     }
     true
 }
 
+// A trace graph with one node per name and the given edges between the indices of the names,
+// for the tests of the matchers
+#[cfg(test)]
+pub(crate) fn graph_from_edges<S: ToString>(names: &[S], edges: &[(usize, usize)]) -> TraceGraph {
+    let mut graph = TraceGraph::new();
+    for name in names {
+        graph.add_node((name.to_string(), IndexMap::new()));
+    }
+    for (source, target) in edges {
+        graph.add_edge(
+            NodeIndex::new(*source),
+            NodeIndex::new(*target),
+            IndexMap::new(),
+        );
+    }
+    graph
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::graph::predicate::Predicate;
 
-    fn make_small_target_graph() -> Graph<(String, IndexMap<String, String>), ()> {
+    fn make_small_target_graph() -> TraceGraph {
         let a = String::from("a");
         let b = String::from("b");
         let c = String::from("c");
@@ -138,19 +387,19 @@ mod tests {
         let graph = generate_target_graph(vertices, edges, ids_to_properties);
         graph
     }
-    fn little_branching_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn little_branching_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         graph.extend_with_edges(&[(0, 1), (0, 2), (0, 3), (1, 4), (3, 5)]);
         return graph;
     }
 
-    fn little_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn little_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a = graph.add_node(("a".to_string(), IndexMap::new()));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
         let c = graph.add_node(("c".to_string(), IndexMap::new()));
-        graph.add_edge(a, b, ());
-        graph.add_edge(b, c, ());
+        graph.add_edge(a, b, IndexMap::new());
+        graph.add_edge(b, c, IndexMap::new());
         graph
     }
 
@@ -168,6 +417,31 @@ mod tests {
         assert!(ret.is_some());
     }
 
+    #[test]
+    fn test_get_nodes_with_service() {
+        // reviews calls ratings twice, e.g. because the first call is retried
+        let mut graph = TraceGraph::new();
+        let mut add_span = |service: &str, sequence: u64| {
+            let mut properties = IndexMap::new();
+            properties.insert(SERVICE_NAME.to_string(), service.to_string());
+            graph.add_node((span_id(service, sequence), properties))
+        };
+        let reviews = add_span("reviews-v1", 0);
+        let first_call = add_span("ratings-v1", 0);
+        let retry = add_span("ratings-v1", 1);
+        graph.add_edge(reviews, first_call, IndexMap::new());
+        graph.add_edge(reviews, retry, IndexMap::new());
+
+        assert!(graph.node_count() == 3 && graph_shape(&graph) == GraphShape::Tree);
+        assert!(get_nodes_with_service(&graph, "ratings-v1") == vec![first_call, retry]);
+        assert!(get_nodes_with_service(&graph, "details-v1").is_empty());
+        assert!(service_name(&graph, retry) == Some("ratings-v1"));
+        assert!(get_node_with_id(&graph, "ratings-v1#1".to_string()) == Some(retry));
+        assert!(get_node_with_id_or_service(&graph, "ratings-v1#1") == Some(retry));
+        assert!(get_node_with_id_or_service(&graph, "ratings-v1") == Some(first_call));
+        assert!(get_node_with_id_or_service(&graph, "details-v1").is_none());
+    }
+
     #[test]
     fn test_find_leaves() {
         let graph = little_branching_graph();
@@ -178,14 +452,98 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_has_property_subset() {
+        let mut properties = IndexMap::new();
+        properties.insert("service_name".to_string(), "reviews-v1".to_string());
+        properties.insert("latency".to_string(), "70".to_string());
+
+        let mut exact = IndexMap::new();
+        exact.insert("service_name".to_string(), "reviews-v1".to_string());
+        assert!(has_property_subset(&properties, &exact));
+        exact.insert("height".to_string(), "2".to_string());
+        assert!(!has_property_subset(&properties, &exact));
+
+        let mut predicates = IndexMap::new();
+        predicates.insert(
+            "service_name".to_string(),
+            Predicate::regex("^reviews-v[12]$").unwrap(),
+        );
+        predicates.insert("latency".to_string(), Predicate::GreaterThan(50.0));
+        assert!(has_property_subset(&properties, &predicates));
+        predicates.insert("latency".to_string(), Predicate::InRange(0.0, 50.0));
+        assert!(!has_property_subset(&properties, &predicates));
+    }
+
     #[test]
     fn test_find_root() {
         let graph = little_graph();
-        let root = find_root(&graph);
+        let root = find_root(&graph).unwrap();
         assert!(
             graph.node_weight(root).unwrap().0 == "a",
             "root label is {:?}",
             graph.node_weight(root).unwrap()
         );
     }
+
+    #[test]
+    fn test_graph_shapes() {
+        let mut graph = little_graph();
+        assert!(graph_shape(&graph) == GraphShape::Tree);
+
+        // a second tree makes a forest
+        let d = graph.add_node(("d".to_string(), IndexMap::new()));
+        let e = graph.add_node(("e".to_string(), IndexMap::new()));
+        graph.add_edge(d, e, IndexMap::new());
+        assert!(graph_shape(&graph) == GraphShape::Forest);
+        assert!(forest_roots(&graph).unwrap().len() == 2);
+        match find_root(&graph) {
+            Err(GraphError::MultipleRoots(roots)) => assert!(roots.len() == 2),
+            other => panic!("expected several roots, got {:?}", other),
+        }
+
+        // e is also called by the root of the first tree
+        graph.add_edge(NodeIndex::new(0), e, IndexMap::new());
+        assert!(graph_shape(&graph) == GraphShape::Dag);
+        assert!(forest_roots(&graph) == Err(GraphError::SharedDescendant(e)));
+
+        graph.add_edge(e, d, IndexMap::new());
+        assert!(graph_shape(&graph) == GraphShape::Cyclic);
+        assert!(forest_roots(&graph) == Err(GraphError::Cycle));
+
+        let mut cycle = TraceGraph::new();
+        let a = cycle.add_node(("a".to_string(), IndexMap::new()));
+        let b = cycle.add_node(("b".to_string(), IndexMap::new()));
+        cycle.add_edge(a, b, IndexMap::new());
+        cycle.add_edge(b, a, IndexMap::new());
+        assert!(find_root(&cycle) == Err(GraphError::Cycle));
+        assert!(find_root(&TraceGraph::new()) == Err(GraphError::Empty));
+    }
+
+    #[test]
+    fn test_edge_kinds() {
+        let vertices = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let edges = vec![
+            (("a".to_string(), "b".to_string()), EdgeKind::Direct),
+            (("a".to_string(), "c".to_string()), EdgeKind::Transitive),
+        ];
+        let graph: TraceGraph =
+            generate_target_graph_with_edge_kinds(vertices, edges, IndexMap::new());
+        let kinds: Vec<EdgeKind> = graph.edge_weights().map(EdgeKind::of).collect();
+        assert!(kinds == vec![EdgeKind::Direct, EdgeKind::Transitive]);
+        assert!(has_transitive_edges(&graph));
+        assert!(!has_transitive_edges(&little_graph()));
+
+        // a -> b -> c, so a reaches c only transitively
+        let trace = little_graph();
+        let reachable = reachability(&trace);
+        assert!(reachable[0][2] && reachable[0][1] && !reachable[2][0] && !reachable[0][0]);
+        let (a, c) = (NodeIndex::new(0), NodeIndex::new(2));
+        let transitive = &graph[graph.find_edge(a, c).unwrap()];
+        assert!(edge_satisfied(&trace, transitive, a, c, Some(&reachable)));
+        assert!(edge_satisfied(&trace, transitive, a, c, None));
+        assert!(!edge_satisfied(&trace, transitive, c, a, None));
+        let direct = &graph[graph.find_edge(a, NodeIndex::new(1)).unwrap()];
+        assert!(!edge_satisfied(&trace, direct, a, c, None));
+    }
 }
@@ -3,13 +3,18 @@
 /// Another thing to consider, but is not implemented here, is
 /// http://chasewoerner.org/popl87.pdf
 ///
-use super::graph_utils::{find_leaves, find_root, has_property_subset};
+use super::graph_utils::{
+    edge_properties_match, find_leaves, find_root, forest_roots, has_property_subset,
+    has_transitive_edges, GraphError, PropertyGraph, TraceGraph,
+};
+use super::predicate::PropertyMatcher;
 use indexmap::map::IndexMap;
 use pathfinding::directed::edmonds_karp::*;
-use petgraph::graph::{Graph, NodeIndex};
+use petgraph::graph::NodeIndex;
 use petgraph::visit::DfsPostOrder;
 use petgraph::{Incoming, Outgoing};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Debug;
 extern crate test;
 
 type SetSType = IndexMap<SetSKey, IndexMap<NodeIndex, Option<Vec<(NodeIndex, NodeIndex)>>>>;
@@ -62,13 +67,15 @@ impl<'de> Deserialize<'de> for SetSKey {
 /// matchings later in the algorithm.  So if cost is below that threshold,
 /// that is not necessarily reflective of the true maximum flow, but rather a
 /// way of signaling that neither this nor subsequent matchings will be useful.
-fn max_matching<EK: EdmondsKarp<i32>>(
+#[allow(clippy::too_many_arguments)]
+fn max_matching<EK: EdmondsKarp<i32>, P: PropertyMatcher>(
     set_x: &[NodeIndex],
     set_y: &[NodeIndex],
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
     set_s: &SetSType,
     u_null: NodeIndex,
+    v_center: NodeIndex, // the node of graph_g whose neighbors are set_y
     target_size: usize,
 ) -> (usize, Option<Vec<(NodeIndex, NodeIndex)>>) {
     let mut vertices = Vec::new();
@@ -112,6 +119,7 @@ fn max_matching<EK: EdmondsKarp<i32>>(
                     &graph_g.node_weight(*v).unwrap().1,
                     &graph_h.node_weight(*u).unwrap().1,
                 )
+                && edge_matches(graph_g, graph_h, (v_center, *v), (u_null, *u))
             {
                 // 2. add edge between v and u
                 edges.push(((u.index() + graph_g.node_count(), v.index()), 1));
@@ -145,11 +153,32 @@ fn max_matching<EK: EdmondsKarp<i32>>(
     (costs as usize, Some(matching))
 }
 
+// Whether the edge between the two nodes of graph_h (in either direction) has a counterpart in
+// the same direction between the two nodes of graph_g, with matching properties
+fn edge_matches<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+    nodes_g: (NodeIndex, NodeIndex),
+    nodes_h: (NodeIndex, NodeIndex),
+) -> bool {
+    let (edge_h, edge_g) = match graph_h.find_edge(nodes_h.0, nodes_h.1) {
+        Some(edge_h) => (edge_h, graph_g.find_edge(nodes_g.0, nodes_g.1)),
+        None => match graph_h.find_edge(nodes_h.1, nodes_h.0) {
+            Some(edge_h) => (edge_h, graph_g.find_edge(nodes_g.1, nodes_g.0)),
+            None => return true,
+        },
+    };
+    match edge_g {
+        Some(edge_g) => edge_properties_match(&graph_g[edge_g], &graph_h[edge_h]),
+        None => false,
+    }
+}
+
 // For debugging only
 #[allow(dead_code)]
-fn print_set_s(
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+fn print_set_s<P: PropertyMatcher + Debug>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
     set_s: &SetSType,
 ) {
     for key in set_s.keys() {
@@ -180,32 +209,35 @@ fn print_set_s(
 // going to look up what None in graph H maps to, and duplicate matchings
 // between the same nodes are clearly not "wrong".  But because of this,
 // the size of the matching returned might be a bit wonky.
-fn get_mapping_from_set_s(
-    _graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+fn get_mapping_from_set_s<P: PropertyMatcher>(
+    _graph_g: &TraceGraph,
+    _graph_h: &PropertyGraph<P>,
     set_s: &SetSType,
+    root_h: NodeIndex,
     root_in_g: &NodeIndex,
 ) -> Vec<(NodeIndex, NodeIndex)> {
-    let root_h = find_root(graph_h);
     let mut to_return = Vec::new();
-    let mut set_to_find_mapping = vec![(root_h, *root_in_g)];
-    while !set_to_find_mapping.is_empty() {
-        let key = set_to_find_mapping.pop().unwrap();
-        if !to_return.contains(&key) {
-            to_return.push(key);
+    // (node of graph_h, node of graph_g, the key of the matching of its children in set S)
+    // The matchings in set S are keyed by the neighbor of the node of graph_h that was left
+    // out:  the root is matched with all of its neighbors, so its key is itself, and any other
+    // node with all neighbors but its parent, so its key is its parent.  Looking a node up by
+    // itself instead stops the mapping below the children of the root.  The pairs in set S
+    // are already (node of graph_h, node of graph_g).
+    let mut set_to_find_mapping = vec![(root_h, *root_in_g, root_h)];
+    while let Some((node_h, node_g, matching_key)) = set_to_find_mapping.pop() {
+        if !to_return.contains(&(node_h, node_g)) {
+            to_return.push((node_h, node_g));
         }
 
         let set_s_key = SetSKey {
-            val1: key.1,
-            val2: key.0,
+            val1: node_g,
+            val2: node_h,
         };
-        if set_s[&set_s_key].contains_key(&key.0) {
-            if let Some(mapping_vec) = &set_s[&set_s_key][&key.0] {
-                for mapping in mapping_vec {
-                    if !to_return.contains(&(mapping.1, mapping.0)) {
-                        to_return.push((mapping.1, mapping.0));
-                        set_to_find_mapping.push(*mapping);
-                    }
+        if let Some(Some(mapping_vec)) = set_s[&set_s_key].get(&matching_key) {
+            for mapping in mapping_vec {
+                if !to_return.contains(mapping) {
+                    to_return.push(*mapping);
+                    set_to_find_mapping.push((mapping.0, mapping.1, node_h));
                 }
             }
         }
@@ -213,13 +245,13 @@ fn get_mapping_from_set_s(
     to_return
 }
 
-fn find_mapping_shamir_inner_loop(
+fn find_mapping_shamir_inner_loop<P: PropertyMatcher>(
     v: NodeIndex,
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+    root_h: NodeIndex,
     set_s: &mut SetSType,
 ) -> (bool, Option<NodeIndex>) {
-    let root_h = find_root(&graph_h);
     let v_neighbors: Vec<NodeIndex> = graph_g.neighbors_undirected(v).collect();
     for u in graph_h.node_indices() {
         let u_neighbors: Vec<NodeIndex> = graph_h.neighbors_undirected(u).collect();
@@ -229,13 +261,14 @@ fn find_mapping_shamir_inner_loop(
         }
 
         // maximum matching where X0 = X
-        let (cost, p) = max_matching::<DenseCapacity<_>>(
+        let (cost, p) = max_matching::<DenseCapacity<_>, P>(
             &u_neighbors,
             &v_neighbors,
             graph_g,
             graph_h,
             set_s,
             u,
+            v,
             u_neighbors.len(),
         );
         if let Some(path) = p {
@@ -257,13 +290,14 @@ fn find_mapping_shamir_inner_loop(
             for vertex in 0..u_neighbors.len() {
                 let mut new_x_set = u_neighbors.clone();
                 let vertex_id = new_x_set.remove(vertex);
-                let (_cost, p) = max_matching::<DenseCapacity<_>>(
+                let (_cost, p) = max_matching::<DenseCapacity<_>, P>(
                     &new_x_set,
                     &v_neighbors,
                     graph_g,
                     graph_h,
                     set_s,
                     u,
+                    v,
                     new_x_set.len(),
                 );
                 if let Some(path) = p {
@@ -314,9 +348,11 @@ fn find_mapping_shamir_inner_loop(
 // ----------------- Shamir Isomorphism Algorithm Centralized ----------------
 
 // this performs lines 0-4 in the Shamir paper figure 3
-fn initialize_s(
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+fn initialize_s<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+    roots_g: &[NodeIndex],
+    root_h: NodeIndex,
 ) -> SetSType {
     let mut s =
         IndexMap::<SetSKey, IndexMap<NodeIndex, Option<Vec<(NodeIndex, NodeIndex)>>>>::new();
@@ -332,9 +368,10 @@ fn initialize_s(
             );
         }
     }
-    let root_g = find_root(&graph_g);
-    let root_h = find_root(&graph_h);
-    for leaf_g in find_leaves(root_g, &graph_g) {
+    let leaves_g = roots_g
+        .iter()
+        .flat_map(|root_g| find_leaves(*root_g, &graph_g));
+    for leaf_g in leaves_g {
         for leaf_h in find_leaves(root_h, &graph_h) {
             s.get_mut(&SetSKey {
                 val1: leaf_g,
@@ -355,44 +392,62 @@ fn initialize_s(
     s
 }
 
-pub fn find_mapping_shamir_centralized(
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
-) -> Option<Vec<(NodeIndex, NodeIndex)>> {
+// The root of graph_h, which has to be a tree of direct edges
+fn target_root<P: PropertyMatcher>(graph_h: &PropertyGraph<P>) -> Result<NodeIndex, GraphError> {
+    forest_roots(graph_h)?;
+    if has_transitive_edges(graph_h) {
+        return Err(GraphError::TransitiveEdges);
+    }
+    find_root(graph_h)
+}
+
+/* Finds graph_h in graph_g, if graph_h is a tree and graph_g is a forest;  each tree of
+ * graph_g is searched in turn.  Other shapes, e.g. DAGs with shared descendants, and target
+ * graphs with transitive edges are errors;  the vf2 matcher in matcher.rs handles those.
+ */
+pub fn find_mapping_shamir_centralized<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+) -> Result<Option<Vec<(NodeIndex, NodeIndex)>>, GraphError> {
+    let root_h = target_root(graph_h)?;
+    let roots_g = forest_roots(graph_g)?;
     // TODO:  before even dealing with isomorphism, ask if breadth,
     // height, num nodes match up
     if graph_g.node_count() < graph_h.node_count() {
-        return None;
+        return Ok(None);
     }
 
     // initialize S with all N(u) sets, lines 1-4
-    let mut set_s = initialize_s(graph_g, graph_h);
-    let root_g = find_root(graph_g);
+    let mut set_s = initialize_s(graph_g, graph_h, &roots_g, root_h);
 
     // postorder traversal and filtering of children for degrees, lines 5-8;
-    let mut post_order = DfsPostOrder::new(graph_g, root_g);
-    while let Some(node) = post_order.next(graph_g) {
-        let (mapping_found, mapping_root) =
-            find_mapping_shamir_inner_loop(node, graph_g, graph_h, &mut set_s);
-        if mapping_found {
-            return Some(get_mapping_from_set_s(
-                graph_g,
-                graph_h,
-                &set_s,
-                &mapping_root.unwrap(),
-            ));
+    for root_g in roots_g {
+        let mut post_order = DfsPostOrder::new(graph_g, root_g);
+        while let Some(node) = post_order.next(graph_g) {
+            let (mapping_found, mapping_root) =
+                find_mapping_shamir_inner_loop(node, graph_g, graph_h, root_h, &mut set_s);
+            if mapping_found {
+                return Ok(Some(get_mapping_from_set_s(
+                    graph_g,
+                    graph_h,
+                    &set_s,
+                    root_h,
+                    &mapping_root.unwrap(),
+                )));
+            }
         }
     }
     // line 15
-    None
+    Ok(None)
 }
 
 // ---------------- Shamir Isomorphism Algorithm Decentralized ---------------
-fn initialize_s_for_node(
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+fn initialize_s_for_node<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
     set_s: &mut SetSType,
     node: NodeIndex,
+    root_h: NodeIndex,
 ) {
     for u in graph_h.node_indices() {
         // initialize S entry as empty set
@@ -404,8 +459,6 @@ fn initialize_s_for_node(
             IndexMap::new(),
         );
     }
-    let root_h = find_root(&graph_h);
-
     // if I am a leaf
     if graph_g.neighbors_directed(node, Outgoing).count() == 0 {
         for leaf_h in find_leaves(root_h, &graph_h) {
@@ -429,21 +482,23 @@ fn initialize_s_for_node(
     }
 }
 
-pub fn find_mapping_shamir_decentralized(
-    graph_g: &Graph<(String, IndexMap<String, String>), ()>,
-    graph_h: &Graph<(String, IndexMap<String, String>), ()>,
+pub fn find_mapping_shamir_decentralized<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
     set_s: &mut SetSType,
     cur_node: NodeIndex, // what node we are in graph_g
     am_root: bool,
-) -> Option<Vec<(NodeIndex, NodeIndex)>> {
+) -> Result<Option<Vec<(NodeIndex, NodeIndex)>>, GraphError> {
+    let root_h = target_root(graph_h)?;
+    forest_roots(graph_g)?;
     // 1. Add yourself (that is, all your entries) to set S
-    initialize_s_for_node(graph_g, graph_h, set_s, cur_node);
+    initialize_s_for_node(graph_g, graph_h, set_s, cur_node, root_h);
 
     // 2. For all your children, run inner loop
     let mut mapping_root_for_children = None;
     for child in graph_g.neighbors_directed(cur_node, Outgoing) {
         let (mapping_found, mapping_root) =
-            find_mapping_shamir_inner_loop(child, graph_g, graph_h, set_s);
+            find_mapping_shamir_inner_loop(child, graph_g, graph_h, root_h, set_s);
         if !am_root && mapping_found {
             mapping_root_for_children = mapping_root;
         }
@@ -464,64 +519,70 @@ pub fn find_mapping_shamir_decentralized(
 
     // 2a. If one of your children matched all of graph_h, return that matching
     if let Some(mrc) = mapping_root_for_children {
-        return Some(get_mapping_from_set_s(graph_g, graph_h, &set_s, &mrc));
+        return Ok(Some(get_mapping_from_set_s(
+            graph_g, graph_h, &set_s, root_h, &mrc,
+        )));
     }
 
     // 3. If you are the root, run the inner loop for yourself as well
     if am_root {
         let (mapping_found, mapping_root) =
-            find_mapping_shamir_inner_loop(cur_node, graph_g, graph_h, set_s);
+            find_mapping_shamir_inner_loop(cur_node, graph_g, graph_h, root_h, set_s);
         if mapping_found {
-            return Some(get_mapping_from_set_s(
+            return Ok(Some(get_mapping_from_set_s(
                 graph_g,
                 graph_h,
                 &set_s,
+                root_h,
                 &mapping_root.unwrap(),
-            ));
+            )));
         }
     }
-    None
+    Ok(None)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::graph::graph_utils::get_node_with_id;
+    use crate::graph::graph_utils::{
+        generate_target_graph, generate_target_graph_with_edge_properties, get_node_with_id,
+    };
+    use crate::graph::predicate::Predicate;
     use serde_json;
     use test::Bencher;
 
     /// --------------- Graph Creation Helper functions -------------------
-    fn three_node_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn three_node_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a = graph.add_node(("a".to_string(), IndexMap::new()));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
         let c = graph.add_node(("c".to_string(), IndexMap::new()));
-        graph.add_edge(a, b, ());
-        graph.add_edge(a, c, ());
+        graph.add_edge(a, b, IndexMap::new());
+        graph.add_edge(a, c, IndexMap::new());
         return graph;
     }
 
-    fn three_node_chain_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn three_node_chain_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a = graph.add_node(("a".to_string(), IndexMap::new()));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
         let c = graph.add_node(("c".to_string(), IndexMap::new()));
-        graph.add_edge(a, b, ());
-        graph.add_edge(b, c, ());
+        graph.add_edge(a, b, IndexMap::new());
+        graph.add_edge(b, c, IndexMap::new());
         return graph;
     }
 
-    fn two_node_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn two_node_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a = graph.add_node(("a".to_string(), IndexMap::new()));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
 
-        graph.add_edge(a, b, ());
+        graph.add_edge(a, b, IndexMap::new());
         return graph;
     }
 
-    fn three_node_graph_with_properties() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn three_node_graph_with_properties() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a_hashmap: IndexMap<String, String> = [
             ("height".to_string(), "100".to_string()),
             ("breadth".to_string(), "5".to_string()),
@@ -532,13 +593,13 @@ mod tests {
         let a = graph.add_node(("a".to_string(), a_hashmap));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
         let c = graph.add_node(("c".to_string(), IndexMap::new()));
-        graph.add_edge(a, b, ());
-        graph.add_edge(a, c, ());
+        graph.add_edge(a, b, IndexMap::new());
+        graph.add_edge(a, c, IndexMap::new());
         return graph;
     }
 
-    fn two_node_graph_with_properties() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn two_node_graph_with_properties() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a_hashmap: IndexMap<String, String> = [("height".to_string(), "100".to_string())]
             .iter()
             .cloned()
@@ -546,12 +607,12 @@ mod tests {
         let a = graph.add_node(("a".to_string(), a_hashmap));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
 
-        graph.add_edge(a, b, ());
+        graph.add_edge(a, b, IndexMap::new());
         return graph;
     }
 
-    fn two_node_graph_with_wrong_properties() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn two_node_graph_with_wrong_properties() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a_hashmap: IndexMap<String, String> = [("height".to_string(), "1".to_string())]
             .iter()
             .cloned()
@@ -559,26 +620,26 @@ mod tests {
         let a = graph.add_node(("a".to_string(), a_hashmap));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
 
-        graph.add_edge(a, b, ());
+        graph.add_edge(a, b, IndexMap::new());
         return graph;
     }
 
-    fn chain_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn chain_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let a = graph.add_node(("a".to_string(), IndexMap::new()));
         let b = graph.add_node(("b".to_string(), IndexMap::new()));
         let c = graph.add_node(("c".to_string(), IndexMap::new()));
         let star = graph.add_node(("*".to_string(), IndexMap::new()));
 
-        graph.add_edge(a, b, ());
-        graph.add_edge(b, c, ());
-        graph.add_edge(c, star, ());
+        graph.add_edge(a, b, IndexMap::new());
+        graph.add_edge(b, c, IndexMap::new());
+        graph.add_edge(c, star, IndexMap::new());
         return graph;
     }
 
     // from figure 2 in shamir paper
-    fn g_figure_2() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn g_figure_2() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let r = graph.add_node((String::from("r"), IndexMap::new()));
         let v = graph.add_node((String::from("v"), IndexMap::new()));
         let v1 = graph.add_node((String::from("v1"), IndexMap::new()));
@@ -588,19 +649,19 @@ mod tests {
         let left_unnamed_child = graph.add_node((String::from("leftchild"), IndexMap::new()));
         let right_unnamed_child = graph.add_node((String::from("rightchild"), IndexMap::new()));
 
-        graph.add_edge(r, v, ());
-        graph.add_edge(v, v1, ());
-        graph.add_edge(v, v2, ());
-        graph.add_edge(v, v3, ());
-        graph.add_edge(v1, left_unnamed_child, ());
-        graph.add_edge(v1, right_unnamed_child, ());
+        graph.add_edge(r, v, IndexMap::new());
+        graph.add_edge(v, v1, IndexMap::new());
+        graph.add_edge(v, v2, IndexMap::new());
+        graph.add_edge(v, v3, IndexMap::new());
+        graph.add_edge(v1, left_unnamed_child, IndexMap::new());
+        graph.add_edge(v1, right_unnamed_child, IndexMap::new());
 
         return graph;
     }
 
     // from figure 2 in shamir paper
-    fn h_figure_2() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn h_figure_2() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let u = graph.add_node((String::from("u"), IndexMap::new()));
         let u1 = graph.add_node((String::from("u1"), IndexMap::new()));
         let u2 = graph.add_node((String::from("u2"), IndexMap::new()));
@@ -609,62 +670,62 @@ mod tests {
         let u1_right_child = graph.add_node((String::from("u1right"), IndexMap::new()));
         let u3_child = graph.add_node((String::from("u3child"), IndexMap::new()));
 
-        graph.add_edge(u, u1, ());
-        graph.add_edge(u, u2, ());
-        graph.add_edge(u, u3, ());
-        graph.add_edge(u1, u1_left_child, ());
-        graph.add_edge(u1, u1_right_child, ());
-        graph.add_edge(u3, u3_child, ());
+        graph.add_edge(u, u1, IndexMap::new());
+        graph.add_edge(u, u2, IndexMap::new());
+        graph.add_edge(u, u3, IndexMap::new());
+        graph.add_edge(u1, u1_left_child, IndexMap::new());
+        graph.add_edge(u1, u1_right_child, IndexMap::new());
+        graph.add_edge(u3, u3_child, IndexMap::new());
 
         return graph;
     }
 
-    fn three_child_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn three_child_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let root = graph.add_node((String::from("root"), IndexMap::new()));
         let child1 = graph.add_node((String::from("child1"), IndexMap::new()));
         let child2 = graph.add_node((String::from("child2"), IndexMap::new()));
         let child3 = graph.add_node((String::from("child3"), IndexMap::new()));
 
-        graph.add_edge(root, child1, ());
-        graph.add_edge(root, child2, ());
-        graph.add_edge(root, child3, ());
+        graph.add_edge(root, child1, IndexMap::new());
+        graph.add_edge(root, child2, IndexMap::new());
+        graph.add_edge(root, child3, IndexMap::new());
 
         return graph;
     }
 
-    fn four_child_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn four_child_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let root = graph.add_node((String::from("root"), IndexMap::new()));
         let child1 = graph.add_node((String::from("child1"), IndexMap::new()));
         let child2 = graph.add_node((String::from("child2"), IndexMap::new()));
         let child3 = graph.add_node((String::from("child3"), IndexMap::new()));
         let child4 = graph.add_node((String::from("child4"), IndexMap::new()));
 
-        graph.add_edge(root, child1, ());
-        graph.add_edge(root, child2, ());
-        graph.add_edge(root, child3, ());
-        graph.add_edge(root, child4, ());
+        graph.add_edge(root, child1, IndexMap::new());
+        graph.add_edge(root, child2, IndexMap::new());
+        graph.add_edge(root, child3, IndexMap::new());
+        graph.add_edge(root, child4, IndexMap::new());
 
         return graph;
     }
 
-    fn bookinfo_trace_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn bookinfo_trace_graph() -> TraceGraph {
+        let mut graph = TraceGraph::new();
         let productpage = graph.add_node((String::from("productpage-v1"), IndexMap::new()));
         let reviews = graph.add_node((String::from("reviews-v1"), IndexMap::new()));
         let ratings = graph.add_node((String::from("ratings-v1"), IndexMap::new()));
         let details = graph.add_node((String::from("details-v1"), IndexMap::new()));
 
-        graph.add_edge(productpage, reviews, ());
-        graph.add_edge(productpage, details, ());
-        graph.add_edge(reviews, ratings, ());
+        graph.add_edge(productpage, reviews, IndexMap::new());
+        graph.add_edge(productpage, details, IndexMap::new());
+        graph.add_edge(reviews, ratings, IndexMap::new());
 
         return graph;
     }
 
-    fn simulation_example() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn simulation_example() -> TraceGraph {
+        let mut graph_g = TraceGraph::new();
         let prod_hashmap: IndexMap<String, String> = [
             ("height".to_string(), "2".to_string()),
             (
@@ -717,14 +778,14 @@ mod tests {
         .collect();
         let details = graph_g.add_node(("details-v1".to_string(), details_hashmap));
 
-        graph_g.add_edge(prod, reviews, ());
-        graph_g.add_edge(reviews, ratings, ());
-        graph_g.add_edge(prod, details, ());
+        graph_g.add_edge(prod, reviews, IndexMap::new());
+        graph_g.add_edge(reviews, ratings, IndexMap::new());
+        graph_g.add_edge(prod, details, IndexMap::new());
         return graph_g;
     }
 
-    fn simulation_example_no_properties() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn simulation_example_no_properties() -> TraceGraph {
+        let mut graph_g = TraceGraph::new();
         let prod = graph_g.add_node(("productpage-v1".to_string(), IndexMap::new()));
 
         let ratings = graph_g.add_node(("ratings-v1".to_string(), IndexMap::new()));
@@ -733,14 +794,14 @@ mod tests {
 
         let details = graph_g.add_node(("details-v1".to_string(), IndexMap::new()));
 
-        graph_g.add_edge(prod, reviews, ());
-        graph_g.add_edge(reviews, ratings, ());
-        graph_g.add_edge(prod, details, ());
+        graph_g.add_edge(prod, reviews, IndexMap::new());
+        graph_g.add_edge(reviews, ratings, IndexMap::new());
+        graph_g.add_edge(prod, details, IndexMap::new());
         return graph_g;
     }
 
-    fn biggest_graph() -> Graph<(String, IndexMap<String, String>), ()> {
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+    fn biggest_graph() -> TraceGraph {
+        let mut graph_g = TraceGraph::new();
         let a = graph_g.add_node(("a".to_string(), IndexMap::new()));
         let b = graph_g.add_node(("b".to_string(), IndexMap::new()));
         let c = graph_g.add_node(("c".to_string(), IndexMap::new()));
@@ -749,12 +810,12 @@ mod tests {
         let f = graph_g.add_node(("f".to_string(), IndexMap::new()));
         let g = graph_g.add_node(("g".to_string(), IndexMap::new()));
 
-        graph_g.add_edge(a, b, ());
-        graph_g.add_edge(b, c, ());
-        graph_g.add_edge(c, d, ());
-        graph_g.add_edge(d, e, ());
-        graph_g.add_edge(d, f, ());
-        graph_g.add_edge(d, g, ());
+        graph_g.add_edge(a, b, IndexMap::new());
+        graph_g.add_edge(b, c, IndexMap::new());
+        graph_g.add_edge(c, d, IndexMap::new());
+        graph_g.add_edge(d, e, IndexMap::new());
+        graph_g.add_edge(d, f, IndexMap::new());
+        graph_g.add_edge(d, g, IndexMap::new());
         return graph_g;
     }
     // ---------------------- Shamir Tests -------------------------
@@ -763,7 +824,8 @@ mod tests {
     fn test_initialize_s() {
         let graph_g = three_node_graph();
         let graph_h = two_node_graph();
-        let s = initialize_s(&graph_g, &graph_h);
+        let roots_g = [find_root(&graph_g).unwrap()];
+        let s = initialize_s(&graph_g, &graph_h, &roots_g, find_root(&graph_h).unwrap());
         assert!(s.keys().count() == 6);
 
         // useful debugging if this fails
@@ -824,38 +886,48 @@ mod tests {
     fn test_shamir_small_graphs() {
         let graph_g = three_node_graph();
         let graph_h = two_node_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_some());
     }
     #[test]
     fn test_shamir_figure_2() {
         let graph_g = g_figure_2();
         let graph_h = h_figure_2();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_none());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_none());
     }
 
     #[test]
     fn test_shamir_chain_graphs() {
         let graph_g = chain_graph();
         let graph_h_1 = two_node_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_1).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_1)
+            .unwrap()
+            .is_some());
     }
 
     #[test]
     fn test_shamir_branching_graphs() {
         let graph_g = four_child_graph();
         let graph_h = three_child_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_some());
 
         let graph_g_2 = three_child_graph();
         let graph_h_2 = four_child_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g_2, &graph_h_2).is_none());
+        assert!(find_mapping_shamir_centralized(&graph_g_2, &graph_h_2)
+            .unwrap()
+            .is_none());
     }
 
     #[test]
     fn test_shamir_on_bookinfo() {
         let graph_g = bookinfo_trace_graph();
         let graph_h = three_node_graph();
-        let mapping_wrapped = find_mapping_shamir_centralized(&graph_g, &graph_h);
+        let mapping_wrapped = find_mapping_shamir_centralized(&graph_g, &graph_h).unwrap();
         assert!(mapping_wrapped.is_some());
         let mapping = mapping_wrapped.unwrap();
         let a = get_node_with_id(&graph_h, "a".to_string()).unwrap();
@@ -870,7 +942,7 @@ mod tests {
 
         let graph_g_2 = bookinfo_trace_graph();
         let graph_h_2 = three_node_chain_graph();
-        let mapping_wrapped_2 = find_mapping_shamir_centralized(&graph_g_2, &graph_h_2);
+        let mapping_wrapped_2 = find_mapping_shamir_centralized(&graph_g_2, &graph_h_2).unwrap();
         assert!(mapping_wrapped_2.is_some());
         let mapping_2 = mapping_wrapped_2.unwrap();
         let a_2 = get_node_with_id(&graph_h_2, "a".to_string()).unwrap();
@@ -881,53 +953,160 @@ mod tests {
         assert!(mapping_2.contains(&(b_2, rev_2)));
     }
 
+    #[test]
+    fn test_shamir_mapping_below_the_root() {
+        // every node of graph H is mapped once, also below the children of the root
+        let graph_g = bookinfo_trace_graph();
+        let graph_h = three_node_chain_graph();
+        let mapping = find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .unwrap();
+        let node = |graph: &TraceGraph, id: &str| get_node_with_id(graph, id.to_string()).unwrap();
+        let expected = [
+            (node(&graph_h, "a"), node(&graph_g, "productpage-v1")),
+            (node(&graph_h, "b"), node(&graph_g, "reviews-v1")),
+            (node(&graph_h, "c"), node(&graph_g, "ratings-v1")),
+        ];
+        assert!(mapping.len() == expected.len(), "{:?}", mapping);
+        assert!(expected.iter().all(|pair| mapping.contains(pair)));
+    }
+
     #[test]
     fn test_shamir_full_match() {
         let graph_g = three_node_graph();
         let graph_h = three_node_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_some());
     }
 
     #[test]
     fn test_property_matches() {
         let graph_g = three_node_graph_with_properties();
         let graph_h = two_node_graph_with_properties();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_some());
         let graph_h_2 = two_node_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_2).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_2)
+            .unwrap()
+            .is_some());
     }
 
     #[test]
     fn test_property_does_not_match() {
         let graph_g = three_node_graph_with_properties();
         let graph_h = two_node_graph_with_wrong_properties();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_none());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_none());
 
         let graph_g_2 = three_node_graph();
         let graph_h_2 = two_node_graph_with_wrong_properties();
-        assert!(find_mapping_shamir_centralized(&graph_g_2, &graph_h_2).is_none());
+        assert!(find_mapping_shamir_centralized(&graph_g_2, &graph_h_2)
+            .unwrap()
+            .is_none());
     }
 
     #[test]
     fn test_simulation_example() {
         let graph_g = simulation_example_no_properties();
-        let mut graph_h = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_h = TraceGraph::new();
         let a = graph_h.add_node(("a".to_string(), IndexMap::new()));
         let b = graph_h.add_node(("b".to_string(), IndexMap::new()));
-        graph_h.add_edge(a, b, ());
+        graph_h.add_edge(a, b, IndexMap::new());
 
-        let mapping_wrapped = find_mapping_shamir_centralized(&graph_g, &graph_h);
+        let mapping_wrapped = find_mapping_shamir_centralized(&graph_g, &graph_h).unwrap();
         assert!(mapping_wrapped.is_some());
 
         let graph_g_2 = simulation_example();
-        assert!(find_mapping_shamir_centralized(&graph_g_2, &graph_h).is_some());
+        assert!(find_mapping_shamir_centralized(&graph_g_2, &graph_h)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_predicate_matches() {
+        let graph_g = simulation_example();
+        let mut a_predicates = IndexMap::new();
+        a_predicates.insert(
+            "service_name".to_string(),
+            Predicate::regex("^productpage").unwrap(),
+        );
+        let mut b_predicates = IndexMap::new();
+        b_predicates.insert("height".to_string(), Predicate::GreaterThan(0.0));
+        let mut ids_to_properties = IndexMap::new();
+        ids_to_properties.insert("a".to_string(), a_predicates);
+        ids_to_properties.insert("b".to_string(), b_predicates.clone());
+        let graph_h = generate_target_graph(
+            vec!["a".to_string(), "b".to_string()],
+            vec![("a".to_string(), "b".to_string())],
+            ids_to_properties.clone(),
+        );
+        let mapping = find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .unwrap();
+        let b = get_node_with_id(&graph_h, "b".to_string()).unwrap();
+        let reviews = get_node_with_id(&graph_g, "reviews-v1".to_string()).unwrap();
+        assert!(mapping.contains(&(b, reviews)));
+
+        // details has height 0, so it can't be b
+        b_predicates.insert(
+            "service_name".to_string(),
+            Predicate::OneOf(vec!["details-v1".to_string()]),
+        );
+        ids_to_properties.insert("b".to_string(), b_predicates);
+        let graph_h_2 = generate_target_graph(
+            vec!["a".to_string(), "b".to_string()],
+            vec![("a".to_string(), "b".to_string())],
+            ids_to_properties,
+        );
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_2)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_edge_properties() {
+        let mut graph_g = simulation_example();
+        let prod = get_node_with_id(&graph_g, "productpage-v1".to_string()).unwrap();
+        let reviews = get_node_with_id(&graph_g, "reviews-v1".to_string()).unwrap();
+        let details = get_node_with_id(&graph_g, "details-v1".to_string()).unwrap();
+        let to_reviews = graph_g.find_edge(prod, reviews).unwrap();
+        graph_g[to_reviews].insert("latency".to_string(), "70".to_string());
+        let to_details = graph_g.find_edge(prod, details).unwrap();
+        graph_g[to_details].insert("latency".to_string(), "5".to_string());
+
+        let mut slow_call = IndexMap::new();
+        slow_call.insert("latency".to_string(), Predicate::GreaterThan(50.0));
+        let graph_h = generate_target_graph_with_edge_properties(
+            vec!["a".to_string(), "b".to_string()],
+            vec![(("a".to_string(), "b".to_string()), slow_call)],
+            IndexMap::new(),
+        );
+        let mapping = find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .unwrap();
+        let b = get_node_with_id(&graph_h, "b".to_string()).unwrap();
+        assert!(mapping.contains(&(b, reviews)));
+
+        let mut very_slow_call = IndexMap::new();
+        very_slow_call.insert("latency".to_string(), Predicate::GreaterThan(100.0));
+        let graph_h_2 = generate_target_graph_with_edge_properties(
+            vec!["a".to_string(), "b".to_string()],
+            vec![(("a".to_string(), "b".to_string()), very_slow_call)],
+            IndexMap::new(),
+        );
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_2)
+            .unwrap()
+            .is_none());
     }
 
     #[test]
     fn test_simulation_example_no_match() {
         let graph_g = simulation_example();
 
-        let mut graph_h = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_h = TraceGraph::new();
         let a_hashmap: IndexMap<String, String> = [("height".to_string(), "0".to_string())]
             .iter()
             .cloned()
@@ -936,10 +1115,12 @@ mod tests {
         let b = graph_h.add_node((String::from("reviews-v1"), IndexMap::new()));
         let c = graph_h.add_node((String::from("ratings-v1"), IndexMap::new()));
 
-        graph_h.add_edge(a, b, ());
-        graph_h.add_edge(b, c, ());
+        graph_h.add_edge(a, b, IndexMap::new());
+        graph_h.add_edge(b, c, IndexMap::new());
 
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h).is_none());
+        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h)
+            .unwrap()
+            .is_none());
     }
 
     #[test]
@@ -947,19 +1128,22 @@ mod tests {
         let mut set_s: SetSType = IndexMap::new();
         let graph_h = three_node_chain_graph();
 
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_g = TraceGraph::new();
         let a = graph_g.add_node((String::from("a"), IndexMap::new()));
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, a, false);
+        let ret =
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, a, false).unwrap();
         assert!(ret.is_none());
 
         let b = graph_g.add_node((String::from("b"), IndexMap::new()));
-        graph_g.add_edge(b, a, ());
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, b, false);
+        graph_g.add_edge(b, a, IndexMap::new());
+        let ret =
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, b, false).unwrap();
         assert!(ret.is_none());
 
         let c = graph_g.add_node((String::from("c"), IndexMap::new()));
-        graph_g.add_edge(c, b, ());
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, c, true);
+        graph_g.add_edge(c, b, IndexMap::new());
+        let ret =
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, c, true).unwrap();
         assert!(ret.is_some());
     }
 
@@ -969,7 +1153,7 @@ mod tests {
             IndexMap::<SetSKey, IndexMap<NodeIndex, Option<Vec<(NodeIndex, NodeIndex)>>>>::new();
 
         // create graph h
-        let mut graph_h = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_h = TraceGraph::new();
         let a_hashmap: IndexMap<String, String> = [("height".to_string(), "2".to_string())]
             .iter()
             .cloned()
@@ -978,11 +1162,11 @@ mod tests {
         let b = graph_h.add_node((String::from("reviews-v1"), IndexMap::new()));
         let c = graph_h.add_node((String::from("ratings-v1"), IndexMap::new()));
 
-        graph_h.add_edge(a, b, ());
-        graph_h.add_edge(b, c, ());
+        graph_h.add_edge(a, b, IndexMap::new());
+        graph_h.add_edge(b, c, IndexMap::new());
 
         //create graph g
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_g = TraceGraph::new();
         let ratings_hashmap: IndexMap<String, String> = [
             ("height".to_string(), "0".to_string()),
             (
@@ -995,7 +1179,8 @@ mod tests {
         .cloned()
         .collect();
         let ratings = graph_g.add_node(("ratings-v1".to_string(), ratings_hashmap));
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, ratings, false);
+        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, ratings, false)
+            .unwrap();
         assert!(ret.is_none());
 
         let reviews_hashmap: IndexMap<String, String> = [
@@ -1010,8 +1195,9 @@ mod tests {
         .cloned()
         .collect();
         let reviews = graph_g.add_node(("reviews-v1".to_string(), reviews_hashmap));
-        graph_g.add_edge(reviews, ratings, ());
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, reviews, false);
+        graph_g.add_edge(reviews, ratings, IndexMap::new());
+        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, reviews, false)
+            .unwrap();
         assert!(ret.is_none());
 
         let prod_hashmap: IndexMap<String, String> = [
@@ -1027,8 +1213,9 @@ mod tests {
         .collect();
         let prod = graph_g.add_node(("productpage-v1".to_string(), prod_hashmap));
 
-        graph_g.add_edge(prod, reviews, ());
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, prod, true);
+        graph_g.add_edge(prod, reviews, IndexMap::new());
+        let ret =
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, prod, true).unwrap();
         assert!(ret.is_some());
     }
 
@@ -1038,7 +1225,7 @@ mod tests {
             IndexMap::<SetSKey, IndexMap<NodeIndex, Option<Vec<(NodeIndex, NodeIndex)>>>>::new();
 
         // create graph h
-        let mut graph_h = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_h = TraceGraph::new();
         let a_hashmap: IndexMap<String, String> = [("height".to_string(), "2".to_string())]
             .iter()
             .cloned()
@@ -1047,11 +1234,11 @@ mod tests {
         let b = graph_h.add_node((String::from("reviews-v1"), IndexMap::new()));
         let c = graph_h.add_node((String::from("ratings-v1"), IndexMap::new()));
 
-        graph_h.add_edge(a, b, ());
-        graph_h.add_edge(b, c, ());
+        graph_h.add_edge(a, b, IndexMap::new());
+        graph_h.add_edge(b, c, IndexMap::new());
 
         //create graph g
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_g = TraceGraph::new();
         let ratings_hashmap: IndexMap<String, String> = [
             ("height".to_string(), "0".to_string()),
             (
@@ -1064,7 +1251,8 @@ mod tests {
         .cloned()
         .collect();
         let ratings = graph_g.add_node(("ratings-v1".to_string(), ratings_hashmap));
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, ratings, false);
+        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, ratings, false)
+            .unwrap();
         assert!(ret.is_none());
 
         let reviews_hashmap: IndexMap<String, String> = [
@@ -1079,8 +1267,9 @@ mod tests {
         .cloned()
         .collect();
         let reviews = graph_g.add_node(("reviews-v1".to_string(), reviews_hashmap));
-        graph_g.add_edge(reviews, ratings, ());
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, reviews, false);
+        graph_g.add_edge(reviews, ratings, IndexMap::new());
+        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, reviews, false)
+            .unwrap();
         assert!(ret.is_none());
 
         let prod_hashmap: IndexMap<String, String> = [
@@ -1096,8 +1285,9 @@ mod tests {
         .collect();
         let prod = graph_g.add_node(("productpage-v1".to_string(), prod_hashmap));
 
-        graph_g.add_edge(prod, reviews, ());
-        let ret = find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, prod, true);
+        graph_g.add_edge(prod, reviews, IndexMap::new());
+        let ret =
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, prod, true).unwrap();
         assert!(ret.is_none());
     }
 
@@ -1118,42 +1308,92 @@ mod tests {
     fn test_big_graph() {
         let graph_g = biggest_graph();
         let graph_h = three_child_graph();
-        let mapping_wrapped = find_mapping_shamir_centralized(&graph_g, &graph_h);
+        let mapping_wrapped = find_mapping_shamir_centralized(&graph_g, &graph_h).unwrap();
         assert!(mapping_wrapped.is_some());
 
         let graph_h_not_a_match = four_child_graph();
-        assert!(find_mapping_shamir_centralized(&graph_g, &graph_h_not_a_match).is_none());
+        assert!(
+            find_mapping_shamir_centralized(&graph_g, &graph_h_not_a_match)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_forests_and_dags() {
+        // bookinfo and a separate three-level chain, e.g. two partial traces
+        let mut graph_g = bookinfo_trace_graph();
+        let x = graph_g.add_node(("x".to_string(), IndexMap::new()));
+        let y = graph_g.add_node(("y".to_string(), IndexMap::new()));
+        let z = graph_g.add_node(("z".to_string(), IndexMap::new()));
+        graph_g.add_edge(x, y, IndexMap::new());
+        graph_g.add_edge(y, z, IndexMap::new());
+        assert!(
+            find_mapping_shamir_centralized(&graph_g, &three_node_chain_graph())
+                .unwrap()
+                .is_some()
+        );
+        // the children of productpage are in one tree, not across both
+        assert!(
+            find_mapping_shamir_centralized(&graph_g, &three_child_graph())
+                .unwrap()
+                .is_none()
+        );
+
+        // ratings also called by details
+        graph_g.add_edge(NodeIndex::new(3), NodeIndex::new(2), IndexMap::new());
+        let ret = find_mapping_shamir_centralized(&graph_g, &three_node_chain_graph());
+        assert!(ret == Err(GraphError::SharedDescendant(NodeIndex::new(2))));
+        let ret = find_mapping_shamir_centralized(&three_node_chain_graph(), &graph_g);
+        assert!(ret == Err(GraphError::SharedDescendant(NodeIndex::new(2))));
+        let ret = find_mapping_shamir_centralized(&graph_g, &TraceGraph::new());
+        assert!(ret == Err(GraphError::Empty));
+    }
+
+    #[test]
+    fn test_transitive_edges_are_not_supported() {
+        let mut graph_h = three_node_chain_graph();
+        let edge = graph_h
+            .find_edge(NodeIndex::new(0), NodeIndex::new(1))
+            .unwrap();
+        graph_h[edge].insert("edge.kind".to_string(), "transitive".to_string());
+        let ret = find_mapping_shamir_centralized(&bookinfo_trace_graph(), &graph_h);
+        assert!(ret == Err(GraphError::TransitiveEdges));
+        // marking an edge as direct is the same as not marking it
+        graph_h[edge].insert("edge.kind".to_string(), "direct".to_string());
+        let ret = find_mapping_shamir_centralized(&bookinfo_trace_graph(), &graph_h);
+        assert!(ret.unwrap().is_some());
     }
 
     #[bench]
     fn bench_centralized(bencher: &mut Bencher) {
         let graph_h = three_node_chain_graph();
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_g = TraceGraph::new();
         let a = graph_g.add_node((String::from("a"), IndexMap::new()));
         let b = graph_g.add_node((String::from("b"), IndexMap::new()));
-        graph_g.add_edge(b, a, ());
+        graph_g.add_edge(b, a, IndexMap::new());
         let c = graph_g.add_node((String::from("c"), IndexMap::new()));
-        graph_g.add_edge(c, b, ());
-        bencher.iter(|| find_mapping_shamir_centralized(&graph_g, &graph_h));
+        graph_g.add_edge(c, b, IndexMap::new());
+        bencher.iter(|| find_mapping_shamir_centralized(&graph_g, &graph_h).unwrap());
     }
 
     #[bench]
     fn bench_decentralized(bencher: &mut Bencher) {
         let mut set_s: SetSType = IndexMap::new();
         let graph_h = three_node_chain_graph();
-        let mut graph_g = Graph::<(String, IndexMap<String, String>), ()>::new();
+        let mut graph_g = TraceGraph::new();
 
         bencher.iter(|| {
             let a = graph_g.add_node((String::from("a"), IndexMap::new()));
-            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, a, false);
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, a, false).unwrap();
 
             let b = graph_g.add_node((String::from("b"), IndexMap::new()));
-            graph_g.add_edge(b, a, ());
-            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, b, false);
+            graph_g.add_edge(b, a, IndexMap::new());
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, b, false).unwrap();
 
             let c = graph_g.add_node((String::from("c"), IndexMap::new()));
-            graph_g.add_edge(c, b, ());
-            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, c, true);
+            graph_g.add_edge(c, b, IndexMap::new());
+            find_mapping_shamir_decentralized(&graph_g, &graph_h, &mut set_s, c, true).unwrap();
         });
     }
 }
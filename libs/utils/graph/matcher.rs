@@ -0,0 +1,552 @@
+/* This file puts the subgraph matching algorithms behind one trait, so a filter can pick the
+ * algorithm that suits its traces:
+ *  - shamir: the Shamir-Tsur subtree isomorphism in iso.rs, for tree-shaped traces
+ *  - vf2: a VF2-style search with degree and look-ahead pruning, for general DAG-shaped traces
+ *    and target graphs with transitive edges
+ *  - backtracking: a plain backtracking search, meant as a reference for testing the others
+ * All of them return the mapping as (target node, trace node) pairs, and an error if the target
+ * graph is empty.  Traces may be forests, DAGs or even have cycles; shamir hands the traces and
+ * targets it cannot handle to vf2.  Shamir itself only matches direct edges:  the iso.rs search
+ * returns GraphError::TransitiveEdges for a target graph with a transitive edge, so with shamir
+ * selected such targets are always searched by vf2.
+ */
+
+use super::enumerate::{anchored_candidates, mappings, Mapping};
+use super::graph_utils::{
+    edge_satisfied, has_property_subset, has_transitive_edges, reachability, EdgeKind, GraphError,
+    PropertyGraph, TraceGraph,
+};
+use super::iso::find_mapping_shamir_centralized;
+use super::predicate::PropertyMatcher;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::{Direction, Incoming, Outgoing};
+extern crate test;
+
+pub trait SubgraphMatcher<P: PropertyMatcher> {
+    fn name(&self) -> &'static str;
+    fn find_mapping(
+        &self,
+        graph_g: &TraceGraph,
+        graph_h: &PropertyGraph<P>,
+    ) -> Result<Option<Mapping>, GraphError>;
+}
+
+// Falls back to vf2 for DAGs, cyclic traces and target graphs with transitive edges
+pub struct ShamirMatcher;
+pub struct Vf2Matcher;
+pub struct BacktrackingMatcher;
+
+pub const MATCHER_NAMES: [&str; 3] = ["shamir", "vf2", "backtracking"];
+
+pub fn matcher_by_name<P: PropertyMatcher>(name: &str) -> Option<Box<dyn SubgraphMatcher<P>>> {
+    match name {
+        "shamir" => Some(Box::new(ShamirMatcher)),
+        "vf2" => Some(Box::new(Vf2Matcher)),
+        "backtracking" => Some(Box::new(BacktrackingMatcher)),
+        _ => None,
+    }
+}
+
+impl<P: PropertyMatcher> SubgraphMatcher<P> for ShamirMatcher {
+    fn name(&self) -> &'static str {
+        "shamir"
+    }
+
+    fn find_mapping(
+        &self,
+        graph_g: &TraceGraph,
+        graph_h: &PropertyGraph<P>,
+    ) -> Result<Option<Mapping>, GraphError> {
+        match find_mapping_shamir_centralized(graph_g, graph_h) {
+            Ok(mapping) => Ok(mapping.map(|mut mapping| {
+                mapping.sort();
+                mapping
+            })),
+            Err(GraphError::Empty) => Err(GraphError::Empty),
+            Err(e) => {
+                log::debug!("shamir cannot match these graphs ({0}), using vf2", e);
+                Vf2Matcher.find_mapping(graph_g, graph_h)
+            }
+        }
+    }
+}
+
+impl<P: PropertyMatcher> SubgraphMatcher<P> for BacktrackingMatcher {
+    fn name(&self) -> &'static str {
+        "backtracking"
+    }
+
+    fn find_mapping(
+        &self,
+        graph_g: &TraceGraph,
+        graph_h: &PropertyGraph<P>,
+    ) -> Result<Option<Mapping>, GraphError> {
+        if graph_h.node_count() == 0 {
+            return Err(GraphError::Empty);
+        }
+        Ok(mappings(graph_g, graph_h).next())
+    }
+}
+
+impl<P: PropertyMatcher> SubgraphMatcher<P> for Vf2Matcher {
+    fn name(&self) -> &'static str {
+        "vf2"
+    }
+
+    fn find_mapping(
+        &self,
+        graph_g: &TraceGraph,
+        graph_h: &PropertyGraph<P>,
+    ) -> Result<Option<Mapping>, GraphError> {
+        if graph_h.node_count() == 0 {
+            return Err(GraphError::Empty);
+        }
+        if graph_h.node_count() > graph_g.node_count() {
+            return Ok(None);
+        }
+        let mut state = Vf2State {
+            graph_g,
+            graph_h,
+            order: vf2_order(graph_h),
+            core_h: vec![None; graph_h.node_count()],
+            core_g: vec![None; graph_g.node_count()],
+            reachable: None,
+        };
+        if has_transitive_edges(graph_h) {
+            state.reachable = Some(reachability(graph_g));
+        }
+        if !state.search(0) {
+            return Ok(None);
+        }
+        Ok(Some(
+            graph_h
+                .node_indices()
+                .map(|node_h| (node_h, state.core_h[node_h.index()].unwrap()))
+                .collect(),
+        ))
+    }
+}
+
+/* VF2++ style ordering: start each component at its node of highest degree, then go breadth
+ * first, preferring nodes with the most already ordered neighbors and then the highest degree,
+ * so the most constrained nodes are matched first.
+ */
+fn vf2_order<N, E>(graph: &petgraph::Graph<N, E>) -> Vec<NodeIndex> {
+    let degree = |node: NodeIndex| graph.neighbors_undirected(node).count();
+    let mut order: Vec<NodeIndex> = Vec::new();
+    let mut ordered = vec![false; graph.node_count()];
+    while order.len() < graph.node_count() {
+        let start = graph
+            .node_indices()
+            .filter(|node| !ordered[node.index()])
+            .max_by_key(|node| degree(*node))
+            .unwrap();
+        ordered[start.index()] = true;
+        let mut level = vec![start];
+        while !level.is_empty() {
+            order.extend(&level);
+            let mut next_level: Vec<NodeIndex> = Vec::new();
+            for node in &level {
+                for neighbor in graph.neighbors_undirected(*node) {
+                    if !ordered[neighbor.index()] {
+                        ordered[neighbor.index()] = true;
+                        next_level.push(neighbor);
+                    }
+                }
+            }
+            let in_order = |node: NodeIndex| {
+                graph
+                    .neighbors_undirected(node)
+                    .filter(|neighbor| order.contains(neighbor))
+                    .count()
+            };
+            next_level.sort_by_key(|node| std::cmp::Reverse((in_order(*node), degree(*node))));
+            level = next_level;
+        }
+    }
+    order
+}
+
+struct Vf2State<'a, P: PropertyMatcher> {
+    graph_g: &'a TraceGraph,
+    graph_h: &'a PropertyGraph<P>,
+    order: Vec<NodeIndex>,
+    core_h: Vec<Option<NodeIndex>>, // where each target node is mapped
+    core_g: Vec<Option<NodeIndex>>, // which target node each trace node is mapped from
+    reachable: Option<Vec<Vec<bool>>>, // only needed for transitive edges
+}
+
+impl<'a, P: PropertyMatcher> Vf2State<'a, P> {
+    fn search(&mut self, depth: usize) -> bool {
+        if depth == self.order.len() {
+            return true;
+        }
+        let node_h = self.order[depth];
+        for node_g in self.candidates(node_h) {
+            if self.feasible(node_h, node_g) {
+                self.core_h[node_h.index()] = Some(node_g);
+                self.core_g[node_g.index()] = Some(node_h);
+                if self.search(depth + 1) {
+                    return true;
+                }
+                self.core_h[node_h.index()] = None;
+                self.core_g[node_g.index()] = None;
+            }
+        }
+        false
+    }
+
+    fn candidates(&self, node_h: NodeIndex) -> Vec<NodeIndex> {
+        let mut candidates = anchored_candidates(
+            self.graph_g,
+            self.graph_h,
+            node_h,
+            &self.core_h,
+            self.reachable.as_deref(),
+        );
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    // Neighbors in the direction, counted once even if there are parallel edges
+    fn distinct_neighbors<N, E>(
+        graph: &petgraph::Graph<N, E>,
+        node: NodeIndex,
+        direction: Direction,
+    ) -> Vec<NodeIndex> {
+        let mut neighbors: Vec<NodeIndex> = graph.neighbors_directed(node, direction).collect();
+        neighbors.sort();
+        neighbors.dedup();
+        neighbors
+    }
+
+    // Like distinct_neighbors, but only over direct edges, which need an edge of their own
+    fn direct_neighbors(&self, node_h: NodeIndex, direction: Direction) -> Vec<NodeIndex> {
+        let mut neighbors: Vec<NodeIndex> = self
+            .graph_h
+            .edges_directed(node_h, direction)
+            .filter(|edge| EdgeKind::of(edge.weight()) == EdgeKind::Direct)
+            .map(|edge| match direction {
+                Outgoing => edge.target(),
+                Incoming => edge.source(),
+            })
+            .collect();
+        neighbors.sort();
+        neighbors.dedup();
+        neighbors
+    }
+
+    fn feasible(&self, node_h: NodeIndex, node_g: NodeIndex) -> bool {
+        if self.core_g[node_g.index()].is_some()
+            || !has_property_subset(&self.graph_g[node_g].1, &self.graph_h[node_h].1)
+        {
+            return false;
+        }
+        for direction in &[Outgoing, Incoming] {
+            // degree pruning
+            let neighbors_h = self.direct_neighbors(node_h, *direction);
+            let neighbors_g = Self::distinct_neighbors(self.graph_g, node_g, *direction);
+            if neighbors_h.len() > neighbors_g.len() {
+                return false;
+            }
+            // look-ahead: the unmapped neighbors of node_h need distinct unmapped images
+            let unmapped = |neighbors: &[NodeIndex], core: &[Option<NodeIndex>], node| {
+                neighbors
+                    .iter()
+                    .filter(|neighbor| core[neighbor.index()].is_none() && **neighbor != node)
+                    .count()
+            };
+            if unmapped(&neighbors_h, &self.core_h, node_h)
+                > unmapped(&neighbors_g, &self.core_g, node_g)
+            {
+                return false;
+            }
+        }
+        // every edge to a mapped node needs a matching trace edge, or a path if transitive
+        let outgoing = self.graph_h.edges_directed(node_h, Outgoing);
+        let incoming = self.graph_h.edges_directed(node_h, Incoming);
+        for edge_h in outgoing.chain(incoming) {
+            let image = |node: NodeIndex| {
+                if node == node_h {
+                    Some(node_g)
+                } else {
+                    self.core_h[node.index()]
+                }
+            };
+            if let (Some(source), Some(target)) = (image(edge_h.source()), image(edge_h.target())) {
+                let reachable = self.reachable.as_deref();
+                if !edge_satisfied(self.graph_g, edge_h.weight(), source, target, reachable) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+// Whether the mapping is injective and preserves properties, edges and paths
+pub fn is_valid_mapping<P: PropertyMatcher>(
+    graph_g: &TraceGraph,
+    graph_h: &PropertyGraph<P>,
+    mapping: &[(NodeIndex, NodeIndex)],
+) -> bool {
+    let mut image: Vec<Option<NodeIndex>> = vec![None; graph_h.node_count()];
+    let mut used = vec![false; graph_g.node_count()];
+    for (node_h, node_g) in mapping {
+        if image[node_h.index()].is_some() || used[node_g.index()] {
+            return false;
+        }
+        image[node_h.index()] = Some(*node_g);
+        used[node_g.index()] = true;
+    }
+    if image.iter().any(|node| node.is_none()) {
+        return false;
+    }
+    let nodes_match = graph_h.node_indices().all(|node_h| {
+        has_property_subset(
+            &graph_g[image[node_h.index()].unwrap()].1,
+            &graph_h[node_h].1,
+        )
+    });
+    let edges_match = graph_h.edge_references().all(|edge_h| {
+        let source = image[edge_h.source().index()].unwrap();
+        let target = image[edge_h.target().index()].unwrap();
+        edge_satisfied(graph_g, edge_h.weight(), source, target, None)
+    });
+    nodes_match && edges_match
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::enumerate::find_all_mappings;
+    use crate::graph::graph_utils::{graph_from_edges, EDGE_KIND};
+    use petgraph::graph::EdgeIndex;
+    use test::Bencher;
+
+    fn bookinfo_trace_graph() -> TraceGraph {
+        let names = ["productpage-v1", "reviews-v1", "ratings-v1", "details-v1"];
+        graph_from_edges(&names, &[(0, 1), (0, 3), (1, 2)])
+    }
+
+    fn biggest_graph() -> TraceGraph {
+        let names = ["a", "b", "c", "d", "e", "f", "g"];
+        graph_from_edges(&names, &[(0, 1), (1, 2), (2, 3), (3, 4), (3, 5), (3, 6)])
+    }
+
+    fn all_matchers() -> Vec<Box<dyn SubgraphMatcher<String>>> {
+        MATCHER_NAMES
+            .iter()
+            .map(|name| matcher_by_name(name).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_matcher_by_name() {
+        for name in MATCHER_NAMES.iter() {
+            let matcher: Box<dyn SubgraphMatcher<String>> = matcher_by_name(name).unwrap();
+            assert!(matcher.name() == *name);
+        }
+        assert!(matcher_by_name::<String>("ullmann").is_none());
+    }
+
+    #[test]
+    fn test_matchers_agree_on_trees() {
+        let chain = graph_from_edges(&["x", "y", "z"], &[(0, 1), (1, 2)]);
+        let fork = graph_from_edges(&["x", "y", "z"], &[(0, 1), (0, 2)]);
+        let three_children = graph_from_edges(&["r", "1", "2", "3"], &[(0, 1), (0, 2), (0, 3)]);
+        let four_children = graph_from_edges(
+            &["r", "1", "2", "3", "4"],
+            &[(0, 1), (0, 2), (0, 3), (0, 4)],
+        );
+        let mut with_property = graph_from_edges(&["x", "y"], &[(0, 1)]);
+        with_property[NodeIndex::new(1)]
+            .1
+            .insert("height".to_string(), "0".to_string());
+        let mut bookinfo_with_heights = bookinfo_trace_graph();
+        for (node, height) in [(0, "2"), (1, "1"), (2, "0"), (3, "0")].iter() {
+            bookinfo_with_heights[NodeIndex::new(*node)]
+                .1
+                .insert("height".to_string(), height.to_string());
+        }
+        let traces = [
+            bookinfo_trace_graph(),
+            bookinfo_with_heights,
+            biggest_graph(),
+        ];
+        let targets = [chain, fork, three_children, four_children, with_property];
+        for graph_g in traces.iter() {
+            for graph_h in targets.iter() {
+                let expected = mappings(graph_g, graph_h).next().is_some();
+                for matcher in all_matchers() {
+                    let mapping = matcher.find_mapping(graph_g, graph_h).unwrap();
+                    assert!(mapping.is_some() == expected, "{}", matcher.name());
+                    if let Some(mapping) = mapping {
+                        assert!(is_valid_mapping(graph_g, graph_h, &mapping));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_vf2_on_dags() {
+        // a diamond: two services calling the same backend
+        let diamond = graph_from_edges(&["a", "b", "c", "d"], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+        let graph_g = graph_from_edges(
+            &["0", "1", "2", "3", "4", "5"],
+            &[
+                (0, 1),
+                (0, 2),
+                (1, 3),
+                (1, 4),
+                (2, 4),
+                (3, 5),
+                (4, 5),
+                (2, 5),
+            ],
+        );
+        let mapping = Vf2Matcher
+            .find_mapping(&graph_g, &diamond)
+            .unwrap()
+            .unwrap();
+        assert!(is_valid_mapping(&graph_g, &diamond, &mapping));
+        assert!(BacktrackingMatcher
+            .find_mapping(&graph_g, &diamond)
+            .unwrap()
+            .is_some());
+
+        // the diamond does not fit into a tree
+        assert!(Vf2Matcher
+            .find_mapping(&biggest_graph(), &diamond)
+            .unwrap()
+            .is_none());
+        let triangle = graph_from_edges(&["a", "b", "c"], &[(0, 1), (1, 2), (0, 2)]);
+        assert!(Vf2Matcher
+            .find_mapping(&graph_g, &triangle)
+            .unwrap()
+            .is_some());
+        assert!(Vf2Matcher
+            .find_mapping(&diamond, &triangle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_non_tree_traces() {
+        let chain = graph_from_edges(&["x", "y", "z"], &[(0, 1), (1, 2)]);
+        let fork = graph_from_edges(&["x", "y", "z"], &[(0, 1), (0, 2)]);
+        // two partial traces, only the second has a chain of three calls
+        let forest = graph_from_edges(
+            &["a", "b", "c", "d", "e", "f"],
+            &[(0, 1), (0, 2), (3, 4), (4, 5)],
+        );
+        // a shared descendant, e.g. from a follow-from link
+        let dag = graph_from_edges(&["a", "b", "c", "d"], &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+        let cyclic = graph_from_edges(&["a", "b", "c"], &[(0, 1), (1, 2), (2, 0)]);
+        for graph_g in [forest, dag, cyclic].iter() {
+            for graph_h in [&chain, &fork].iter() {
+                let expected = mappings(graph_g, graph_h).next().is_some();
+                for matcher in all_matchers() {
+                    let mapping = matcher.find_mapping(graph_g, graph_h).unwrap();
+                    assert!(mapping.is_some() == expected, "{}", matcher.name());
+                    if let Some(mapping) = mapping {
+                        assert!(is_valid_mapping(graph_g, graph_h, &mapping));
+                    }
+                }
+            }
+        }
+        for matcher in all_matchers() {
+            let empty = TraceGraph::new();
+            assert!(matcher.find_mapping(&chain, &empty) == Err(GraphError::Empty));
+        }
+    }
+
+    #[test]
+    fn test_transitive_edges() {
+        // productpage calls ratings somewhere below it, and details directly
+        let mut graph_h = graph_from_edges(&["a", "b", "c"], &[(0, 1), (0, 2)]);
+        let transitive = graph_h
+            .find_edge(NodeIndex::new(0), NodeIndex::new(1))
+            .unwrap();
+        graph_h[transitive].insert(EDGE_KIND.to_string(), "transitive".to_string());
+        for (node, name) in [(0, "productpage-v1"), (1, "ratings-v1"), (2, "details-v1")].iter() {
+            graph_h[NodeIndex::new(*node)]
+                .1
+                .insert("service_name".to_string(), name.to_string());
+        }
+        let mut graph_g = bookinfo_trace_graph();
+        for node in graph_g.node_indices() {
+            let name = graph_g[node].0.clone();
+            graph_g[node].1.insert("service_name".to_string(), name);
+        }
+        for matcher in all_matchers() {
+            let mapping = matcher.find_mapping(&graph_g, &graph_h).unwrap().unwrap();
+            assert!(is_valid_mapping(&graph_g, &graph_h, &mapping));
+            // ratings-v1 is two calls below productpage-v1
+            assert!(mapping[1] == (NodeIndex::new(1), NodeIndex::new(2)));
+        }
+        assert!(find_all_mappings(&graph_g, &graph_h, None).len() == 1);
+
+        // a transitive edge can also be a single call
+        graph_h[NodeIndex::new(1)]
+            .1
+            .insert("service_name".to_string(), "reviews-v1".to_string());
+        assert!(Vf2Matcher
+            .find_mapping(&graph_g, &graph_h)
+            .unwrap()
+            .is_some());
+
+        // but only follows the direction of the calls: b is below a in (a, b), (a, c), (b, c)
+        let mut below = graph_from_edges(&["a", "b"], &[(1, 0)]);
+        below[EdgeIndex::new(0)].insert(EDGE_KIND.to_string(), "transitive".to_string());
+        let chain = graph_from_edges(&["a", "b", "c"], &[(0, 1), (1, 2)]);
+        let all = find_all_mappings(&chain, &below, None);
+        assert!(all.len() == 3);
+        assert!(all.iter().all(|mapping| mapping[0].1 > mapping[1].1));
+        let mapping = Vf2Matcher.find_mapping(&chain, &below).unwrap().unwrap();
+        assert!(is_valid_mapping(&chain, &below, &mapping));
+    }
+
+    fn bench_matcher(bencher: &mut Bencher, name: &str, graph_g: TraceGraph) {
+        let graph_h = graph_from_edges(&["root", "1", "2", "3"], &[(0, 1), (0, 2), (0, 3)]);
+        let chain = graph_from_edges(&["x", "y", "z"], &[(0, 1), (1, 2)]);
+        let matcher: Box<dyn SubgraphMatcher<String>> = matcher_by_name(name).unwrap();
+        bencher.iter(|| {
+            matcher.find_mapping(&graph_g, &graph_h).unwrap();
+            matcher.find_mapping(&graph_g, &chain).unwrap()
+        });
+    }
+
+    #[bench]
+    fn bench_shamir_bookinfo(bencher: &mut Bencher) {
+        bench_matcher(bencher, "shamir", bookinfo_trace_graph());
+    }
+
+    #[bench]
+    fn bench_vf2_bookinfo(bencher: &mut Bencher) {
+        bench_matcher(bencher, "vf2", bookinfo_trace_graph());
+    }
+
+    #[bench]
+    fn bench_backtracking_bookinfo(bencher: &mut Bencher) {
+        bench_matcher(bencher, "backtracking", bookinfo_trace_graph());
+    }
+
+    #[bench]
+    fn bench_shamir_biggest(bencher: &mut Bencher) {
+        bench_matcher(bencher, "shamir", biggest_graph());
+    }
+
+    #[bench]
+    fn bench_vf2_biggest(bencher: &mut Bencher) {
+        bench_matcher(bencher, "vf2", biggest_graph());
+    }
+
+    #[bench]
+    fn bench_backtracking_biggest(bencher: &mut Bencher) {
+        bench_matcher(bencher, "backtracking", biggest_graph());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod enumerate;
 pub mod graph_utils;
 pub mod iso;
+pub mod matcher;
+pub mod predicate;
 pub mod serde;
@@ -0,0 +1,140 @@
+/* This file contains the predicates that the properties of a target (user-given) graph can
+ * place on the properties of a trace graph, e.g. "latency > 50" instead of an exact value.
+ * Exact values are still written as plain Strings; both are PropertyMatchers.
+ */
+
+use regex::Regex;
+
+pub trait PropertyMatcher {
+    // value is None if the trace node does not have the property
+    fn matches(&self, value: Option<&str>) -> bool;
+
+    // The one value that matches, if there is exactly one
+    fn exact_value(&self) -> Option<&str> {
+        None
+    }
+}
+
+// A plain String matches exactly that value
+impl PropertyMatcher for String {
+    fn matches(&self, value: Option<&str>) -> bool {
+        value == Some(self.as_str())
+    }
+
+    fn exact_value(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+/* Numeric predicates fail on values that are not numbers.  All predicates fail on a
+ * missing property, except for a negation: "not 500" holds if there is no value at all.
+ */
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Equals(String),
+    LessThan(f64),
+    LessOrEqual(f64),
+    GreaterThan(f64),
+    GreaterOrEqual(f64),
+    InRange(f64, f64), // inclusive on both ends
+    Matches(Regex),
+    OneOf(Vec<String>),
+    Not(Box<Predicate>),
+}
+
+// So that code building target graphs can write exact values for either kind of graph
+impl From<String> for Predicate {
+    fn from(value: String) -> Predicate {
+        Predicate::Equals(value)
+    }
+}
+
+impl Predicate {
+    pub fn regex(pattern: &str) -> Result<Predicate, regex::Error> {
+        Ok(Predicate::Matches(Regex::new(pattern)?))
+    }
+
+    pub fn negate(predicate: Predicate) -> Predicate {
+        Predicate::Not(Box::new(predicate))
+    }
+
+    fn matches_number(&self, value: &str) -> bool {
+        let number = match value.trim().parse::<f64>() {
+            Ok(number) => number,
+            Err(_) => return false,
+        };
+        match self {
+            Predicate::LessThan(bound) => number < *bound,
+            Predicate::LessOrEqual(bound) => number <= *bound,
+            Predicate::GreaterThan(bound) => number > *bound,
+            Predicate::GreaterOrEqual(bound) => number >= *bound,
+            Predicate::InRange(low, high) => *low <= number && number <= *high,
+            _ => false,
+        }
+    }
+}
+
+impl PropertyMatcher for Predicate {
+    fn matches(&self, value: Option<&str>) -> bool {
+        if let Predicate::Not(predicate) = self {
+            return !predicate.matches(value);
+        }
+        let value = match value {
+            Some(value) => value,
+            None => return false,
+        };
+        match self {
+            Predicate::Equals(expected) => value == expected,
+            Predicate::Matches(regex) => regex.is_match(value),
+            Predicate::OneOf(values) => values.iter().any(|expected| expected == value),
+            _ => self.matches_number(value),
+        }
+    }
+
+    fn exact_value(&self) -> Option<&str> {
+        match self {
+            Predicate::Equals(expected) => Some(expected),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric_predicates() {
+        assert!(Predicate::GreaterThan(50.0).matches(Some("70")));
+        assert!(!Predicate::GreaterThan(50.0).matches(Some("50")));
+        assert!(Predicate::GreaterOrEqual(50.0).matches(Some("50")));
+        assert!(Predicate::LessThan(1.5).matches(Some("1")));
+        assert!(Predicate::LessOrEqual(1.0).matches(Some("1.0")));
+        assert!(Predicate::InRange(10.0, 20.0).matches(Some("20")));
+        assert!(!Predicate::InRange(10.0, 20.0).matches(Some("9.9")));
+        assert!(!Predicate::GreaterThan(0.0).matches(Some("fast")));
+        assert!(!Predicate::GreaterThan(0.0).matches(None));
+    }
+
+    #[test]
+    fn test_string_predicates() {
+        assert!("reviews-v1".to_string().matches(Some("reviews-v1")));
+        assert!(!"reviews-v1".to_string().matches(None));
+        assert!(Predicate::Equals("200".to_string()).matches(Some("200")));
+        let reviews = Predicate::regex("^reviews-v[0-9]+$").unwrap();
+        assert!(reviews.matches(Some("reviews-v3")));
+        assert!(!reviews.matches(Some("ratings-v1")));
+        assert!(Predicate::regex("(").is_err());
+        let codes = Predicate::OneOf(vec!["500".to_string(), "503".to_string()]);
+        assert!(codes.matches(Some("503")) && !codes.matches(Some("200")));
+    }
+
+    #[test]
+    fn test_negation() {
+        let not_error = Predicate::negate(Predicate::Equals("500".to_string()));
+        assert!(not_error.matches(Some("200")));
+        assert!(!not_error.matches(Some("500")));
+        // a missing property is not 500 either
+        assert!(not_error.matches(None));
+    }
+}
@@ -1,8 +1,11 @@
-use super::graph_utils;
-use indexmap::map::IndexMap;
-use petgraph::Graph;
+use super::graph_utils::TraceGraph;
 
+use bincode::Options;
+use indexmap::map::IndexMap;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct Property {
@@ -29,79 +32,805 @@ impl Property {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/* FerriedData travels in the ferried_data header of every RPC, so by default it is encoded
+ * compactly: every string (node names, property names and values) is stored once in a table and
+ * referred to by index, the result is serialized with bincode and then base64-encoded so it is
+ * still a valid header value.  The first byte of the binary form is the version of the encoding.
+ * Headers that hold JSON, the encoding used before, can still be decoded.
+ */
+pub const ENCODING_VERSION: u8 = 1;
+
+type CompactProperties = Vec<(u32, u32)>; // (name, value) indices into the string table
+
+#[derive(Serialize, Deserialize)]
+struct CompactFerriedData {
+    strings: Vec<String>,
+    nodes: Vec<(u32, CompactProperties)>, // name and properties, by node index
+    edges: Vec<(u32, u32, CompactProperties)>, // source, target and properties
+    unassigned_properties: Vec<(u32, u32, u32)>, // entity, property name and value
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indices: IndexMap<String, u32>,
+}
+
+impl StringTable {
+    fn intern(&mut self, string: &str) -> u32 {
+        if let Some(index) = self.indices.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(string.to_string());
+        self.indices.insert(string.to_string(), index);
+        index
+    }
+
+    fn intern_properties(&mut self, properties: &IndexMap<String, String>) -> CompactProperties {
+        properties
+            .iter()
+            .map(|(name, value)| (self.intern(name), self.intern(value)))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Base64(base64::DecodeError),
+    Binary(bincode::Error),
+    Json(serde_json::Error),
+    UnknownVersion(u8),
+    BadStringIndex(u32),
+    BadNodeIndex(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Base64(e) => write!(f, "invalid base64: {0}", e),
+            DecodeError::Binary(e) => write!(f, "invalid binary ferried data: {0}", e),
+            DecodeError::Json(e) => write!(f, "invalid json ferried data: {0}", e),
+            DecodeError::UnknownVersion(version) => {
+                write!(f, "unknown ferried data encoding version {0}", version)
+            }
+            DecodeError::BadStringIndex(index) => write!(f, "no string at index {0}", index),
+            DecodeError::BadNodeIndex(index) => write!(f, "no node at index {0}", index),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FerriedData {
-    pub trace_graph: Graph<(String, IndexMap<String, String>), ()>,
+    pub trace_graph: TraceGraph,
     pub unassigned_properties: Vec<Property>, // entity property value
 }
 
 impl FerriedData {
     pub fn default() -> FerriedData {
         FerriedData {
-            trace_graph: Graph::new(),
+            trace_graph: TraceGraph::new(),
             unassigned_properties: Vec::new(),
         }
     }
 
     // take any unassigned properties that apply to nodes in the graph,
     // and associate them with those nodes
-    pub fn assign_properties(&mut self) {
-        let mut to_delete = Vec::new();
-        for property in &mut self.unassigned_properties {
-            if let Some(node) =
-                graph_utils::get_node_with_id(&self.trace_graph, property.entity.clone())
-            {
-                self.trace_graph
-                    .node_weight_mut(node)
-                    .unwrap()
-                    .1
-                    .insert(property.property_name.clone(), property.value.clone());
-                to_delete.push(property.clone());
+    pub fn assign_properties(&mut self) -> Vec<PropertyConflict> {
+        let ids = self.node_ids();
+        let mut conflicts = Vec::new();
+        let graph = &mut self.trace_graph;
+        self.unassigned_properties
+            .retain(|property| match ids.get(&property.entity) {
+                Some(node) => {
+                    let mut assigned = IndexMap::new();
+                    assigned.insert(property.property_name.clone(), property.value.clone());
+                    merge_properties(
+                        &property.entity,
+                        &mut graph[*node].1,
+                        &assigned,
+                        &mut conflicts,
+                    );
+                    false
+                }
+                None => true,
+            });
+        conflicts
+    }
+
+    // The node of every id;  if an id appears more than once, its first node
+    fn node_ids(&self) -> IndexMap<String, NodeIndex> {
+        let mut ids = IndexMap::new();
+        for node in self.trace_graph.node_indices() {
+            ids.entry(self.trace_graph[node].0.clone()).or_insert(node);
+        }
+        ids
+    }
+
+    // The compact encoding, for the ferried_data header
+    pub fn encode(&self) -> String {
+        let mut table = StringTable::default();
+        let graph = &self.trace_graph;
+        let nodes = graph
+            .node_indices()
+            .map(|node| {
+                let (name, properties) = &graph[node];
+                (table.intern(name), table.intern_properties(properties))
+            })
+            .collect();
+        let edges = graph
+            .edge_references()
+            .map(|edge| {
+                (
+                    edge.source().index() as u32,
+                    edge.target().index() as u32,
+                    table.intern_properties(edge.weight()),
+                )
+            })
+            .collect();
+        let unassigned_properties = self
+            .unassigned_properties
+            .iter()
+            .map(|property| {
+                (
+                    table.intern(&property.entity),
+                    table.intern(&property.property_name),
+                    table.intern(&property.value),
+                )
+            })
+            .collect();
+        let compact = CompactFerriedData {
+            strings: table.strings,
+            nodes,
+            edges,
+            unassigned_properties,
+        };
+        let mut bytes = vec![ENCODING_VERSION];
+        // serializing into memory only fails for types serde cannot represent
+        bytes.extend(bincode_options().serialize(&compact).unwrap());
+        base64::encode_config(bytes, base64::STANDARD_NO_PAD)
+    }
+
+    // Decodes both the compact encoding and JSON
+    pub fn decode(encoded: &str) -> Result<FerriedData, DecodeError> {
+        if encoded.starts_with('{') {
+            return serde_json::from_str(encoded).map_err(DecodeError::Json);
+        }
+        let bytes =
+            base64::decode_config(encoded, base64::STANDARD_NO_PAD).map_err(DecodeError::Base64)?;
+        match bytes.first() {
+            Some(&ENCODING_VERSION) => {}
+            Some(version) => return Err(DecodeError::UnknownVersion(*version)),
+            None => return Err(DecodeError::UnknownVersion(0)),
+        }
+        let compact: CompactFerriedData = bincode_options()
+            .deserialize(&bytes[1..])
+            .map_err(DecodeError::Binary)?;
+
+        let string = |index: u32| {
+            compact
+                .strings
+                .get(index as usize)
+                .cloned()
+                .ok_or(DecodeError::BadStringIndex(index))
+        };
+        let properties = |compact_properties: &CompactProperties| {
+            let mut properties = IndexMap::new();
+            for (name, value) in compact_properties {
+                properties.insert(string(*name)?, string(*value)?);
             }
+            Ok(properties)
+        };
+        let mut data = FerriedData::default();
+        for (name, node_properties) in &compact.nodes {
+            data.trace_graph
+                .add_node((string(*name)?, properties(node_properties)?));
         }
-        self.unassigned_properties
-            .retain(|x| !to_delete.contains(&x));
-    }
-    pub fn merge(&mut self, other_data: FerriedData) {
-        //  Merge the graphs by simply adding other data's to self's
-
-        // add nodes
-        for node in other_data.trace_graph.node_indices() {
-            //let node_name = &other_data.trace_graph.node_weight(node).unwrap().0;
-            //if utils::get_node_with_id(&self.trace_graph, node_name.to_string()).is_none() {
-            self.trace_graph
-                .add_node(other_data.trace_graph.node_weight(node).unwrap().clone());
-            //}
-        }
-        // add edges
-        for edge in other_data.trace_graph.edge_indices() {
-            match other_data.trace_graph.edge_endpoints(edge) {
-                Some((edge0, edge1)) => {
-                    let edge0_weight = &other_data.trace_graph.node_weight(edge0).unwrap().0;
-                    let edge1_weight = &other_data.trace_graph.node_weight(edge1).unwrap().0;
-                    let edge0_in_stored_graph =
-                        graph_utils::get_node_with_id(&self.trace_graph, edge0_weight.to_string())
-                            .unwrap();
-                    let edge1_in_stored_graph =
-                        graph_utils::get_node_with_id(&self.trace_graph, edge1_weight.to_string())
-                            .unwrap();
-                    self.trace_graph
-                        .add_edge(edge0_in_stored_graph, edge1_in_stored_graph, ());
+        let node_count = compact.nodes.len() as u32;
+        for (source, target, edge_properties) in &compact.edges {
+            for node in &[*source, *target] {
+                if *node >= node_count {
+                    return Err(DecodeError::BadNodeIndex(*node));
+                }
+            }
+            data.trace_graph.add_edge(
+                NodeIndex::new(*source as usize),
+                NodeIndex::new(*target as usize),
+                properties(edge_properties)?,
+            );
+        }
+        for (entity, name, value) in &compact.unassigned_properties {
+            data.unassigned_properties.push(Property::new(
+                string(*entity)?,
+                string(*name)?,
+                string(*value)?,
+            ));
+        }
+        Ok(data)
+    }
+
+    /* What this data has that base does not, so a filter can send upstream only what the
+     * caller has not seen yet:  nodes base does not have, nodes whose properties are new or
+     * changed (with only those properties), edges base does not have or whose properties changed
+     * (again with only those) together with their endpoints, and new unassigned properties.
+     * Merging the delta into base gives the same as merging this data into base.
+     */
+    pub fn delta_since(&self, base: &FerriedData) -> FerriedData {
+        let mut delta = FerriedData::default();
+        let graph = &self.trace_graph;
+        let base_ids = base.node_ids();
+        let mut delta_nodes: IndexMap<NodeIndex, NodeIndex> = IndexMap::new();
+        for node in graph.node_indices() {
+            let (id, properties) = &graph[node];
+            let base_node = base_ids.get(id);
+            let changed = match base_node {
+                Some(base_node) => changed_properties(properties, &base.trace_graph[*base_node].1),
+                None => properties.clone(),
+            };
+            if base_node.is_none() || !changed.is_empty() {
+                let delta_node = delta.trace_graph.add_node((id.clone(), changed));
+                delta_nodes.insert(node, delta_node);
+            }
+        }
+        for edge in graph.edge_references() {
+            let base_edge = match (
+                base_ids.get(&graph[edge.source()].0),
+                base_ids.get(&graph[edge.target()].0),
+            ) {
+                (Some(source), Some(target)) => base.trace_graph.find_edge(*source, *target),
+                _ => None,
+            };
+            let changed = match base_edge {
+                Some(base_edge) => changed_properties(edge.weight(), &base.trace_graph[base_edge]),
+                None => edge.weight().clone(),
+            };
+            if base_edge.is_some() && changed.is_empty() {
+                continue;
+            }
+            let mut endpoint = |node: NodeIndex| {
+                *delta_nodes.entry(node).or_insert_with(|| {
+                    delta
+                        .trace_graph
+                        .add_node((graph[node].0.clone(), IndexMap::new()))
+                })
+            };
+            let (source, target) = (endpoint(edge.source()), endpoint(edge.target()));
+            delta.trace_graph.add_edge(source, target, changed);
+        }
+        delta.unassigned_properties = self
+            .unassigned_properties
+            .iter()
+            .filter(|property| !base.unassigned_properties.contains(property))
+            .cloned()
+            .collect();
+        delta
+    }
+
+    /* Adds other_data to this data.  Nodes are keyed by their id and edges by the ids of their
+     * endpoints, so merging is idempotent, and merging a into b gives the same trace as merging
+     * b into a:  if both have a value for the same property, both keep the same one (see
+     * resolve_conflict), and the conflict is returned.  This also applies a delta made by
+     * delta_since.
+     */
+    pub fn merge(&mut self, other_data: FerriedData) -> Vec<PropertyConflict> {
+        let mut conflicts = Vec::new();
+        let mut ids = self.node_ids();
+        let other_graph = &other_data.trace_graph;
+
+        // 1. add nodes, or their properties, and remember where every node went
+        let mut merged_nodes = Vec::with_capacity(other_graph.node_count());
+        for node in other_graph.node_indices() {
+            let (id, properties) = &other_graph[node];
+            let merged_node = match ids.get(id) {
+                Some(existing) => {
+                    let existing_properties = &mut self.trace_graph[*existing].1;
+                    merge_properties(id, existing_properties, properties, &mut conflicts);
+                    *existing
+                }
+                None => {
+                    let added = self.trace_graph.add_node(other_graph[node].clone());
+                    ids.insert(id.clone(), added);
+                    added
+                }
+            };
+            merged_nodes.push(merged_node);
+        }
+        // 2. add edges, or their properties
+        for edge in other_graph.edge_references() {
+            let source = merged_nodes[edge.source().index()];
+            let target = merged_nodes[edge.target().index()];
+            match self.trace_graph.find_edge(source, target) {
+                Some(existing) => {
+                    let entity = format!(
+                        "{0}->{1}",
+                        other_graph[edge.source()].0,
+                        other_graph[edge.target()].0
+                    );
+                    let existing_properties = &mut self.trace_graph[existing];
+                    merge_properties(&entity, existing_properties, edge.weight(), &mut conflicts);
                 }
                 None => {
-                    log::error!("no edge endpoints found \n");
-                    return;
+                    self.trace_graph
+                        .add_edge(source, target, edge.weight().clone());
                 }
             }
         }
 
-        // 2. merge unassigned properties
+        // 3. merge unassigned properties
         //    these are properties we have collected but are not yet in the graph
         let mut other_properties = other_data.unassigned_properties;
-        //FIXME this is odd, why is this necessary?
         self.unassigned_properties.append(&mut other_properties);
         self.unassigned_properties.sort_unstable();
         self.unassigned_properties.dedup(); // remove duplicates
-        self.assign_properties();
+        conflicts.append(&mut self.assign_properties());
+        for conflict in &conflicts {
+            log::debug!("{0}", conflict);
+        }
+        conflicts
+    }
+}
+
+// Two different values for the same property of a node or an edge
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropertyConflict {
+    pub entity: String, // the node id, or "source->target" for an edge
+    pub property_name: String,
+    pub kept: String,
+    pub dropped: String,
+}
+
+impl fmt::Display for PropertyConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "conflicting values for {0} of {1}: kept {2}, dropped {3}",
+            self.property_name, self.entity, self.kept, self.dropped
+        )
+    }
+}
+
+/* Which of two values of a property to keep, independent of their order so that merging is
+ * commutative:  the larger number if both are numbers (e.g. a height computed from more of the
+ * trace), else the greater string.  Numbers are ordered by f64::total_cmp, so NaN is larger
+ * than infinity instead of unordered, and equal numbers such as "1" and "1.0" by their string.
+ */
+pub fn resolve_conflict<'a>(value: &'a str, other: &'a str) -> &'a str {
+    let greater = match (value.parse::<f64>(), other.parse::<f64>()) {
+        (Ok(number), Ok(other_number)) => number
+            .total_cmp(&other_number)
+            .then_with(|| value.cmp(other))
+            .is_gt(),
+        _ => value > other,
+    };
+    if greater {
+        value
+    } else {
+        other
+    }
+}
+
+fn merge_properties(
+    entity: &str,
+    properties: &mut IndexMap<String, String>,
+    other: &IndexMap<String, String>,
+    conflicts: &mut Vec<PropertyConflict>,
+) {
+    for (name, other_value) in other {
+        match properties.get_mut(name) {
+            Some(value) if value != other_value => {
+                let kept = resolve_conflict(value, other_value).to_string();
+                let dropped = if kept == *value {
+                    other_value.clone()
+                } else {
+                    value.clone()
+                };
+                conflicts.push(PropertyConflict {
+                    entity: entity.to_string(),
+                    property_name: name.clone(),
+                    kept: kept.clone(),
+                    dropped,
+                });
+                *value = kept;
+            }
+            Some(_) => {}
+            None => {
+                properties.insert(name.clone(), other_value.clone());
+            }
+        }
+    }
+}
+
+// The properties that base does not have with the same value
+fn changed_properties(
+    properties: &IndexMap<String, String>,
+    base: &IndexMap<String, String>,
+) -> IndexMap<String, String> {
+    properties
+        .iter()
+        .filter(|(name, value)| base.get(*name) != Some(value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+// Variable-length integers keep the many small indices to one byte each
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_varint_encoding()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::graph_utils;
+    use proptest::prelude::*;
+
+    // What the example filter has collected by the time productpage responds
+    fn bookinfo_ferried_data() -> FerriedData {
+        let mut data = FerriedData::default();
+        let mut add_service = |name: &str, height: &str| {
+            let mut properties = IndexMap::new();
+            properties.insert("node.metadata.WORKLOAD_NAME".to_string(), name.to_string());
+            properties.insert("service_name".to_string(), name.to_string());
+            properties.insert("height".to_string(), height.to_string());
+            properties.insert("response.code".to_string(), "200".to_string());
+            properties.insert("response.total_size".to_string(), "1".to_string());
+            data.trace_graph.add_node((name.to_string(), properties))
+        };
+        let productpage = add_service("productpage-v1", "2");
+        let reviews = add_service("reviews-v1", "1");
+        let ratings = add_service("ratings-v1", "0");
+        let details = add_service("details-v1", "0");
+        data.trace_graph
+            .add_edge(productpage, reviews, IndexMap::new());
+        data.trace_graph
+            .add_edge(productpage, details, IndexMap::new());
+        let mut call = IndexMap::new();
+        call.insert("status".to_string(), "200".to_string());
+        data.trace_graph.add_edge(reviews, ratings, call);
+        data.unassigned_properties.push(Property::new(
+            "reviews-v2".to_string(),
+            "height".to_string(),
+            "1".to_string(),
+        ));
+        data
+    }
+
+    fn same_data(data: &FerriedData, other: &FerriedData) -> bool {
+        let (graph, other_graph) = (&data.trace_graph, &other.trace_graph);
+        let edges = |graph: &TraceGraph| {
+            graph
+                .edge_references()
+                .map(|edge| (edge.source(), edge.target(), edge.weight().clone()))
+                .collect::<Vec<_>>()
+        };
+        graph.node_weights().eq(other_graph.node_weights())
+            && edges(graph) == edges(other_graph)
+            && data.unassigned_properties == other.unassigned_properties
+    }
+
+    #[test]
+    fn test_merge_is_keyed_by_name() {
+        let data = bookinfo_ferried_data();
+        let mut merged = bookinfo_ferried_data();
+        merged.merge(bookinfo_ferried_data());
+        assert!(same_data(&data, &merged));
+    }
+
+    #[test]
+    fn test_delta() {
+        // reviews already sent productpage what it knows about ratings
+        let full = bookinfo_ferried_data();
+        let mut base = FerriedData::default();
+        for name in &["reviews-v1", "ratings-v1"] {
+            let node = graph_utils::get_node_with_id(&full.trace_graph, name.to_string());
+            base.trace_graph
+                .add_node(full.trace_graph[node.unwrap()].clone());
+        }
+        let mut call = IndexMap::new();
+        call.insert("status".to_string(), "200".to_string());
+        base.trace_graph
+            .add_edge(NodeIndex::new(0), NodeIndex::new(1), call);
+        base.unassigned_properties = full.unassigned_properties.clone();
+        // since then, reviews has a new height
+        base.trace_graph[NodeIndex::new(0)]
+            .1
+            .insert("height".to_string(), "0".to_string());
+
+        let delta = full.delta_since(&base);
+        let names: Vec<&str> = delta
+            .trace_graph
+            .node_weights()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert!(names == vec!["productpage-v1", "reviews-v1", "details-v1"]);
+        let reviews = &delta.trace_graph[NodeIndex::new(1)].1;
+        assert!(reviews.len() == 1 && reviews["height"] == "1");
+        assert!(delta.trace_graph.edge_count() == 2);
+        assert!(delta.unassigned_properties.is_empty());
+
+        let mut applied = base;
+        applied.merge(delta);
+        assert!(applied.trace_graph.node_count() == full.trace_graph.node_count());
+        assert!(applied.trace_graph.edge_count() == full.trace_graph.edge_count());
+        for (name, properties) in full.trace_graph.node_weights() {
+            let node = graph_utils::get_node_with_id(&applied.trace_graph, name.clone());
+            assert!(applied.trace_graph[node.unwrap()].1 == *properties);
+        }
+        assert!(full.delta_since(&full).trace_graph.node_count() == 0);
+        assert!(same_data(&full.delta_since(&FerriedData::default()), &full));
+    }
+
+    #[test]
+    fn test_delta_is_smaller() {
+        // a caller that has seen everything but one new property
+        let full = bookinfo_ferried_data();
+        let mut base = bookinfo_ferried_data();
+        base.trace_graph[NodeIndex::new(0)].1.remove("height");
+        let delta = full.delta_since(&base).encode();
+        assert!(delta.len() * 5 < full.encode().len());
+        // the sizes the README quotes
+        assert!(delta.len() <= 44, "{0} bytes as a delta", delta.len());
+    }
+
+    #[test]
+    fn test_compact_encoding_round_trip() {
+        let data = bookinfo_ferried_data();
+        let decoded = FerriedData::decode(&data.encode()).unwrap();
+        assert!(same_data(&data, &decoded));
+        let empty = FerriedData::decode(&FerriedData::default().encode()).unwrap();
+        assert!(empty.trace_graph.node_count() == 0);
+
+        // headers written as JSON can still be read
+        let json = serde_json::to_string(&data).unwrap();
+        assert!(same_data(&data, &FerriedData::decode(&json).unwrap()));
+    }
+
+    #[test]
+    fn test_compact_encoding_is_smaller() {
+        let data = bookinfo_ferried_data();
+        let json = serde_json::to_string(&data).unwrap();
+        let compact = data.encode();
+        assert!(compact.len() * 2 < json.len());
+        // the sizes the README quotes
+        assert!(compact.len() <= 300, "{0} bytes compact", compact.len());
+        assert!(json.len() <= 816, "{0} bytes as json", json.len());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut bytes =
+            base64::decode_config(FerriedData::default().encode(), base64::STANDARD_NO_PAD)
+                .unwrap();
+        bytes[0] = ENCODING_VERSION + 1;
+        let wrong_version = base64::encode_config(&bytes, base64::STANDARD_NO_PAD);
+        match FerriedData::decode(&wrong_version) {
+            Err(DecodeError::UnknownVersion(version)) => assert!(version == ENCODING_VERSION + 1),
+            _ => panic!("expected an unknown version"),
+        }
+        assert!(FerriedData::decode("not base64!").is_err());
+        assert!(FerriedData::decode("{\"trace_graph\": 3}").is_err());
+
+        // an edge to a node that does not exist
+        let compact = CompactFerriedData {
+            strings: vec!["a".to_string()],
+            nodes: vec![(0, vec![])],
+            edges: vec![(0, 1, vec![])],
+            unassigned_properties: vec![],
+        };
+        let mut bytes = vec![ENCODING_VERSION];
+        bytes.extend(bincode_options().serialize(&compact).unwrap());
+        let encoded = base64::encode_config(&bytes, base64::STANDARD_NO_PAD);
+        match FerriedData::decode(&encoded) {
+            Err(DecodeError::BadNodeIndex(index)) => assert!(index == 1),
+            _ => panic!("expected a bad node index"),
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_edge_properties() {
+        let mut data = FerriedData::default();
+        data.trace_graph
+            .add_node(("productpage-v1".to_string(), IndexMap::new()));
+        let mut other = FerriedData::default();
+        let reviews = other
+            .trace_graph
+            .add_node(("reviews-v1".to_string(), IndexMap::new()));
+        let ratings = other
+            .trace_graph
+            .add_node(("ratings-v1".to_string(), IndexMap::new()));
+        let mut call = IndexMap::new();
+        call.insert("status".to_string(), "200".to_string());
+        other.trace_graph.add_edge(reviews, ratings, call.clone());
+
+        data.merge(other);
+        let reviews = graph_utils::get_node_with_id(&data.trace_graph, "reviews-v1".to_string());
+        let ratings = graph_utils::get_node_with_id(&data.trace_graph, "ratings-v1".to_string());
+        let edge = data
+            .trace_graph
+            .find_edge(reviews.unwrap(), ratings.unwrap())
+            .unwrap();
+        assert!(data.trace_graph[edge] == call);
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let mut data = bookinfo_ferried_data();
+        let mut other = bookinfo_ferried_data();
+        // reviews computed its height before hearing back from ratings
+        other.trace_graph[NodeIndex::new(1)]
+            .1
+            .insert("height".to_string(), "0".to_string());
+        other.unassigned_properties.push(Property::new(
+            "ratings-v1".to_string(),
+            "response.code".to_string(),
+            "503".to_string(),
+        ));
+        let conflicts = data.merge(other);
+        assert!(
+            conflicts
+                == vec![
+                    PropertyConflict {
+                        entity: "reviews-v1".to_string(),
+                        property_name: "height".to_string(),
+                        kept: "1".to_string(),
+                        dropped: "0".to_string(),
+                    },
+                    PropertyConflict {
+                        entity: "ratings-v1".to_string(),
+                        property_name: "response.code".to_string(),
+                        kept: "503".to_string(),
+                        dropped: "200".to_string(),
+                    }
+                ]
+        );
+        assert!(data.trace_graph[NodeIndex::new(1)].1["height"] == "1");
+        assert!(data.trace_graph[NodeIndex::new(2)].1["response.code"] == "503");
+        assert!(resolve_conflict("10", "9") == "10" && resolve_conflict("9", "10") == "10");
+        assert!(resolve_conflict("b", "a") == "b" && resolve_conflict("a", "b") == "b");
+        assert!(resolve_conflict("NaN", "1") == "NaN" && resolve_conflict("1", "NaN") == "NaN");
+        assert!(resolve_conflict("inf", "NaN") == "NaN" && resolve_conflict("-inf", "0") == "0");
+    }
+
+    type Canonical = (
+        Vec<(String, Vec<(String, String)>)>,
+        Vec<(String, String, Vec<(String, String)>)>,
+        Vec<Property>,
+    );
+
+    // The data independent of the order of nodes, edges and properties
+    fn canonical(data: &FerriedData) -> Canonical {
+        let graph = &data.trace_graph;
+        let sorted = |properties: &IndexMap<String, String>| {
+            let mut properties: Vec<_> = properties.clone().into_iter().collect();
+            properties.sort();
+            properties
+        };
+        let mut nodes: Vec<_> = graph
+            .node_weights()
+            .map(|(id, properties)| (id.clone(), sorted(properties)))
+            .collect();
+        nodes.sort();
+        let mut edges: Vec<_> = graph
+            .edge_references()
+            .map(|edge| {
+                (
+                    graph[edge.source()].0.clone(),
+                    graph[edge.target()].0.clone(),
+                    sorted(edge.weight()),
+                )
+            })
+            .collect();
+        edges.sort();
+        let mut unassigned_properties = data.unassigned_properties.clone();
+        unassigned_properties.sort();
+        (nodes, edges, unassigned_properties)
+    }
+
+    fn canonical_conflicts(
+        mut conflicts: Vec<PropertyConflict>,
+    ) -> Vec<(String, String, String, String)> {
+        let mut conflicts: Vec<_> = conflicts
+            .drain(..)
+            .map(|c| (c.entity, c.property_name, c.kept, c.dropped))
+            .collect();
+        conflicts.sort();
+        conflicts
+    }
+
+    fn merged(data: &FerriedData, other: &FerriedData) -> FerriedData {
+        let mut merged = data.clone();
+        merged.merge(other.clone());
+        merged
+    }
+
+    fn arbitrary_properties() -> impl Strategy<Value = IndexMap<String, String>> {
+        prop::collection::vec(
+            (
+                prop::sample::select(vec!["height", "service_name", "response.code"]),
+                prop::sample::select(vec!["0", "1", "2", "10", "200", "ok", "NaN", "inf", "-inf"]),
+            ),
+            0..3,
+        )
+        .prop_map(|properties| {
+            properties
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        })
+    }
+
+    // Small pools of ids and values, so that the data overlap and conflict
+    fn arbitrary_ferried_data() -> impl Strategy<Value = FerriedData> {
+        let ids = || prop::sample::select(vec!["productpage", "reviews", "ratings", "details"]);
+        (
+            prop::collection::vec((ids(), arbitrary_properties()), 0..5),
+            prop::collection::vec((0..5usize, 0..5usize, arbitrary_properties()), 0..5),
+            prop::collection::vec((ids(), arbitrary_properties()), 0..3),
+        )
+            .prop_map(|(nodes, edges, unassigned)| {
+                let mut data = FerriedData::default();
+                for (id, properties) in &nodes {
+                    data.trace_graph
+                        .add_node((id.to_string(), properties.clone()));
+                }
+                if !nodes.is_empty() {
+                    for (source, target, properties) in edges {
+                        let source = NodeIndex::new(source % nodes.len());
+                        let target = NodeIndex::new(target % nodes.len());
+                        data.trace_graph.add_edge(source, target, properties);
+                    }
+                }
+                for (id, properties) in unassigned {
+                    for (name, value) in properties {
+                        data.unassigned_properties
+                            .push(Property::new(id.to_string(), name, value));
+                    }
+                }
+                // as it would be after merging what filters send, one node per id
+                let mut normalized = FerriedData::default();
+                normalized.merge(data);
+                normalized
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn merge_is_idempotent(data in arbitrary_ferried_data()) {
+            let mut twice = data.clone();
+            prop_assert!(twice.merge(data.clone()).is_empty());
+            prop_assert_eq!(canonical(&twice), canonical(&data));
+        }
+
+        #[test]
+        fn merge_is_commutative(a in arbitrary_ferried_data(), b in arbitrary_ferried_data()) {
+            let (mut ab, mut ba) = (a.clone(), b.clone());
+            let (ab_conflicts, ba_conflicts) = (ab.merge(b), ba.merge(a));
+            prop_assert_eq!(canonical(&ab), canonical(&ba));
+            prop_assert_eq!(canonical_conflicts(ab_conflicts), canonical_conflicts(ba_conflicts));
+        }
+
+        #[test]
+        fn merge_is_associative(
+            a in arbitrary_ferried_data(),
+            b in arbitrary_ferried_data(),
+            c in arbitrary_ferried_data(),
+        ) {
+            prop_assert_eq!(
+                canonical(&merged(&merged(&a, &b), &c)),
+                canonical(&merged(&a, &merged(&b, &c)))
+            );
+        }
+
+        #[test]
+        fn delta_merges_like_the_full_data(
+            base in arbitrary_ferried_data(),
+            full in arbitrary_ferried_data(),
+        ) {
+            prop_assert_eq!(
+                canonical(&merged(&base, &full.delta_since(&base))),
+                canonical(&merged(&base, &full))
+            );
+        }
     }
 }